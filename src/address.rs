@@ -0,0 +1,79 @@
+/*
+Addresses typed on the command line and on the debugger: hexadecimal
+numbers, with an optional 0x prefix or h suffix, and ranges with the
+rom: or ram: prefix to select the bank, as in:
+    rom:0066
+    ram:fc00-fcff
+*/
+
+/// Parses a hexadecimal number
+pub fn parse_hex(text: &str) -> Result<u16, String> {
+    let number = text.trim_start_matches("0x").trim_end_matches(['h', 'H']);
+    u16::from_str_radix(number, 16)
+        .map_err(|_| format!("Invalid number '{}'", text))
+}
+
+/// Parses the address of a line of a listing, always with four digits
+pub fn parse_listing_address(token: &str) -> Option<u16> {
+    if token.len() == 4 && token.bytes().all(|b| b.is_ascii_hexdigit()) {
+        parse_hex(token).ok()
+    } else {
+        None
+    }
+}
+
+/// Splits the bank prefix: Some(true) for rom:, Some(false) for ram: and
+/// None without prefix
+pub fn split_bank(text: &str) -> (Option<bool>, &str) {
+    if let Some(rest) = text.strip_prefix("rom:") {
+        (Some(true), rest)
+    } else if let Some(rest) = text.strip_prefix("ram:") {
+        (Some(false), rest)
+    } else {
+        (None, text)
+    }
+}
+
+/// Parses [rom:|ram:]start[-end], a single address is a range of one
+pub fn parse_range(text: &str) -> Result<(Option<bool>, u16, u16), String> {
+    let (rom_rank, range) = split_bank(text);
+    let (start, end) = match range.split_once('-') {
+        Some((start, end)) => (parse_hex(start)?, parse_hex(end)?),
+        None => {
+            let address = parse_hex(range)?;
+            (address, address)
+        }
+    };
+    if start > end {
+        return Err(format!("Invalid range '{}'", text));
+    }
+    Ok((rom_rank, start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers() {
+        assert_eq!(parse_hex("fc00"), Ok(0xfc00));
+        assert_eq!(parse_hex("0x1C"), Ok(0x1c));
+        assert_eq!(parse_hex("0100h"), Ok(0x0100));
+        assert_eq!(parse_hex("10000"), Err("Invalid number '10000'".to_owned()));
+        assert_eq!(parse_hex("start"), Err("Invalid number 'start'".to_owned()));
+        assert_eq!(parse_listing_address("01a0"), Some(0x01a0));
+        assert_eq!(parse_listing_address("1a0h"), None);
+        assert_eq!(parse_listing_address("c3"), None);
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_range("0066"), Ok((None, 0x0066, 0x0066)));
+        assert_eq!(parse_range("rom:3000-3fff"), Ok((Some(true), 0x3000, 0x3fff)));
+        assert_eq!(parse_range("ram:fc00-0xfcffh"), Ok((Some(false), 0xfc00, 0xfcff)));
+        assert_eq!(parse_range("fcff-fc00"), Err("Invalid range 'fcff-fc00'".to_owned()));
+        assert_eq!(parse_range("rom:x-1"), Err("Invalid number 'x'".to_owned()));
+        assert_eq!(split_bank("rom:bios.sym"), (Some(true), "bios.sym"));
+        assert_eq!(split_bank("bios.sym"), (None, "bios.sym"));
+    }
+}
@@ -0,0 +1,1253 @@
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+
+/*
+Z80 assembler for the programs of the playground, with the syntax of
+z80asm. One statement per line, ';' starts a comment:
+    label:  mnemonic operands
+    name:   EQU value
+Labels end with ':', they are case sensitive. The mnemonics and the
+register names are case insensitive.
+
+Directives:
+    ORG addr            address of the next bytes
+    EQU value           defines the label with the value
+    DB / DEFB / DEFM    bytes and strings, "text" or 'text'
+    DW / DEFW           16 bit words, low byte first
+    DS / DEFS count[,v] count bytes with the value v, 0 by default
+    END                 ignores the rest of the file
+
+The strings can have the escapes \r, \n, \t, \0, \\, \", \' and \xNN.
+Numbers are decimal, hex with the h suffix or the $ and 0x prefixes, or
+binary with the b suffix or the % prefix. 'c' is the code of a char,
+and $ the address of the current statement. The expressions have the
+operators + - * / % & | ^ << >> ~ and parentheses, with C precedence.
+
+The documented instructions are supported, and the halves of the index
+registers as IXH, IXL, IYH and IYL. The code is assembled in two passes:
+the first finds the addresses of the labels, the second generates the
+bytes. The addresses of ORG and DS must be known on the first pass, and
+the labels must get the same value on both passes.
+*/
+
+const CONDITIONS: [&str; 8] = ["NZ", "Z", "NC", "C", "PO", "PE", "P", "M"];
+const ALU: [&str; 8] = ["ADD", "ADC", "SUB", "SBC", "AND", "XOR", "OR", "CP"];
+const ROT: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SLL", "SRL"];
+const MNEMONICS: [&str; 68] = [
+    "ADC", "ADD", "AND", "BIT", "CALL", "CCF", "CP", "CPD", "CPDR", "CPI", "CPIR", "CPL",
+    "DAA", "DEC", "DI", "DJNZ", "EI", "EX", "EXX", "HALT", "IM", "IN", "INC", "IND", "INDR",
+    "INI", "INIR", "JP", "JR", "LD", "LDD", "LDDR", "LDI", "LDIR", "NEG", "NOP", "OR", "OTDR",
+    "OTIR", "OUT", "OUTD", "OUTI", "POP", "PUSH", "RES", "RET", "RETI", "RETN", "RL", "RLA",
+    "RLC", "RLCA", "RLD", "RR", "RRA", "RRC", "RRCA", "RRD", "RST", "SBC", "SCF", "SET", "SLA",
+    "SLL", "SRA", "SRL", "SUB", "XOR",
+];
+const IX: u8 = 0xdd;
+const IY: u8 = 0xfd;
+
+pub struct Assembly {
+    /// Address of the first byte of the code
+    pub origin: u16,
+    pub code: Vec<u8>,
+    pub listing: String,
+    pub symbols: BTreeMap<String, u16>,
+}
+
+impl Assembly {
+    /// Symbols as "name equ value" lines, as the z80asm .sym files
+    pub fn symbols_text(&self) -> String {
+        self.symbols.iter()
+            .map(|(name, value)| format!("{}:\tequ {:04x}h\n", name, value))
+            .collect()
+    }
+}
+
+struct Statement {
+    number: usize,
+    text: String,
+    label: Option<String>,
+    mnemonic: Option<String>,
+    operands: Vec<String>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Pair {
+    BC,
+    DE,
+    HL,
+    SP,
+    AF,
+    AFAlt,
+    Index(u8),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Operand {
+    Reg(u8), // B, C, D, E, H, L, (HL) and A as 0 to 7
+    IndexHalf(u8, u8), // prefix and 4 for the high half, 5 for the low
+    Indexed(u8, i8), // prefix and displacement
+    I,
+    R,
+    Pair(Pair),
+    IndBC,
+    IndDE,
+    IndSP,
+    IndC,
+    Mem(i64),
+    Imm(i64),
+}
+
+struct Assembler {
+    symbols: BTreeMap<String, u16>,
+    final_pass: bool,
+    address: u16,
+    // Set when a symbol is not defined yet on the first pass
+    unresolved: Cell<bool>,
+    // EQU symbols with forward references, their value is not known
+    // until the second pass
+    forward: BTreeSet<String>,
+}
+
+/// Assembles a file, writes the binary and optionally the listing and
+/// the symbols
+pub fn assemble_file(source: &str, output: &str, listing: Option<&str>, symbols: Option<&str>)
+        -> Result<Assembly, Vec<String>> {
+    let text = fs::read(source)
+        .map_err(|err| vec![format!("Error loading file '{}': {}", source, err)])?;
+    let assembly = assemble(source, &String::from_utf8_lossy(&text))?;
+    let files = [
+        (Some(output), assembly.code.clone()),
+        (listing, assembly.listing.clone().into_bytes()),
+        (symbols, assembly.symbols_text().into_bytes()),
+    ];
+    for (filename, content) in files.iter() {
+        if let Some(filename) = filename {
+            fs::write(filename, content)
+                .map_err(|err| vec![format!("Error saving file '{}': {}", filename, err)])?;
+        }
+    }
+    Ok(assembly)
+}
+
+/// Assembles a source file. The errors are given as "file:line: message".
+pub fn assemble(filename: &str, source: &str) -> Result<Assembly, Vec<String>> {
+    let mut errors = Vec::new();
+    let mut statements = Vec::new();
+    for (i, text) in source.lines().enumerate() {
+        match parse_statement(text) {
+            Ok(mut statement) => {
+                statement.number = i + 1;
+                statements.push(statement);
+            }
+            Err(err) => errors.push(format!("{}:{}: {}", filename, i + 1, err)),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let mut assembler = Assembler {
+        symbols: BTreeMap::new(),
+        final_pass: false,
+        address: 0,
+        unresolved: Cell::new(false),
+        forward: BTreeSet::new(),
+    };
+
+    // First pass, for the addresses of the labels
+    for statement in statements.iter() {
+        match assembler.statement(statement) {
+            Ok(None) => break,
+            Ok(Some(_)) => {},
+            Err(err) => errors.push(format!("{}:{}: {}", filename, statement.number, err)),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    // EQU values with forward references, resolved in the order of their
+    // dependencies
+    let mut pending: Vec<&Statement> = statements.iter()
+        .filter(|statement| matches!(&statement.label, Some(label) if assembler.forward.contains(label)))
+        .collect();
+    while !pending.is_empty() {
+        let count = pending.len();
+        pending.retain(|statement| !assembler.resolve(statement));
+        if pending.len() == count {
+            break;
+        }
+    }
+
+    // Second pass, with all the labels known
+    assembler.final_pass = true;
+    assembler.address = 0;
+    let mut memory = vec![0u8; 0x10000];
+    let mut range: Option<(usize, usize)> = None;
+    let mut listing = format!("# File {}\n", filename);
+    for statement in statements.iter() {
+        let address = assembler.address;
+        let bytes = match assembler.statement(statement) {
+            Ok(None) => {
+                listing += &format!("{:04x}\t\t\t{}\n", address, statement.text);
+                break;
+            }
+            Ok(Some(bytes)) => bytes,
+            Err(err) => {
+                errors.push(format!("{}:{}: {}", filename, statement.number, err));
+                continue;
+            }
+        };
+        if address as usize + bytes.len() > memory.len() {
+            errors.push(format!("{}:{}: the code goes past 0xffff", filename, statement.number));
+            continue;
+        }
+        memory[address as usize..address as usize + bytes.len()].copy_from_slice(&bytes);
+        if !bytes.is_empty() {
+            let end = address as usize + bytes.len();
+            range = Some(match range {
+                Some((start, last)) => (start.min(address as usize), last.max(end)),
+                None => (address as usize, end),
+            });
+        }
+        listing += &listing_lines(address, &bytes, &statement.text);
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let (start, end) = range.unwrap_or((0, 0));
+    Ok(Assembly {
+        origin: start as u16,
+        code: memory[start..end].to_vec(),
+        listing,
+        symbols: assembler.symbols,
+    })
+}
+
+// Lines of the listing for a statement, 4 bytes per line
+fn listing_lines(address: u16, bytes: &[u8], text: &str) -> String {
+    let mut lines = String::new();
+    let mut chunks = bytes.chunks(4);
+    let first = chunks.next().unwrap_or(&[]);
+    lines += &format!("{:04x} {:12}\t{}\n", address, hex_bytes(first), text);
+    for (i, chunk) in chunks.enumerate() {
+        lines += &format!("{:04x} {}\n", address as usize + 4 * (i + 1), hex_bytes(chunk));
+    }
+    lines
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<String>>().join(" ")
+}
+
+fn parse_statement(text: &str) -> Result<Statement, String> {
+    let line = strip_comment(text).trim();
+    let mut statement = Statement {
+        number: 0,
+        text: text.to_owned(),
+        label: None,
+        mnemonic: None,
+        operands: Vec::new(),
+    };
+
+    let mut rest = line;
+    let first_end = line.find(char::is_whitespace).unwrap_or(line.len());
+    let first = &line[..first_end];
+    if let Some(label) = first.strip_suffix(':') {
+        statement.label = Some(label.to_owned());
+        rest = line[first_end..].trim();
+    } else if let Some(colon) = first.find(':') {
+        // "label:mnemonic" without a space
+        statement.label = Some(first[..colon].to_owned());
+        rest = line[colon + 1..].trim();
+    } else {
+        // "name EQU value" without the colon
+        let second = line[first_end..].split_whitespace().next().unwrap_or("");
+        if second.eq_ignore_ascii_case("equ") {
+            statement.label = Some(first.to_owned());
+            rest = line[first_end..].trim();
+        }
+    }
+    if let Some(label) = &statement.label {
+        if !is_identifier(label) {
+            return Err(format!("invalid label '{}'", label));
+        }
+    }
+    if rest.is_empty() {
+        return Ok(statement);
+    }
+
+    let mnemonic_end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+    statement.mnemonic = Some(rest[..mnemonic_end].to_ascii_uppercase());
+    let operands = rest[mnemonic_end..].trim();
+    if !operands.is_empty() {
+        statement.operands = split_operands(operands)?;
+    }
+    Ok(statement)
+}
+
+// The line up to the ';' that is not in a string
+fn strip_comment(line: &str) -> &str {
+    let bytes = line.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b';' => return &line[..i],
+            quote @ (b'"' | b'\'') => i = closing_quote(bytes, i, quote).unwrap_or(i),
+            _ => {},
+        }
+        i += 1;
+    }
+    line
+}
+
+// Position of the quote closing the one at start. A single quote without
+// a closing one is a char, as in AF'.
+fn closing_quote(bytes: &[u8], start: usize, quote: u8) -> Option<usize> {
+    let mut i = start + 1;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' {
+            i += 1;
+        } else if bytes[i] == quote {
+            return Some(i);
+        }
+        i += 1;
+    }
+    None
+}
+
+fn split_operands(text: &str) -> Result<Vec<String>, String> {
+    let bytes = text.as_bytes();
+    let mut operands = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'(' => depth += 1,
+            b')' => depth -= 1,
+            b',' if depth == 0 => {
+                operands.push(text[start..i].trim().to_owned());
+                start = i + 1;
+            }
+            b'"' => i = closing_quote(bytes, i, b'"').ok_or("missing closing quote")?,
+            b'\'' => i = closing_quote(bytes, i, b'\'').unwrap_or(i),
+            _ => {},
+        }
+        i += 1;
+    }
+    operands.push(text[start..].trim().to_owned());
+    if operands.iter().any(|operand| operand.is_empty()) {
+        return Err("missing operand".to_owned());
+    }
+    Ok(operands)
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(first) if first.is_ascii_alphabetic() || "_.@".contains(first) =>
+            chars.all(|c| c.is_ascii_alphanumeric() || "_.@$?".contains(c)),
+        _ => false,
+    }
+}
+
+impl Assembler {
+    // Bytes of a statement, None for END
+    fn statement(&mut self, statement: &Statement) -> Result<Option<Vec<u8>>, String> {
+        let mnemonic = statement.mnemonic.as_deref().unwrap_or("");
+        let operands = &statement.operands;
+        if mnemonic == "EQU" {
+            let label = statement.label.as_ref().ok_or("EQU without a name")?;
+            let value = self.word(self.eval(self.single(operands)?)?)?;
+            if self.unresolved.get() {
+                self.forward.insert(label.to_owned());
+            }
+            self.define(label, value)?;
+            return Ok(Some(Vec::new()));
+        }
+        if let Some(label) = &statement.label {
+            self.define(label, self.address)?;
+        }
+
+        let bytes = match mnemonic {
+            "" => Vec::new(),
+            "END" => return Ok(None),
+            "ORG" => {
+                self.address = self.word(self.known(self.eval(self.single(operands)?)?)?)?;
+                Vec::new()
+            }
+            "DB" | "DEFB" | "DEFM" => {
+                let mut bytes = Vec::new();
+                for operand in operands {
+                    match string_literal(operand)? {
+                        Some(text) if text.len() != 1 || operand.starts_with('"') => bytes.extend(text),
+                        _ => bytes.push(self.byte(self.eval(operand)?)?),
+                    }
+                }
+                bytes
+            }
+            "DW" | "DEFW" => {
+                let mut bytes = Vec::new();
+                for operand in operands {
+                    let value = self.word(self.eval(operand)?)?;
+                    bytes.extend_from_slice(&value.to_le_bytes());
+                }
+                bytes
+            }
+            "DS" | "DEFS" => {
+                let (count, fill) = match operands.as_slice() {
+                    [count] => (count, 0),
+                    [count, fill] => (count, self.byte(self.eval(fill)?)?),
+                    _ => return Err("expected a count and an optional value".to_owned()),
+                };
+                let count = self.known(self.eval(count)?)?;
+                if !(0..=0xffff).contains(&count) {
+                    return Err(format!("invalid count {}", count));
+                }
+                vec![fill; count as usize]
+            }
+            _ => self.instruction(mnemonic, operands)?,
+        };
+        self.address = self.address.wrapping_add(bytes.len() as u16);
+        Ok(Some(bytes))
+    }
+
+    fn define(&mut self, name: &str, value: u16) -> Result<(), String> {
+        if !self.final_pass {
+            if self.symbols.insert(name.to_owned(), value).is_some() {
+                return Err(format!("'{}' is already defined", name));
+            }
+            return Ok(());
+        }
+        // A change would leave wrong addresses on the code already generated
+        let previous = self.symbols.insert(name.to_owned(), value);
+        if previous != Some(value) {
+            return Err(format!("'{}' is {:04x}h on the second pass, it was {:04x}h on the first",
+                name, value, previous.unwrap_or(0)));
+        }
+        Ok(())
+    }
+
+    // Value of an EQU with forward references, true when it's known
+    fn resolve(&mut self, statement: &Statement) -> bool {
+        let value = self.single(&statement.operands)
+            .and_then(|operand| self.eval(operand))
+            .and_then(|value| self.word(value));
+        match (value, &statement.label) {
+            (Ok(value), Some(label)) if !self.unresolved.get() => {
+                self.symbols.insert(label.to_owned(), value);
+                self.forward.remove(label);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn single<'a>(&self, operands: &'a [String]) -> Result<&'a str, String> {
+        match operands {
+            [operand] => Ok(operand),
+            _ => Err("expected one operand".to_owned()),
+        }
+    }
+
+    // Value that must be known on the first pass
+    fn known(&self, value: i64) -> Result<i64, String> {
+        if self.unresolved.replace(false) {
+            Err("the value must be defined before".to_owned())
+        } else {
+            Ok(value)
+        }
+    }
+
+    fn byte(&self, value: i64) -> Result<u8, String> {
+        if (-128..=255).contains(&value) {
+            Ok(value as u8)
+        } else {
+            Err(format!("value {} out of range for a byte", value))
+        }
+    }
+
+    fn word(&self, value: i64) -> Result<u16, String> {
+        if (-32768..=65535).contains(&value) {
+            Ok(value as u16)
+        } else {
+            Err(format!("value {} out of range for a word", value))
+        }
+    }
+
+    fn displacement(&self, value: i64) -> Result<i8, String> {
+        if (-128..=127).contains(&value) {
+            Ok(value as i8)
+        } else {
+            Err(format!("displacement {} out of range", value))
+        }
+    }
+
+    fn relative(&self, value: i64) -> Result<u8, String> {
+        let offset = value - (self.address as i64 + 2);
+        if !self.final_pass || (-128..=127).contains(&offset) {
+            Ok(offset as u8)
+        } else {
+            Err(format!("relative jump out of range by {}", offset))
+        }
+    }
+
+    fn eval(&self, text: &str) -> Result<i64, String> {
+        self.unresolved.set(false);
+        let mut parser = Expression {
+            assembler: self,
+            text: text.as_bytes(),
+            position: 0,
+        };
+        let value = parser.or()?;
+        parser.skip_spaces();
+        if parser.position < parser.text.len() {
+            return Err(format!("invalid expression '{}'", text));
+        }
+        Ok(value)
+    }
+
+    fn symbol(&self, name: &str) -> Result<i64, String> {
+        match self.symbols.get(name) {
+            Some(value) if !self.forward.contains(name) => Ok(*value as i64),
+            _ if !self.final_pass => {
+                self.unresolved.set(true);
+                Ok(0)
+            }
+            Some(_) => Err(format!("the value of '{}' can't be resolved", name)),
+            None => Err(format!("undefined symbol '{}'", name)),
+        }
+    }
+
+    fn operand(&self, text: &str) -> Result<Operand, String> {
+        let upper = text.to_ascii_uppercase();
+        if let Some(code) = ["B", "C", "D", "E", "H", "L", "", "A"].iter().position(|r| *r == upper) {
+            return Ok(Operand::Reg(code as u8));
+        }
+        let operand = match upper.as_str() {
+            "IXH" => Operand::IndexHalf(IX, 4),
+            "IXL" => Operand::IndexHalf(IX, 5),
+            "IYH" => Operand::IndexHalf(IY, 4),
+            "IYL" => Operand::IndexHalf(IY, 5),
+            "I" => Operand::I,
+            "R" => Operand::R,
+            "BC" => Operand::Pair(Pair::BC),
+            "DE" => Operand::Pair(Pair::DE),
+            "HL" => Operand::Pair(Pair::HL),
+            "SP" => Operand::Pair(Pair::SP),
+            "AF" => Operand::Pair(Pair::AF),
+            "AF'" => Operand::Pair(Pair::AFAlt),
+            "IX" => Operand::Pair(Pair::Index(IX)),
+            "IY" => Operand::Pair(Pair::Index(IY)),
+            _ => match indirect(text) {
+                Some(inner) => {
+                    let inner_upper = inner.to_ascii_uppercase();
+                    match inner_upper.as_str() {
+                        "HL" => Operand::Reg(6),
+                        "BC" => Operand::IndBC,
+                        "DE" => Operand::IndDE,
+                        "SP" => Operand::IndSP,
+                        "C" => Operand::IndC,
+                        _ if inner_upper.starts_with("IX") || inner_upper.starts_with("IY") => {
+                            let prefix = if inner_upper.starts_with("IX") {IX} else {IY};
+                            let rest = inner[2..].trim();
+                            if rest.is_empty() {
+                                Operand::Indexed(prefix, 0)
+                            } else if rest.starts_with(['+', '-']) {
+                                Operand::Indexed(prefix, self.displacement(self.eval(rest)?)?)
+                            } else {
+                                Operand::Mem(self.eval(inner)?)
+                            }
+                        }
+                        _ => Operand::Mem(self.eval(inner)?),
+                    }
+                }
+                None => Operand::Imm(self.eval(text)?),
+            },
+        };
+        Ok(operand)
+    }
+
+    fn instruction(&self, mnemonic: &str, texts: &[String]) -> Result<Vec<u8>, String> {
+        if !MNEMONICS.contains(&mnemonic) {
+            return Err(format!("unknown instruction '{}'", mnemonic));
+        }
+        // The conditions are parsed apart, C is also a register
+        let condition = |text: &str| CONDITIONS.iter()
+            .position(|c| c.eq_ignore_ascii_case(text)).map(|c| c as u8);
+        match (mnemonic, texts) {
+            ("JP" | "CALL" | "JR", [cc, target]) => {
+                let cc = condition(cc).ok_or_else(|| format!("invalid condition '{}'", cc))?;
+                let target = self.eval(target)?;
+                return match mnemonic {
+                    "JP" => self.with_word(vec![0xc2 | cc << 3], target),
+                    "CALL" => self.with_word(vec![0xc4 | cc << 3], target),
+                    _ if cc < 4 => Ok(vec![0x20 | cc << 3, self.relative(target)?]),
+                    _ => Err("invalid condition for JR".to_owned()),
+                };
+            }
+            ("RET", [cc]) => {
+                let cc = condition(cc).ok_or_else(|| format!("invalid condition '{}'", cc))?;
+                return Ok(vec![0xc0 | cc << 3]);
+            }
+            _ => {},
+        }
+
+        let operands = texts.iter().map(|text| self.operand(text)).collect::<Result<Vec<Operand>, String>>()?;
+        let invalid = || Err(format!("invalid operands for {}", mnemonic));
+        use Operand::*;
+        let bytes = match (mnemonic, operands.as_slice()) {
+            ("NOP", []) => vec![0x00],
+            ("HALT", []) => vec![0x76],
+            ("DI", []) => vec![0xf3],
+            ("EI", []) => vec![0xfb],
+            ("EXX", []) => vec![0xd9],
+            ("DAA", []) => vec![0x27],
+            ("CPL", []) => vec![0x2f],
+            ("SCF", []) => vec![0x37],
+            ("CCF", []) => vec![0x3f],
+            ("RLCA", []) => vec![0x07],
+            ("RRCA", []) => vec![0x0f],
+            ("RLA", []) => vec![0x17],
+            ("RRA", []) => vec![0x1f],
+            ("NEG", []) => vec![0xed, 0x44],
+            ("RETN", []) => vec![0xed, 0x45],
+            ("RETI", []) => vec![0xed, 0x4d],
+            ("RRD", []) => vec![0xed, 0x67],
+            ("RLD", []) => vec![0xed, 0x6f],
+            ("LDI", []) => vec![0xed, 0xa0],
+            ("CPI", []) => vec![0xed, 0xa1],
+            ("INI", []) => vec![0xed, 0xa2],
+            ("OUTI", []) => vec![0xed, 0xa3],
+            ("LDD", []) => vec![0xed, 0xa8],
+            ("CPD", []) => vec![0xed, 0xa9],
+            ("IND", []) => vec![0xed, 0xaa],
+            ("OUTD", []) => vec![0xed, 0xab],
+            ("LDIR", []) => vec![0xed, 0xb0],
+            ("CPIR", []) => vec![0xed, 0xb1],
+            ("INIR", []) => vec![0xed, 0xb2],
+            ("OTIR", []) => vec![0xed, 0xb3],
+            ("LDDR", []) => vec![0xed, 0xb8],
+            ("CPDR", []) => vec![0xed, 0xb9],
+            ("INDR", []) => vec![0xed, 0xba],
+            ("OTDR", []) => vec![0xed, 0xbb],
+            ("RET", []) => vec![0xc9],
+
+            ("JP", [Reg(6)]) => vec![0xe9],
+            ("JP", [Indexed(prefix, 0)]) => vec![*prefix, 0xe9],
+            ("JP", [Imm(target)]) => self.with_word(vec![0xc3], *target)?,
+            ("CALL", [Imm(target)]) => self.with_word(vec![0xcd], *target)?,
+            ("JR", [Imm(target)]) => vec![0x18, self.relative(*target)?],
+            ("DJNZ", [Imm(target)]) => vec![0x10, self.relative(*target)?],
+            ("RST", [Imm(vector)]) if vector % 8 == 0 && (0..0x40).contains(vector) =>
+                vec![0xc7 | *vector as u8],
+            ("IM", [Imm(mode)]) => match mode {
+                0 => vec![0xed, 0x46],
+                1 => vec![0xed, 0x56],
+                2 => vec![0xed, 0x5e],
+                _ => return invalid(),
+            },
+
+            ("PUSH" | "POP", [Pair(pair)]) => {
+                let base = if mnemonic == "PUSH" {0xc5} else {0xc1};
+                match pair {
+                    self::Pair::AF => vec![base | 0x30],
+                    self::Pair::Index(prefix) => vec![*prefix, base | 0x20],
+                    _ => match pair_code(*pair) {
+                        Some(p) if p < 3 => vec![base | p << 4],
+                        _ => return invalid(),
+                    },
+                }
+            }
+
+            ("EX", [Pair(self::Pair::DE), Pair(self::Pair::HL)]) => vec![0xeb],
+            ("EX", [Pair(self::Pair::AF), Pair(self::Pair::AFAlt)]) => vec![0x08],
+            ("EX", [IndSP, Pair(self::Pair::HL)]) => vec![0xe3],
+            ("EX", [IndSP, Pair(self::Pair::Index(prefix))]) => vec![*prefix, 0xe3],
+
+            ("IN", [Reg(7), Mem(port)]) => vec![0xdb, self.byte(*port)?],
+            ("IN", [Reg(r), IndC]) if *r != 6 => vec![0xed, 0x40 | r << 3],
+            ("OUT", [Mem(port), Reg(7)]) => vec![0xd3, self.byte(*port)?],
+            ("OUT", [IndC, Reg(r)]) if *r != 6 => vec![0xed, 0x41 | r << 3],
+
+            ("ADD", [Pair(self::Pair::HL), Pair(pair)]) => match pair_code(*pair) {
+                Some(p) => vec![0x09 | p << 4],
+                None => return invalid(),
+            },
+            ("ADD", [Pair(self::Pair::Index(prefix)), Pair(pair)]) => match pair {
+                self::Pair::Index(other) if other == prefix => vec![*prefix, 0x29],
+                self::Pair::HL | self::Pair::Index(_) => return invalid(),
+                _ => match pair_code(*pair) {
+                    Some(p) => vec![*prefix, 0x09 | p << 4],
+                    None => return invalid(),
+                },
+            },
+            ("ADC" | "SBC", [Pair(self::Pair::HL), Pair(pair)]) => match pair_code(*pair) {
+                Some(p) => vec![0xed, if mnemonic == "ADC" {0x4a} else {0x42} | p << 4],
+                None => return invalid(),
+            },
+            ("ADD" | "ADC" | "SUB" | "SBC" | "AND" | "XOR" | "OR" | "CP", [Reg(7), source])
+                    | ("ADD" | "ADC" | "SUB" | "SBC" | "AND" | "XOR" | "OR" | "CP", [source]) => {
+                let alu = ALU.iter().position(|m| *m == mnemonic).unwrap_or(0) as u8;
+                match source {
+                    Imm(value) => vec![0xc6 | alu << 3, self.byte(*value)?],
+                    _ => match reg8(source) {
+                        Some((prefix, r, d)) => encode(prefix, &[0x80 | alu << 3 | r], d, &[]),
+                        None => return invalid(),
+                    },
+                }
+            }
+
+            ("INC" | "DEC", [Pair(pair)]) => {
+                let base = if mnemonic == "INC" {0x03} else {0x0b};
+                match (pair, pair_code(*pair)) {
+                    (self::Pair::Index(prefix), _) => vec![*prefix, base | 0x20],
+                    (_, Some(p)) => vec![base | p << 4],
+                    _ => return invalid(),
+                }
+            }
+            ("INC" | "DEC", [target]) => match reg8(target) {
+                Some((prefix, r, d)) => {
+                    let base = if mnemonic == "INC" {0x04} else {0x05};
+                    encode(prefix, &[base | r << 3], d, &[])
+                }
+                None => return invalid(),
+            },
+
+            ("RLC" | "RRC" | "RL" | "RR" | "SLA" | "SRA" | "SLL" | "SRL", [target]) => {
+                let rot = ROT.iter().position(|m| *m == mnemonic).unwrap_or(0) as u8;
+                self.bits(rot << 3, target)?
+            }
+            ("BIT" | "RES" | "SET", [Imm(bit), target]) if (0..8).contains(bit) => {
+                let base = match mnemonic {
+                    "BIT" => 0x40,
+                    "RES" => 0x80,
+                    _ => 0xc0,
+                };
+                self.bits(base | (*bit as u8) << 3, target)?
+            }
+
+            ("LD", [destination, source]) => self.load(*destination, *source)?,
+            _ => return invalid(),
+        };
+        Ok(bytes)
+    }
+
+    fn with_word(&self, mut bytes: Vec<u8>, value: i64) -> Result<Vec<u8>, String> {
+        bytes.extend_from_slice(&self.word(value)?.to_le_bytes());
+        Ok(bytes)
+    }
+
+    // CB prefixed instructions, the displacement goes before the opcode
+    fn bits(&self, opcode: u8, target: &Operand) -> Result<Vec<u8>, String> {
+        match reg8(target) {
+            Some((None, r, None)) => Ok(vec![0xcb, opcode | r]),
+            Some((Some(prefix), 6, Some(d))) => Ok(vec![prefix, 0xcb, d as u8, opcode | 6]),
+            _ => Err("invalid operand for a bit instruction".to_owned()),
+        }
+    }
+
+    fn load(&self, destination: Operand, source: Operand) -> Result<Vec<u8>, String> {
+        use Operand::*;
+        let bytes = match (destination, source) {
+            (Reg(7), IndBC) => vec![0x0a],
+            (Reg(7), IndDE) => vec![0x1a],
+            (Reg(7), Mem(address)) => self.with_word(vec![0x3a], address)?,
+            (IndBC, Reg(7)) => vec![0x02],
+            (IndDE, Reg(7)) => vec![0x12],
+            (Mem(address), Reg(7)) => self.with_word(vec![0x32], address)?,
+            (Reg(7), I) => vec![0xed, 0x57],
+            (Reg(7), R) => vec![0xed, 0x5f],
+            (I, Reg(7)) => vec![0xed, 0x47],
+            (R, Reg(7)) => vec![0xed, 0x4f],
+
+            (Pair(self::Pair::SP), Pair(self::Pair::HL)) => vec![0xf9],
+            (Pair(self::Pair::SP), Pair(self::Pair::Index(prefix))) => vec![prefix, 0xf9],
+            (Pair(self::Pair::Index(prefix)), Imm(value)) => self.with_word(vec![prefix, 0x21], value)?,
+            (Pair(self::Pair::Index(prefix)), Mem(address)) => self.with_word(vec![prefix, 0x2a], address)?,
+            (Mem(address), Pair(self::Pair::Index(prefix))) => self.with_word(vec![prefix, 0x22], address)?,
+            (Pair(self::Pair::HL), Mem(address)) => self.with_word(vec![0x2a], address)?,
+            (Mem(address), Pair(self::Pair::HL)) => self.with_word(vec![0x22], address)?,
+            (Pair(pair), Imm(value)) => match pair_code(pair) {
+                Some(p) => self.with_word(vec![0x01 | p << 4], value)?,
+                None => return Err("invalid operands for LD".to_owned()),
+            },
+            (Pair(pair), Mem(address)) => match pair_code(pair) {
+                Some(p) => self.with_word(vec![0xed, 0x4b | p << 4], address)?,
+                None => return Err("invalid operands for LD".to_owned()),
+            },
+            (Mem(address), Pair(pair)) => match pair_code(pair) {
+                Some(p) => self.with_word(vec![0xed, 0x43 | p << 4], address)?,
+                None => return Err("invalid operands for LD".to_owned()),
+            },
+
+            (destination, Imm(value)) => match reg8(&destination) {
+                Some((prefix, r, d)) => encode(prefix, &[0x06 | r << 3], d, &[self.byte(value)?]),
+                None => return Err("invalid operands for LD".to_owned()),
+            },
+            (destination, source) => match (reg8(&destination), reg8(&source)) {
+                (Some((dp, d, dd)), Some((sp, s, sd))) => {
+                    // The index registers replace H, L or (HL) on the whole instruction
+                    let valid = match (dp, sp) {
+                        (None, None) => !(d == 6 && s == 6),
+                        (Some(_), None) => if dd.is_some() {s != 6} else {s != 6 && s != 4 && s != 5},
+                        (None, Some(_)) => if sd.is_some() {d != 6} else {d != 6 && d != 4 && d != 5},
+                        (Some(a), Some(b)) => a == b && dd.is_none() && sd.is_none(),
+                    };
+                    if !valid {
+                        return Err("invalid operands for LD".to_owned());
+                    }
+                    encode(dp.or(sp), &[0x40 | d << 3 | s], dd.or(sd), &[])
+                }
+                _ => return Err("invalid operands for LD".to_owned()),
+            },
+        };
+        Ok(bytes)
+    }
+}
+
+// Prefix, register code and displacement of an 8 bit operand
+fn reg8(operand: &Operand) -> Option<(Option<u8>, u8, Option<i8>)> {
+    match *operand {
+        Operand::Reg(r) => Some((None, r, None)),
+        Operand::IndexHalf(prefix, r) => Some((Some(prefix), r, None)),
+        Operand::Indexed(prefix, d) => Some((Some(prefix), 6, Some(d))),
+        _ => None,
+    }
+}
+
+// Code of the pair on the opcodes, HL and the index registers are 2
+fn pair_code(pair: Pair) -> Option<u8> {
+    match pair {
+        Pair::BC => Some(0),
+        Pair::DE => Some(1),
+        Pair::HL => Some(2),
+        Pair::SP => Some(3),
+        _ => None,
+    }
+}
+
+fn encode(prefix: Option<u8>, opcode: &[u8], displacement: Option<i8>, rest: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend(prefix);
+    bytes.extend_from_slice(opcode);
+    bytes.extend(displacement.map(|d| d as u8));
+    bytes.extend_from_slice(rest);
+    bytes
+}
+
+// Text inside the parentheses, if they wrap the whole operand
+fn indirect(text: &str) -> Option<&str> {
+    let inner = text.strip_prefix('(')?.strip_suffix(')')?;
+    let mut depth = 0;
+    for c in inner.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => return None, // as in (1)+(2)
+            ')' => depth -= 1,
+            _ => {},
+        }
+    }
+    Some(inner.trim())
+}
+
+// Bytes of a quoted string with escapes
+fn string_literal(text: &str) -> Result<Option<Vec<u8>>, String> {
+    let quote = match text.chars().next() {
+        Some(quote @ ('"' | '\'')) if text.len() >= 2 && text.ends_with(quote) => quote,
+        _ => return Ok(None),
+    };
+    let inner = &text[1..text.len() - 1];
+    let mut bytes = Vec::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buffer = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+            continue;
+        }
+        let escaped = match chars.next() {
+            Some('r') => b'\r',
+            Some('n') => b'\n',
+            Some('t') => b'\t',
+            Some('0') => 0,
+            Some('\\') => b'\\',
+            Some('"') => b'"',
+            Some('\'') => b'\'',
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                u8::from_str_radix(&hex, 16).map_err(|_| format!("invalid escape '\\x{}'", hex))?
+            }
+            Some(other) => return Err(format!("invalid escape '\\{}'", other)),
+            None => return Err("escape at the end of the string".to_owned()),
+        };
+        bytes.push(escaped);
+    }
+    if quote == '\'' && bytes.is_empty() {
+        return Err("empty char".to_owned());
+    }
+    Ok(Some(bytes))
+}
+
+// Recursive descent parser of the expressions, with the C precedence
+struct Expression<'a> {
+    assembler: &'a Assembler,
+    text: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Expression<'a> {
+    fn skip_spaces(&mut self) {
+        while self.position < self.text.len() && self.text[self.position].is_ascii_whitespace() {
+            self.position += 1;
+        }
+    }
+
+    // Consumes the operator if it is next
+    fn next_is(&mut self, operator: &str) -> bool {
+        self.skip_spaces();
+        if self.text[self.position..].starts_with(operator.as_bytes()) {
+            self.position += operator.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn or(&mut self) -> Result<i64, String> {
+        let mut value = self.xor()?;
+        while self.next_is("|") {
+            value |= self.xor()?;
+        }
+        Ok(value)
+    }
+
+    fn xor(&mut self) -> Result<i64, String> {
+        let mut value = self.and()?;
+        while self.next_is("^") {
+            value ^= self.and()?;
+        }
+        Ok(value)
+    }
+
+    fn and(&mut self) -> Result<i64, String> {
+        let mut value = self.shift()?;
+        while self.next_is("&") {
+            value &= self.shift()?;
+        }
+        Ok(value)
+    }
+
+    fn shift(&mut self) -> Result<i64, String> {
+        let mut value = self.sum()?;
+        loop {
+            if self.next_is("<<") {
+                value = value.wrapping_shl(self.sum()? as u32);
+            } else if self.next_is(">>") {
+                value = value.wrapping_shr(self.sum()? as u32);
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn sum(&mut self) -> Result<i64, String> {
+        let mut value = self.product()?;
+        loop {
+            if self.next_is("+") {
+                value = value.wrapping_add(self.product()?);
+            } else if self.next_is("-") {
+                value = value.wrapping_sub(self.product()?);
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn product(&mut self) -> Result<i64, String> {
+        let mut value = self.unary()?;
+        loop {
+            if self.next_is("*") {
+                value = value.wrapping_mul(self.unary()?);
+            } else if self.next_is("/") || self.next_is("%") {
+                let modulo = self.text[self.position - 1] == b'%';
+                let divisor = self.unary()?;
+                if divisor == 0 {
+                    // Undefined symbols are 0 on the first pass
+                    if self.assembler.unresolved.get() {
+                        return Ok(0);
+                    }
+                    return Err("division by zero".to_owned());
+                }
+                value = if modulo {value % divisor} else {value / divisor};
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Result<i64, String> {
+        if self.next_is("-") {
+            Ok(self.unary()?.wrapping_neg())
+        } else if self.next_is("+") {
+            self.unary()
+        } else if self.next_is("~") {
+            Ok(!self.unary()?)
+        } else if self.next_is("(") {
+            let value = self.or()?;
+            if !self.next_is(")") {
+                return Err("missing ')'".to_owned());
+            }
+            Ok(value)
+        } else {
+            self.atom()
+        }
+    }
+
+    fn atom(&mut self) -> Result<i64, String> {
+        self.skip_spaces();
+        let text = self.text;
+        let start = self.position;
+        match text.get(start) {
+            Some(b'\'') => {
+                let end = closing_quote(text, start, b'\'').ok_or("missing closing quote")?;
+                self.position = end + 1;
+                let literal = String::from_utf8_lossy(&text[start..=end]).to_string();
+                match string_literal(&literal)? {
+                    Some(bytes) if bytes.len() == 1 => Ok(bytes[0] as i64),
+                    _ => Err(format!("invalid char {}", literal)),
+                }
+            }
+            Some(b'$') | Some(b'%') | Some(b'0'..=b'9') | Some(b'_') | Some(b'.') | Some(b'@')
+                    | Some(b'a'..=b'z') | Some(b'A'..=b'Z') => {
+                let mut end = start + 1;
+                while end < text.len() && (text[end].is_ascii_alphanumeric() || b"_.@$?".contains(&text[end])) {
+                    end += 1;
+                }
+                self.position = end;
+                let token = String::from_utf8_lossy(&text[start..end]).to_string();
+                if token == "$" {
+                    Ok(self.assembler.address as i64)
+                } else if let Some(value) = parse_number(&token) {
+                    Ok(value)
+                } else if is_identifier(&token) {
+                    self.assembler.symbol(&token)
+                } else {
+                    Err(format!("invalid number '{}'", token))
+                }
+            }
+            _ => Err(format!("invalid expression '{}'", String::from_utf8_lossy(&text[start..]))),
+        }
+    }
+}
+
+fn parse_number(token: &str) -> Option<i64> {
+    let lower = token.to_ascii_lowercase();
+    let (digits, radix) = if let Some(hex) = lower.strip_prefix('$').or_else(|| lower.strip_prefix("0x")) {
+        (hex, 16)
+    } else if let Some(binary) = lower.strip_prefix('%') {
+        (binary, 2)
+    } else if !lower.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    } else if let Some(hex) = lower.strip_suffix('h') {
+        (hex, 16)
+    } else if let Some(binary) = lower.strip_suffix('b').filter(|b| b.bytes().all(|c| c == b'0' || c == b'1')) {
+        (binary, 2)
+    } else {
+        (lower.as_str(), 10)
+    };
+    i64::from_str_radix(digits, radix).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disassembler;
+
+    fn code(source: &str) -> Vec<u8> {
+        match assemble("test.s", source) {
+            Ok(assembly) => assembly.code,
+            Err(errors) => panic!("{}", errors.join("\n")),
+        }
+    }
+
+    fn errors(source: &str) -> Vec<String> {
+        match assemble("test.s", source) {
+            Ok(assembly) => panic!("assembled as {}", hex_bytes(&assembly.code)),
+            Err(errors) => errors,
+        }
+    }
+
+    // The documented instructions, with the text of the disassembler
+    fn instructions() -> Vec<String> {
+        const R: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+        const RP: [&str; 4] = ["BC", "DE", "HL", "SP"];
+        const ALU_TEXT: [&str; 8] = ["ADD A, ", "ADC A, ", "SUB ", "SBC A, ", "AND ", "XOR ", "OR ", "CP "];
+        let mut list: Vec<String> = "NOP HALT DI EI EXX DAA CPL SCF CCF RLCA RRCA RLA RRA NEG RETN RETI \
+            RRD RLD LDI CPI INI OUTI LDD CPD IND OUTD LDIR CPIR INIR OTIR LDDR CPDR INDR OTDR RET"
+            .split_whitespace().map(str::to_owned).collect();
+        for d in R.iter() {
+            for s in R.iter().filter(|s| !(*d == "(HL)" && **s == "(HL)")) {
+                list.push(format!("LD {}, {}", d, s));
+            }
+            list.push(format!("LD {}, 12h", d));
+        }
+        for x in ["IX", "IY"].iter() {
+            for r in R.iter().filter(|r| **r != "(HL)") {
+                list.push(format!("LD ({}+05h), {}", x, r));
+                list.push(format!("LD {}, ({}-03h)", r, x));
+            }
+            list.push(format!("LD ({}+05h), 12h", x));
+            for h in ["H", "L"].iter() {
+                for r in ["B", "C", "D", "E", "A", "H", "L"].iter() {
+                    let source = if r.len() == 1 && "HL".contains(*r) {format!("{}{}", x, r)} else {r.to_string()};
+                    list.push(format!("LD {}{}, {}", x, h, source));
+                }
+                list.push(format!("LD {}{}, 12h", x, h));
+            }
+            for text in ["LD {}, 1234h", "LD {}, (1234h)", "LD (1234h), {}", "LD SP, {}", "PUSH {}",
+                    "POP {}", "EX (SP), {}", "JP ({})", "INC {}", "DEC {}", "INC ({}+05h)",
+                    "DEC ({}+05h)", "INC {}H", "DEC {}L"].iter() {
+                list.push(text.replace("{}", x));
+            }
+            for p in ["BC", "DE", x, "SP"].iter() {
+                list.push(format!("ADD {}, {}", x, p));
+            }
+            for alu in ALU_TEXT.iter() {
+                list.push(format!("{}({}+05h)", alu, x));
+                list.push(format!("{}{}H", alu, x));
+            }
+            for rot in ROT.iter() {
+                list.push(format!("{} ({}+05h)", rot, x));
+            }
+            for b in 0..8 {
+                for op in ["BIT", "RES", "SET"].iter() {
+                    list.push(format!("{} {}, ({}+05h)", op, b, x));
+                }
+            }
+        }
+        for text in ["LD A, (BC)", "LD A, (DE)", "LD A, (1234h)", "LD (BC), A", "LD (DE), A",
+                "LD (1234h), A", "LD A, I", "LD A, R", "LD I, A", "LD R, A", "LD SP, HL",
+                "LD HL, (1234h)", "LD (1234h), HL", "EX DE, HL", "EX AF, AF'", "EX (SP), HL",
+                "JP (HL)", "IN A, (12h)", "OUT (12h), A", "IM 0", "IM 1", "IM 2", "JP 1234h",
+                "CALL 1234h", "DJNZ 0110h", "JR 00f0h"].iter() {
+            list.push(text.to_string());
+        }
+        for p in RP.iter() {
+            for text in ["LD {}, 1234h", "INC {}", "DEC {}", "ADD HL, {}", "ADC HL, {}", "SBC HL, {}"].iter() {
+                list.push(text.replace("{}", p));
+            }
+            if *p != "HL" {
+                list.push(format!("LD {}, (1234h)", p));
+                list.push(format!("LD (1234h), {}", p));
+            }
+        }
+        for p in ["BC", "DE", "HL", "AF"].iter() {
+            list.push(format!("PUSH {}", p));
+            list.push(format!("POP {}", p));
+        }
+        for r in R.iter() {
+            list.push(format!("INC {}", r));
+            list.push(format!("DEC {}", r));
+            for alu in ALU_TEXT.iter() {
+                list.push(format!("{}{}", alu, r));
+            }
+            for rot in ROT.iter() {
+                list.push(format!("{} {}", rot, r));
+            }
+            for b in 0..8 {
+                for op in ["BIT", "RES", "SET"].iter() {
+                    list.push(format!("{} {}, {}", op, b, r));
+                }
+            }
+            if *r != "(HL)" {
+                list.push(format!("IN {}, (C)", r));
+                list.push(format!("OUT (C), {}", r));
+            }
+        }
+        for alu in ALU_TEXT.iter() {
+            list.push(format!("{}12h", alu));
+        }
+        for (i, cc) in CONDITIONS.iter().enumerate() {
+            list.push(format!("JP {}, 1234h", cc));
+            list.push(format!("CALL {}, 1234h", cc));
+            list.push(format!("RET {}", cc));
+            if i < 4 {
+                list.push(format!("JR {}, 0180h", cc));
+            }
+        }
+        for v in (0..0x40).step_by(8) {
+            list.push(format!("RST {:02x}h", v));
+        }
+        list
+    }
+
+    #[test]
+    fn encodings_match_the_disassembler() {
+        for text in instructions() {
+            let bytes = code(&format!("\torg 0100h\n\t{}\n", text));
+            let peek = |address: u16| bytes.get(address.wrapping_sub(0x100) as usize).copied().unwrap_or(0);
+            let instruction = disassembler::disassemble(&peek, &|_| None, 0x100);
+            assert_eq!(instruction.text, text, "bytes {}", hex_bytes(&bytes));
+            assert_eq!(instruction.length as usize, bytes.len(), "length of {}", text);
+        }
+    }
+
+    #[test]
+    fn encodings() {
+        assert_eq!(code("ld a,(ix)"), [0xdd, 0x7e, 0x00]);
+        assert_eq!(code("ld (iy-128),255"), [0xfd, 0x36, 0x80, 0xff]);
+        assert_eq!(code("ex af,af'"), [0x08]);
+        assert_eq!(code("loop: djnz loop"), [0x10, 0xfe]);
+        assert_eq!(code("jr c,$+2"), [0x38, 0x00]);
+        assert_eq!(code("in a,(0x1a)\nout (c),a"), [0xdb, 0x1a, 0xed, 0x79]);
+    }
+
+    #[test]
+    fn expressions() {
+        assert_eq!(code("db 2+3*4, (2+3)*4, 1<<4|1, 7&~2, 17%5, -1"), [14, 20, 17, 5, 2, 0xff]);
+        assert_eq!(code("db 10h, $1f, 0x20, 101b, %11, 'A', '\\n'"), [0x10, 0x1f, 0x20, 5, 3, 0x41, 10]);
+        assert_eq!(code("org 8000h\ndw $, $+2"), [0x00, 0x80, 0x02, 0x80]);
+        assert_eq!(code("db 256/16>>1^1"), [9]);
+        assert!(errors("db 1/0")[0].contains("division by zero"));
+        assert!(errors("db 256")[0].contains("out of range"));
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(code("db \"a;b\", 0"), b"a;b\0");
+        assert_eq!(code("defm \"\\r\\n\\t\\0\\\\\\\"\\'\\x7f\""), b"\r\n\t\0\\\"'\x7f");
+        assert_eq!(code("db 'ab', 'c'+1"), b"abd");
+        assert!(errors("db \"\\q\"")[0].contains("invalid escape"));
+    }
+
+    #[test]
+    fn forward_references() {
+        let source = "\torg 100h\n\tld hl,LAST\n\tjr next\nnext:\tld de,SIZE\n\
+            SIZE:\tequ LAST-next\nLAST:\tequ END1+1\nEND1:\tnop\n";
+        assert_eq!(code(source), [0x21, 0x09, 0x01, 0x18, 0x00, 0x11, 0x04, 0x00, 0x00]);
+        let assembly = assemble("test.s", source).unwrap();
+        assert_eq!(assembly.symbols["LAST"], 0x0109);
+        assert_eq!(assembly.symbols["SIZE"], 4);
+    }
+
+    #[test]
+    fn phase_errors() {
+        let errors = errors("START: EQU BASE+100h\nBASE: EQU 1000h\n\torg START\n\tjp target\ntarget:\tnop\n");
+        assert_eq!(errors, ["test.s:3: the value must be defined before"]);
+        let errors = self::errors("\tds COUNT\nCOUNT: equ 2\n");
+        assert_eq!(errors, ["test.s:1: the value must be defined before"]);
+        let errors = self::errors("X1: equ X2\nX2: equ X1+1\n");
+        assert!(errors[0].contains("can't be resolved"));
+    }
+
+    #[test]
+    fn statement_errors() {
+        assert_eq!(errors("\tfoo a"), ["test.s:1: unknown instruction 'FOO'"]);
+        assert_eq!(errors("x: nop\nx: nop"), ["test.s:2: 'x' is already defined"]);
+        assert!(errors("\tjr 1000h")[0].contains("relative jump out of range"));
+        assert!(errors("\tld a,missing")[0].contains("undefined symbol 'missing'"));
+    }
+}
@@ -0,0 +1,242 @@
+use iz80::*;
+
+use super::KayproMachine;
+use super::pending_returns::PendingReturns;
+
+/*
+Trace of the calls to the CP/M BDOS on 0x0005. The arguments are decoded
+for each function: the FCB for the file functions, the string for
+C_WRITESTR and the characters for the console functions. The return
+values on A or HL are logged when the calls return, see PendingReturns.
+*/
+
+const BDOS_COMMAND_NAMES: [&str; 50] = [
+    // 0
+    "P_TERMCPM", "C_READ", "C_WRITE", "A_READ", "A_WRITE",
+    "L_WRITE", "C_RAWIO", "A_STATIN", "A_STATOUT", "C_WRITESTR",
+    // 10
+    "C_READSTR", "C_STAT", "S_BDOSVER", "DRV_ALLRESET", "DRV_SET",
+    "F_OPEN", "F_CLOSE", "F_SFIRST", "F_SNEXT", "F_DELETE",
+    // 20
+    "F_READ", "F_WRITE", "F_MAKE", "F_RENAME", "DRV_LOGINVEC",
+    "DRV_GET", "F_DMAOFF", "DRV_ALLOCVEC", "DRV_SETRO", "DRV_ROVEC",
+    // 30
+    "F_ATTRIB", "DRV_DPB", "F_USERNUM", "F_READRAND", "F_WRITERAND",
+    "F_SIZE", "F_RANDREC", "DRV_RESET", "*", "",
+    // 40
+    "F_WRITEZ", "", "", "", "",
+    "F_ERRMODE", "", "", "", "",
+    ];
+
+const P_TERMCPM: u8 = 0;
+const C_READ: u8 = 1;
+const C_WRITE: u8 = 2;
+const A_READ: u8 = 3;
+const A_WRITE: u8 = 4;
+const L_WRITE: u8 = 5;
+const C_RAWIO: u8 = 6;
+const C_WRITESTR: u8 = 9;
+const C_READSTR: u8 = 10;
+const S_BDOSVER: u8 = 12;
+const DRV_SET: u8 = 14;
+const F_OPEN: u8 = 15;
+const F_CLOSE: u8 = 16;
+const F_SFIRST: u8 = 17;
+const F_DELETE: u8 = 19;
+const F_READ: u8 = 20;
+const F_WRITE: u8 = 21;
+const F_MAKE: u8 = 22;
+const F_RENAME: u8 = 23;
+const DRV_LOGINVEC: u8 = 24;
+const F_DMAOFF: u8 = 26;
+const DRV_ALLOCVEC: u8 = 27;
+const DRV_ROVEC: u8 = 29;
+const F_ATTRIB: u8 = 30;
+const DRV_DPB: u8 = 31;
+const F_USERNUM: u8 = 32;
+const F_READRAND: u8 = 33;
+const F_WRITERAND: u8 = 34;
+const F_SIZE: u8 = 35;
+const F_RANDREC: u8 = 36;
+const F_WRITEZ: u8 = 40;
+
+// Longest string shown for C_WRITESTR and C_READSTR
+const MAX_STRING: u16 = 80;
+
+struct BdosCall {
+    command: u8,
+    args: u16,
+}
+
+pub struct BdosTrace {
+    filter: Option<Vec<u8>>,
+    pending: PendingReturns<BdosCall>,
+}
+
+impl BdosTrace {
+    pub fn new() -> BdosTrace {
+        BdosTrace {
+            filter: None,
+            pending: PendingReturns::new(),
+        }
+    }
+
+    /// Limits the trace to the functions in a comma separated list of
+    /// numbers or names.
+    pub fn set_filter(&mut self, filter: &str) -> Result<(), String> {
+        let mut commands = Vec::new();
+        for item in filter.split(',') {
+            let item = item.trim();
+            let command = match item.parse::<u8>() {
+                Ok(command) => command,
+                Err(_) => BDOS_COMMAND_NAMES.iter()
+                    .position(|name| !name.is_empty() && name.eq_ignore_ascii_case(item))
+                    .ok_or(format!("Unknown BDOS function '{}'", item))? as u8,
+            };
+            commands.push(command);
+        }
+        self.filter = Some(commands);
+        Ok(())
+    }
+
+    /// Called before each instruction
+    pub fn trace(&mut self, cpu: &mut Cpu, machine: &KayproMachine) {
+        if machine.is_rom_rank() {
+            return;
+        }
+        let regs = cpu.registers();
+        let pc = regs.pc();
+        let sp = regs.get16(Reg16::SP);
+
+        if let Some(call) = self.pending.returned(pc, sp) {
+            print_return(&call, regs, machine);
+        }
+
+        if pc != 0x0005 {
+            return;
+        }
+        let command = regs.get8(Reg8::C);
+        if let Some(filter) = &self.filter {
+            if !filter.contains(&command) {
+                return;
+            }
+        }
+        let args = regs.get16(Reg16::DE);
+        trace!("bdos", "BDOS command {}: {}({:04x}){}", command, command_name(command),
+            args, describe_args(command, args, machine));
+
+        let call = BdosCall {command, args};
+        self.pending.called((command != P_TERMCPM).then_some(call), sp, machine);
+    }
+}
+
+pub fn command_name(command: u8) -> &'static str {
+    match BDOS_COMMAND_NAMES.get(command as usize) {
+        Some(name) if !name.is_empty() => name,
+        _ => "unknown",
+    }
+}
+
+fn describe_args(command: u8, args: u16, machine: &KayproMachine) -> String {
+    let e = args as u8;
+    match command {
+        C_WRITE | A_WRITE | L_WRITE => format!(" {}", describe_char(e)),
+        C_RAWIO => match e {
+            0xff => " input".to_owned(),
+            0xfe => " status".to_owned(),
+            _ => format!(" {}", describe_char(e)),
+        },
+        C_WRITESTR => format!(" \"{}\"", read_string(machine, args, Some(b'$'), MAX_STRING)),
+        C_READSTR => format!(" max {}", machine.peek(args)),
+        DRV_SET => format!(" {}:", drive_letter(e)),
+        F_USERNUM => if e == 0xff {" get".to_owned()} else {format!(" set {}", e)},
+        F_OPEN | F_CLOSE | F_SFIRST | F_DELETE | F_MAKE | F_ATTRIB => {
+            format!(" {}", describe_fcb(machine, args))
+        }
+        F_READ | F_WRITE => {
+            format!(" {} ex {} cr {}", describe_fcb(machine, args),
+                machine.peek(args.wrapping_add(12)), machine.peek(args.wrapping_add(32)))
+        }
+        F_RENAME => {
+            format!(" {} to {}", describe_fcb(machine, args),
+                describe_fcb(machine, args.wrapping_add(16)))
+        }
+        F_READRAND | F_WRITERAND | F_WRITEZ => {
+            format!(" {} record {}", describe_fcb(machine, args),
+                random_record(machine, args))
+        }
+        F_SIZE | F_RANDREC => format!(" {}", describe_fcb(machine, args)),
+        _ => String::new(),
+    }
+}
+
+fn print_return(call: &BdosCall, regs: &Registers, machine: &KayproMachine) {
+    let command = call.command;
+    let a = regs.get8(Reg8::A);
+    let hl = regs.get16(Reg16::HL);
+    let result = match command {
+        C_READ | A_READ => describe_char(a),
+        C_RAWIO if call.args as u8 == 0xff => describe_char(a),
+        C_READSTR => {
+            let len = machine.peek(call.args.wrapping_add(1)) as u16;
+            format!("\"{}\"", read_string(machine, call.args.wrapping_add(2), None, len.min(MAX_STRING)))
+        }
+        S_BDOSVER | DRV_LOGINVEC | DRV_ALLOCVEC | DRV_ROVEC | DRV_DPB => format!("{:04x}", hl),
+        F_SIZE | F_RANDREC => format!("record {}", random_record(machine, call.args)),
+        F_DMAOFF | C_WRITE | A_WRITE | L_WRITE | C_WRITESTR | DRV_SET => return,
+        _ => format!("{:02x}", a),
+    };
+    trace!("bdos", "BDOS return {}: {} -> {}", command, command_name(command), result);
+}
+
+fn describe_fcb(machine: &KayproMachine, address: u16) -> String {
+    let drive = machine.peek(address);
+    let name: String = (1..12)
+        .map(|i| machine.peek(address.wrapping_add(i)) & 0x7f)
+        .map(|c| if (0x20..0x7f).contains(&c) {c as char} else {'?'})
+        .collect();
+    let drive = if drive == 0 {
+        String::new()
+    } else {
+        format!("{}:", drive_letter(drive - 1))
+    };
+    format!("{}{}.{}", drive, name[..8].trim_end(), name[8..].trim_end())
+}
+
+fn random_record(machine: &KayproMachine, fcb: u16) -> u32 {
+    machine.peek(fcb.wrapping_add(33)) as u32
+        + ((machine.peek(fcb.wrapping_add(34)) as u32) << 8)
+        + ((machine.peek(fcb.wrapping_add(35)) as u32) << 16)
+}
+
+fn read_string(machine: &KayproMachine, address: u16, terminator: Option<u8>, max: u16) -> String {
+    let mut text = String::new();
+    for i in 0..max {
+        let c = machine.peek(address.wrapping_add(i));
+        if terminator == Some(c) {
+            return text;
+        }
+        match c {
+            0x0d => text += "\\r",
+            0x0a => text += "\\n",
+            0x20..=0x7e => text.push(c as char),
+            _ => text += &format!("\\x{:02x}", c),
+        }
+    }
+    if terminator.is_some() {
+        text += "...";
+    }
+    text
+}
+
+fn drive_letter(drive: u8) -> char {
+    (b'A' + (drive & 0x0f)) as char
+}
+
+pub fn describe_char(c: u8) -> String {
+    if (0x20..0x7f).contains(&c) {
+        format!("'{}' {:02x}", c as char, c)
+    } else {
+        format!("{:02x}", c)
+    }
+}
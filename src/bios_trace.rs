@@ -0,0 +1,119 @@
+use iz80::*;
+
+use super::KayproMachine;
+use super::bdos_trace::describe_char;
+use super::pending_returns::PendingReturns;
+
+/*
+Trace of the calls to the CP/M BIOS. The BIOS jump table is found with
+the warm boot vector at 0x0000, as it points to the second entry of the
+table. It is looked up on every instruction, to follow the CP/M versions
+that relocate the BIOS.
+
+The return values are logged when the calls return, see PendingReturns.
+*/
+
+const BIOS_ENTRY_NAMES: [&str; 17] = [
+    "BOOT", "WBOOT", "CONST", "CONIN", "CONOUT", "LIST", "PUNCH", "READER",
+    "HOME", "SELDSK", "SETTRK", "SETSEC", "SETDMA", "READ", "WRITE",
+    "LISTST", "SECTRAN"];
+
+const CONST: usize = 2;
+const CONIN: usize = 3;
+pub const CONOUT: usize = 4;
+const LIST: usize = 5;
+const PUNCH: usize = 6;
+const READER: usize = 7;
+const SELDSK: usize = 9;
+const SETTRK: usize = 10;
+const SETSEC: usize = 11;
+const SETDMA: usize = 12;
+const READ: usize = 13;
+const WRITE: usize = 14;
+const LISTST: usize = 15;
+const SECTRAN: usize = 16;
+
+pub struct BiosTrace {
+    // Entries called
+    pending: PendingReturns<usize>,
+}
+
+impl BiosTrace {
+    pub fn new() -> BiosTrace {
+        BiosTrace {
+            pending: PendingReturns::new(),
+        }
+    }
+
+    /// Called before each instruction
+    pub fn trace(&mut self, cpu: &mut Cpu, machine: &KayproMachine) {
+        let regs = cpu.registers();
+        let pc = regs.pc();
+        let sp = regs.get16(Reg16::SP);
+
+        if let Some(entry) = self.pending.returned(pc, sp) {
+            print_return(entry, regs);
+        }
+
+        let entry = match entry_at(machine, pc) {
+            Some(entry) => entry,
+            None => return,
+        };
+        let name = BIOS_ENTRY_NAMES[entry];
+        let bc = regs.get16(Reg16::BC);
+        let c = regs.get8(Reg8::C);
+        match entry {
+            CONOUT | LIST | PUNCH => trace!("bios", "BIOS {} {}", name, describe_char(c)),
+            SELDSK => trace!("bios", "BIOS {} {}: {}", name, (b'A' + (c & 0x0f)) as char,
+                if regs.get8(Reg8::E) & 1 == 0 {"first select"} else {"logged"}),
+            SETTRK | SETSEC => trace!("bios", "BIOS {} {}", name, bc),
+            SETDMA => trace!("bios", "BIOS {} {:04x}", name, bc),
+            WRITE => trace!("bios", "BIOS {} type {}", name, c),
+            SECTRAN => trace!("bios", "BIOS {} {} table {:04x}", name, bc, regs.get16(Reg16::DE)),
+            _ => trace!("bios", "BIOS {}", name),
+        }
+
+        let returns = matches!(entry, CONST | CONIN | READER | SELDSK | READ | WRITE | LISTST | SECTRAN);
+        self.pending.called(returns.then_some(entry), sp, machine);
+    }
+}
+
+/// Index of the BIOS entry point at pc, if any
+pub fn entry_at(machine: &KayproMachine, pc: u16) -> Option<usize> {
+    // The BIOS is in RAM, also visible above 0x4000 with the ROM selected
+    if machine.is_rom_rank() && pc < 0x4000 {
+        return None;
+    }
+    if machine.peek_bank(false, 0x0000) != 0xc3 /* JP */ {
+        return None;
+    }
+    let base = machine.peek_bank(false, 0x0001) as u16
+        + ((machine.peek_bank(false, 0x0002) as u16) << 8);
+    let base = base.wrapping_sub(3);
+    let offset = pc.wrapping_sub(base);
+    if !offset.is_multiple_of(3) || offset as usize / 3 >= BIOS_ENTRY_NAMES.len() {
+        return None;
+    }
+    Some(offset as usize / 3)
+}
+
+pub fn entry_name(entry: usize) -> &'static str {
+    BIOS_ENTRY_NAMES[entry]
+}
+
+fn print_return(entry: usize, regs: &Registers) {
+    let name = BIOS_ENTRY_NAMES[entry];
+    let a = regs.get8(Reg8::A);
+    let hl = regs.get16(Reg16::HL);
+    match entry {
+        CONST | LISTST => trace!("bios", "BIOS {} -> {}", name, if a == 0 {"not ready"} else {"ready"}),
+        CONIN | READER => trace!("bios", "BIOS {} -> {}", name, describe_char(a)),
+        SELDSK => if hl == 0 {
+            trace!("bios", "BIOS {} -> error", name);
+        } else {
+            trace!("bios", "BIOS {} -> DPH {:04x}", name, hl);
+        },
+        READ | WRITE => trace!("bios", "BIOS {} -> {}", name, if a == 0 {"ok"} else {"error"}),
+        _ => trace!("bios", "BIOS {} -> {}", name, hl),
+    }
+}
@@ -0,0 +1,267 @@
+use std::fs::{self, File};
+use std::io::{Error, Result, Write};
+
+use super::KayproMachine;
+use super::address;
+use super::disassembler;
+
+/*
+Code coverage of an address range, like the TPA of a program under test.
+The range is given as [rom:|ram:]start-end in hex, the RAM bank by
+default. For each address it counts the times an instruction started
+there and the times the next instruction wasn't the following one, to
+tell the branches taken from the ones not taken.
+
+On exit the coverage is written as an annotated listing or in the lcov
+format. The listing of the assembler, like the .prn of z80asm, maps the
+addresses to the lines; each line starts with the address and the bytes
+generated, as in:
+    0100 11 14 01		ld de, message
+Without a listing, the range is disassembled from memory. The lcov
+output needs a listing and uses its line numbers.
+
+The annotated listing marks each line like gcov: the count for
+executed lines, ##### for lines of code that were never executed and
+- for the other lines. The lines with bytes are code if they decode as
+an instruction and are not DB, DW or DS directives, or if they were
+executed. Conditional jumps, calls and returns get the times taken and
+not taken.
+*/
+
+const DATA_DIRECTIVES: [&str; 7] = ["DB", "DEFB", "DEFM", "DW", "DEFW", "DS", "DEFS"];
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum CoverageFormat {
+    Listing,
+    Lcov,
+}
+
+impl CoverageFormat {
+    pub fn parse(text: &str) -> Option<CoverageFormat> {
+        match text {
+            "listing" => Some(CoverageFormat::Listing),
+            "lcov" => Some(CoverageFormat::Lcov),
+            _ => None,
+        }
+    }
+}
+
+pub struct Coverage {
+    start: u16,
+    end: u16,
+    rom_rank: bool,
+    executed: Vec<u64>,
+    jumped: Vec<u64>,
+}
+
+struct ListingLine {
+    text: String,
+    address: Option<u16>,
+    bytes: Vec<u8>,
+    // Bytes of an instruction, not data
+    code: bool,
+}
+
+impl Coverage {
+    pub fn new(range: &str) -> std::result::Result<Coverage, String> {
+        let parsed = address::parse_range(range).ok().filter(|_| range.contains('-'));
+        match parsed {
+            Some((rom_rank, start, end)) => Ok(Coverage {
+                start,
+                end,
+                rom_rank: rom_rank.unwrap_or(false),
+                executed: vec![0; (end - start) as usize + 1],
+                jumped: vec![0; (end - start) as usize + 1],
+            }),
+            None => Err(format!("Invalid coverage range '{}'", range)),
+        }
+    }
+
+    /// Called after executing the instruction at pc, next_pc is the
+    /// address of the next instruction
+    pub fn record(&mut self, pc: u16, rom_rank: bool, next_pc: u16, machine: &KayproMachine) {
+        if rom_rank != self.rom_rank || pc < self.start || pc > self.end {
+            return;
+        }
+        let index = (pc - self.start) as usize;
+        self.executed[index] += 1;
+        let peek = |address| machine.peek_bank(rom_rank, address);
+        if is_conditional_branch(peek(pc)) {
+            let instruction = disassembler::disassemble(&peek, &|_| None, pc);
+            if next_pc != pc.wrapping_add(instruction.length) {
+                self.jumped[index] += 1;
+            }
+        }
+    }
+
+    fn count(&self, address: u16) -> u64 {
+        if address < self.start || address > self.end {
+            0
+        } else {
+            self.executed[(address - self.start) as usize]
+        }
+    }
+
+    /// Times taken and not taken, for conditional branches
+    fn branches(&self, address: u16, opcode: u8) -> Option<(u64, u64)> {
+        if !is_conditional_branch(opcode) || address < self.start || address > self.end {
+            return None;
+        }
+        let index = (address - self.start) as usize;
+        Some((self.jumped[index], self.executed[index] - self.jumped[index]))
+    }
+
+    fn is_code(&self, line: &ListingLine, address: u16) -> bool {
+        !line.bytes.is_empty() && (line.code || self.count(address) > 0)
+    }
+
+    pub fn write(&self, filename: &str, format: CoverageFormat, listing: Option<&str>,
+            machine: &KayproMachine) -> Result<()> {
+        let lines = match listing {
+            Some(listing) => parse_listing(listing)?,
+            None => self.disassemble(machine),
+        };
+        let mut file = File::create(filename)?;
+        match format {
+            CoverageFormat::Listing => self.write_listing(&mut file, &lines),
+            CoverageFormat::Lcov => match listing {
+                Some(listing) => self.write_lcov(&mut file, &lines, listing),
+                None => Err(Error::other("The lcov format needs a listing")),
+            },
+        }
+    }
+
+    fn disassemble(&self, machine: &KayproMachine) -> Vec<ListingLine> {
+        let peek = |address| machine.peek_bank(self.rom_rank, address);
+        let symbol = |_| None;
+        let mut lines = Vec::new();
+        let mut address = self.start as u32;
+        while address <= self.end as u32 {
+            let instruction = disassembler::disassemble(&peek, &symbol, address as u16);
+            let bytes: Vec<u8> = (0..instruction.length)
+                .map(|i| peek(instruction.address.wrapping_add(i)))
+                .collect();
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            lines.push(ListingLine {
+                text: format!("{:04x} {:12} {}", address, hex.join(" "), instruction.text),
+                address: Some(address as u16),
+                bytes,
+                code: true,
+            });
+            address += instruction.length as u32;
+        }
+        lines
+    }
+
+    fn write_listing(&self, file: &mut File, lines: &[ListingLine]) -> Result<()> {
+        let mut total = 0;
+        let mut hit = 0;
+        let mut annotated = Vec::new();
+        for line in lines {
+            let (marker, branches) = match line.address {
+                Some(address) if self.is_code(line, address) => {
+                    let count = self.count(address);
+                    total += 1;
+                    if count > 0 {
+                        hit += 1;
+                    }
+                    let branches = self.branches(address, line.bytes[0]).map(|(taken, not_taken)|
+                        format!("    ; taken {}, not taken {}", taken, not_taken));
+                    (if count == 0 {"#####".to_owned()} else {count.to_string()}, branches)
+                }
+                _ => ("-".to_owned(), None),
+            };
+            annotated.push(format!("{:>10}: {}{}", marker, line.text, branches.unwrap_or_default()));
+        }
+        writeln!(file, "Lines executed: {} of {} ({:.1}%)", hit, total,
+            if total == 0 {0.0} else {hit as f64 * 100.0 / total as f64})?;
+        for line in annotated {
+            writeln!(file, "{}", line)?;
+        }
+        Ok(())
+    }
+
+    fn write_lcov(&self, file: &mut File, lines: &[ListingLine], listing: &str) -> Result<()> {
+        writeln!(file, "TN:")?;
+        writeln!(file, "SF:{}", listing)?;
+        let (mut found, mut hit, mut branches_found, mut branches_hit) = (0, 0, 0, 0);
+        for (number, line) in lines.iter().enumerate() {
+            let address = match line.address {
+                Some(address) if self.is_code(line, address) => address,
+                _ => continue,
+            };
+            let count = self.count(address);
+            writeln!(file, "DA:{},{}", number + 1, count)?;
+            found += 1;
+            if count > 0 {
+                hit += 1;
+            }
+            if let Some((taken, not_taken)) = self.branches(address, line.bytes[0]) {
+                for (branch, times) in [taken, not_taken].iter().enumerate() {
+                    // "-" for the branches of lines never executed
+                    let times = if count == 0 {"-".to_owned()} else {times.to_string()};
+                    writeln!(file, "BRDA:{},0,{},{}", number + 1, branch, times)?;
+                    branches_found += 1;
+                    if times != "-" && times != "0" {
+                        branches_hit += 1;
+                    }
+                }
+            }
+        }
+        writeln!(file, "BRF:{}", branches_found)?;
+        writeln!(file, "BRH:{}", branches_hit)?;
+        writeln!(file, "LF:{}", found)?;
+        writeln!(file, "LH:{}", hit)?;
+        writeln!(file, "end_of_record")?;
+        Ok(())
+    }
+}
+
+fn parse_listing(filename: &str) -> Result<Vec<ListingLine>> {
+    let content = fs::read(filename)?;
+    let content = String::from_utf8_lossy(&content);
+    Ok(content.lines().map(parse_listing_line).collect())
+}
+
+fn parse_listing_line(line: &str) -> ListingLine {
+    // The address and bytes are before the source, separated by a tab
+    let (prefix, source) = line.split_once('\t').unwrap_or((line, ""));
+    let mut tokens = prefix.split_whitespace();
+    let address = tokens.next().and_then(address::parse_listing_address);
+    let bytes = if address.is_some() {
+        tokens.take(4)
+            .map_while(|token| if token.len() == 2 {u8::from_str_radix(token, 16).ok()} else {None})
+            .collect()
+    } else {
+        Vec::new()
+    };
+    // The lines that continue the bytes of a statement have no source
+    let code = !source.trim().is_empty() && !is_data_directive(source) && {
+        let peek = |address: u16| bytes.get(address as usize).copied().unwrap_or(0);
+        disassembler::disassemble(&peek, &|_| None, 0).length as usize == bytes.len()
+    };
+    ListingLine {
+        text: line.to_owned(),
+        address,
+        bytes,
+        code,
+    }
+}
+
+// Source line with DB, DW or DS, after the optional label
+fn is_data_directive(source: &str) -> bool {
+    let mut words = source.split(';').next().unwrap_or("").split_whitespace();
+    let first = words.next().unwrap_or("");
+    let mnemonic = if first.ends_with(':') {
+        words.next().unwrap_or("")
+    } else {
+        first
+    };
+    DATA_DIRECTIVES.iter().any(|directive| directive.eq_ignore_ascii_case(mnemonic))
+}
+
+// JR cc, DJNZ, JP cc, CALL cc and RET cc
+fn is_conditional_branch(opcode: u8) -> bool {
+    matches!(opcode, 0x10 | 0x20 | 0x28 | 0x30 | 0x38)
+        || matches!(opcode & 0xc7, 0xc0 | 0xc2 | 0xc4)
+}
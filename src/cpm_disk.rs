@@ -0,0 +1,195 @@
+use std::io::{Error, Result};
+
+/*
+Files on the CP/M 2.2 filesystem of the SSDD disk images, as seen by
+the Kaypro II BIOS (like "-f kpii" on cpmtools):
+    The first track is reserved for the system.
+    The blocks have 1024 bytes, there are 195 blocks after the system
+    track. The directory is on the first 2 blocks, with 64 entries of
+    32 bytes. The next 2 blocks are reserved too, the boot loader reads
+    the end of the system from them.
+    Each directory entry is an extent with up to 16 blocks, 128 records
+    of 128 bytes.
+There is no skew, the sectors are in order on the image.
+*/
+
+const SYSTEM_TRACK_SIZE: usize = 10 * 512;
+const BLOCK_SIZE: usize = 1024;
+const BLOCKS: usize = 195;
+const RESERVED_BLOCKS: usize = 4;
+const DIRECTORY_ENTRIES: usize = 64;
+const ENTRY_SIZE: usize = 32;
+const BLOCKS_PER_EXTENT: usize = 16;
+const RECORD_SIZE: usize = 128;
+const UNUSED: u8 = 0xe5;
+
+/// Name and extension as on the directory entries, from a host path
+pub fn cpm_name(path: &str) -> Result<[u8; 11]> {
+    let filename = path.rsplit('/').next().unwrap_or(path).to_ascii_uppercase();
+    let (name, extension) = filename.split_once('.').unwrap_or((&filename, ""));
+    let valid = |part: &str, len: usize| !part.is_empty() && part.len() <= len
+        && part.bytes().all(|c| c.is_ascii_graphic() && !b"<>.,;:=?*[]_%|()/\\".contains(&c));
+    if !valid(name, 8) || !(extension.is_empty() || valid(extension, 3)) {
+        return Err(Error::other(format!("'{}' is not a valid CP/M file name", filename)));
+    }
+    let mut entry_name = [b' '; 11];
+    entry_name[..name.len()].copy_from_slice(name.as_bytes());
+    entry_name[8..8 + extension.len()].copy_from_slice(extension.as_bytes());
+    Ok(entry_name)
+}
+
+/// Adds a file for the user 0 on a disk image
+pub fn add_file(image: &mut [u8], name: &[u8; 11], data: &[u8]) -> Result<()> {
+    if image.len() < SYSTEM_TRACK_SIZE + BLOCKS * BLOCK_SIZE {
+        return Err(Error::other("The disk image is too small"));
+    }
+
+    // Blocks and entries in use
+    let mut used_blocks = [false; BLOCKS];
+    used_blocks[..RESERVED_BLOCKS].fill(true);
+    let mut free_entries = Vec::new();
+    for index in 0..DIRECTORY_ENTRIES {
+        let entry = &image[entry_offset(index)..entry_offset(index) + ENTRY_SIZE];
+        if entry[0] == UNUSED {
+            free_entries.push(index);
+        } else if entry[0] < 16 {
+            if entry[0] == 0 && entry[1..12] == name[..] {
+                return Err(Error::other("The file is already on the disk"));
+            }
+            for &block in entry[16..].iter() {
+                if block != 0 && (block as usize) < BLOCKS {
+                    used_blocks[block as usize] = true;
+                }
+            }
+        }
+    }
+
+    let records = data.len().div_ceil(RECORD_SIZE);
+    let blocks_needed = data.len().div_ceil(BLOCK_SIZE);
+    let extents = blocks_needed.div_ceil(BLOCKS_PER_EXTENT).max(1);
+    let free_blocks: Vec<usize> = (0..BLOCKS).filter(|b| !used_blocks[*b]).collect();
+    if free_blocks.len() < blocks_needed || free_entries.len() < extents {
+        return Err(Error::other("Not enough space on the disk"));
+    }
+
+    for (block, chunk) in free_blocks.iter().zip(data.chunks(BLOCK_SIZE)) {
+        let offset = block_offset(*block);
+        image[offset..offset + BLOCK_SIZE].fill(0x1a); // ^Z, end of text files
+        image[offset..offset + chunk.len()].copy_from_slice(chunk);
+    }
+
+    for (extent, index) in free_entries.iter().take(extents).enumerate() {
+        let offset = entry_offset(*index);
+        let entry = &mut image[offset..offset + ENTRY_SIZE];
+        entry.fill(0);
+        entry[1..12].copy_from_slice(name);
+        entry[12] = (extent & 0x1f) as u8; // EX
+        entry[14] = (extent >> 5) as u8; // S2
+        let first_record = extent * BLOCKS_PER_EXTENT * BLOCK_SIZE / RECORD_SIZE;
+        entry[15] = (records - first_record.min(records)).min(0x80) as u8; // RC
+        let first_block = extent * BLOCKS_PER_EXTENT;
+        for i in 0..BLOCKS_PER_EXTENT {
+            if first_block + i < blocks_needed {
+                entry[16 + i] = free_blocks[first_block + i] as u8;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn block_offset(block: usize) -> usize {
+    SYSTEM_TRACK_SIZE + block * BLOCK_SIZE
+}
+
+fn entry_offset(index: usize) -> usize {
+    block_offset(0) + index * ENTRY_SIZE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blank_image() -> Vec<u8> {
+        vec![UNUSED; SYSTEM_TRACK_SIZE + BLOCKS * BLOCK_SIZE]
+    }
+
+    fn entry(image: &[u8], index: usize) -> &[u8] {
+        &image[entry_offset(index)..entry_offset(index) + ENTRY_SIZE]
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(&cpm_name("dir/hello.com").unwrap(), b"HELLO   COM");
+        assert_eq!(&cpm_name("README").unwrap(), b"README     ");
+        assert!(cpm_name("toolongname.com").is_err());
+        assert!(cpm_name("file.long").is_err());
+        assert!(cpm_name("a_b.com").is_err());
+        assert!(cpm_name(".com").is_err());
+    }
+
+    #[test]
+    fn add_files() {
+        let mut image = blank_image();
+        let data: Vec<u8> = (0..20000).map(|i| i as u8).collect();
+        add_file(&mut image, b"DATA    BIN", &data).unwrap();
+
+        // 157 records on 20 blocks, two extents
+        let first = entry(&image, 0);
+        assert_eq!(first[0], 0);
+        assert_eq!(&first[1..12], b"DATA    BIN");
+        assert_eq!((first[12], first[14], first[15]), (0, 0, 0x80));
+        assert_eq!(first[16..].to_vec(), (4..20).collect::<Vec<u8>>());
+        let second = entry(&image, 1);
+        assert_eq!(&second[1..12], b"DATA    BIN");
+        assert_eq!((second[12], second[14], second[15]), (1, 0, 29));
+        assert_eq!(&second[16..], &[20, 21, 22, 23, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(entry(&image, 2)[0], UNUSED);
+
+        // The content, padded with ^Z to the end of the last block
+        let content: Vec<u8> = (4..24)
+            .flat_map(|block| image[block_offset(block)..block_offset(block) + BLOCK_SIZE].to_vec())
+            .collect();
+        assert_eq!(&content[..data.len()], &data[..]);
+        assert!(content[data.len()..].iter().all(|b| *b == 0x1a));
+
+        // The next file goes after, an empty file takes an entry and no blocks
+        add_file(&mut image, b"SMALL   TXT", b"hello").unwrap();
+        let small = entry(&image, 2);
+        assert_eq!((small[12], small[15], small[16], small[17]), (0, 1, 24, 0));
+        add_file(&mut image, b"EMPTY      ", &[]).unwrap();
+        let empty = entry(&image, 3);
+        assert_eq!(&empty[1..12], b"EMPTY      ");
+        assert!(empty[12..].iter().all(|b| *b == 0));
+
+        assert!(add_file(&mut image, b"SMALL   TXT", b"again").is_err());
+        assert!(add_file(&mut vec![UNUSED; SYSTEM_TRACK_SIZE], b"SMALL   TXT", b"").is_err());
+    }
+
+    #[test]
+    fn disk_full() {
+        let mut image = blank_image();
+        add_file(&mut image, b"SMALL   TXT", b"hello").unwrap();
+        let free = BLOCKS - RESERVED_BLOCKS - 1;
+        let before = image.clone();
+        assert!(add_file(&mut image, b"BIG     BIN", &vec![0; free * BLOCK_SIZE + 1]).is_err());
+        assert!(image == before);
+        add_file(&mut image, b"BIG     BIN", &vec![0; free * BLOCK_SIZE]).unwrap();
+        assert_eq!(entry(&image, 12)[15], (free * BLOCK_SIZE / RECORD_SIZE % 0x80) as u8);
+        assert!(add_file(&mut image, b"MORE    TXT", b"x").is_err());
+        assert!(add_file(&mut image, b"EMPTY      ", &[]).is_ok());
+    }
+
+    #[test]
+    fn directory_full() {
+        let mut image = blank_image();
+        for index in 0..DIRECTORY_ENTRIES {
+            let mut name = [0; 11];
+            name.copy_from_slice(format!("FILE{:<4}TXT", index).as_bytes());
+            add_file(&mut image, &name, b"x").unwrap();
+        }
+        let before = image.clone();
+        assert!(add_file(&mut image, b"ONE     MOR", b"x").is_err());
+        assert!(add_file(&mut image, b"EMPTY      ", &[]).is_err());
+        assert!(image == before);
+    }
+}
@@ -0,0 +1,24 @@
+use super::KayproMachine;
+
+/*
+Layout of CP/M on RAM, as seen from the programs. The BDOS vector at
+0x0005 points 6 bytes after the start of the BDOS, and the CCP is right
+below the BDOS.
+*/
+
+pub const TPA: u16 = 0x0100;
+const CCP_SIZE: u16 = 0x0800;
+const BDOS_VECTOR: u16 = 0x0006;
+const BDOS_VECTOR_OFFSET: u16 = 6;
+
+/// Start of the CCP, found from the BDOS vector
+pub fn ccp_start(machine: &KayproMachine) -> u16 {
+    let bdos = machine.peek_bank(false, BDOS_VECTOR) as u16
+        + ((machine.peek_bank(false, BDOS_VECTOR + 1) as u16) << 8);
+    bdos.wrapping_sub(BDOS_VECTOR_OFFSET + CCP_SIZE)
+}
+
+/// True if the address is on the CCP that starts at ccp
+pub fn is_ccp(ccp: u16, address: u16) -> bool {
+    address >= ccp && address < ccp.wrapping_add(CCP_SIZE)
+}
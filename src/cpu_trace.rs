@@ -0,0 +1,63 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use iz80::*;
+
+use super::KayproMachine;
+use super::disassembler;
+use super::symbols::Symbols;
+
+/*
+CPU trace with the names of the symbols. It replaces the iz80 trace when
+symbol files are loaded or the traces go to a file, with the same
+registers after the execution of each instruction.
+*/
+
+// iz80 has no getter for its own trace, it is kept here
+static IZ80_TRACE: AtomicBool = AtomicBool::new(false);
+
+/// Turns the trace of iz80 on or off.
+pub fn set_iz80_trace(cpu: &mut Cpu, trace: bool) {
+    IZ80_TRACE.store(trace, Ordering::Relaxed);
+    cpu.set_trace(trace);
+}
+
+/// True if iz80 prints the trace of the instructions
+pub fn is_iz80_trace() -> bool {
+    IZ80_TRACE.load(Ordering::Relaxed)
+}
+
+pub struct TracedInstruction {
+    pc: u16,
+    location: String,
+    text: String,
+}
+
+/// Decodes the instruction at pc, to be called before executing it.
+pub fn before(machine: &KayproMachine, symbols: &Symbols, pc: u16) -> TracedInstruction {
+    let rom_rank = machine.is_rom_rank();
+    let peek = |address| machine.peek(address);
+    let symbol = |address| symbols.name(address, rom_rank);
+    TracedInstruction {
+        pc,
+        location: symbols.describe(pc, rom_rank).unwrap_or_default(),
+        text: disassembler::disassemble(&peek, &symbol, pc).text,
+    }
+}
+
+/// Prints the trace line with the registers after the execution.
+pub fn after(cpu: &mut Cpu, machine: &KayproMachine, traced: TracedInstruction) {
+    let regs = cpu.registers();
+    let pc = traced.pc;
+    trace!("cpu", "==> {:04x} {:16}: {:20} PC:{:04x} AF:{:04x} BC:{:04x} DE:{:04x} HL:{:04x} SP:{:04x} IX:{:04x} IY:{:04x} Flags:{:08b} [{:02x} {:02x} {:02x}]",
+        pc, traced.location, traced.text,
+        regs.pc(),
+        regs.get16(Reg16::AF),
+        regs.get16(Reg16::BC),
+        regs.get16(Reg16::DE),
+        regs.get16(Reg16::HL),
+        regs.get16(Reg16::SP),
+        regs.get16(Reg16::IX),
+        regs.get16(Reg16::IY),
+        regs.get8(Reg8::F),
+        machine.peek(pc), machine.peek(pc.wrapping_add(1)), machine.peek(pc.wrapping_add(2)));
+}
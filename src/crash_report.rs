@@ -0,0 +1,166 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{Result, Write};
+
+use iz80::*;
+
+use super::KayproMachine;
+use super::debug_panes::DebugPanes;
+use super::debugger::Debugger;
+use super::disassembler;
+use super::symbols::Symbols;
+
+/*
+Report written when the emulation can't go on: on a HALT that will never
+be interrupted or on a panic of the emulator. It has the last
+instructions executed, the registers, code, stack and floppy controller
+state as on the debug panes, and the last port accesses.
+
+The instructions are kept with their bytes, to be disassembled as they
+were executed even if the memory is modified later. Repeated accesses to
+the same port, like the data transfers of the floppy controller, are
+counted on a single entry.
+*/
+
+pub const CRASH_REPORT_FILE: &str = "crash_report.txt";
+
+const IO_HISTORY_DEPTH: usize = 32;
+
+struct Executed {
+    counter: u64,
+    pc: u16,
+    rom_rank: bool,
+    bytes: [u8; 4],
+}
+
+pub struct History {
+    depth: usize,
+    instructions: VecDeque<Executed>,
+}
+
+impl History {
+    pub fn new(depth: usize) -> History {
+        History {
+            depth,
+            instructions: VecDeque::with_capacity(depth),
+        }
+    }
+
+    /// Called before executing the instruction at pc
+    pub fn record(&mut self, counter: u64, pc: u16, machine: &KayproMachine) {
+        if self.depth == 0 {
+            return;
+        }
+        if self.instructions.len() == self.depth {
+            self.instructions.pop_front();
+        }
+        let mut bytes = [0; 4];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = machine.peek(pc.wrapping_add(i as u16));
+        }
+        self.instructions.push_back(Executed {
+            counter,
+            pc,
+            rom_rank: machine.is_rom_rank(),
+            bytes,
+        });
+    }
+
+    fn lines(&self, symbols: &Symbols) -> Vec<String> {
+        let mut lines = Vec::new();
+        for executed in self.instructions.iter() {
+            let pc = executed.pc;
+            let peek = |address: u16| executed.bytes[address.wrapping_sub(pc) as usize & 3];
+            let symbol = |address| symbols.name(address, executed.rom_rank);
+            let text = disassembler::disassemble(&peek, &symbol, pc).text;
+            lines.push(format!("{:10} {} {:04x} {:16} {}", executed.counter,
+                if executed.rom_rank {"ROM"} else {"RAM"}, pc,
+                symbols.describe(pc, executed.rom_rank).unwrap_or_default(), text));
+        }
+        lines
+    }
+}
+
+struct IoAccess {
+    out: bool,
+    port: u8,
+    value: u8,
+    count: usize,
+}
+
+pub struct IoHistory {
+    accesses: VecDeque<IoAccess>,
+}
+
+impl IoHistory {
+    pub fn new() -> IoHistory {
+        IoHistory {
+            accesses: VecDeque::with_capacity(IO_HISTORY_DEPTH),
+        }
+    }
+
+    pub fn record(&mut self, out: bool, port: u8, value: u8) {
+        if let Some(last) = self.accesses.back_mut() {
+            if last.out == out && last.port == port {
+                last.value = value;
+                last.count += 1;
+                return;
+            }
+        }
+        if self.accesses.len() == IO_HISTORY_DEPTH {
+            self.accesses.pop_front();
+        }
+        self.accesses.push_back(IoAccess {
+            out,
+            port,
+            value,
+            count: 1,
+        });
+    }
+
+    fn lines(&self) -> Vec<String> {
+        self.accesses.iter().map(|access| {
+            let text = if access.out {
+                format!("OUT(0x{:02x}, 0x{:02x})", access.port, access.value)
+            } else {
+                format!("IN(0x{:02x}) = 0x{:02x}", access.port, access.value)
+            };
+            if access.count > 1 {
+                format!("{} ({} times)", text, access.count)
+            } else {
+                text
+            }
+        }).collect()
+    }
+}
+
+/// Writes the crash report on CRASH_REPORT_FILE
+pub fn write(reason: &str, counter: u64, history: &History, cpu: &mut Cpu,
+        machine: &KayproMachine, debugger: &Debugger, symbols: &Symbols) -> Result<()> {
+    let mut file = File::create(CRASH_REPORT_FILE)?;
+    writeln!(file, "izkaypro crash report")?;
+    writeln!(file, "{}", reason)?;
+    writeln!(file, "Instructions executed: {}", counter)?;
+    writeln!(file)?;
+
+    let panes = DebugPanes::new(cpu, machine, debugger, symbols);
+    for line in panes.right.iter() {
+        let line = line.trim_end();
+        if !line.is_empty() {
+            writeln!(file, "{}", line)?;
+        }
+    }
+    writeln!(file)?;
+
+    writeln!(file, "== Last instructions ==")?;
+    for line in history.lines(symbols) {
+        writeln!(file, "{}", line)?;
+    }
+    writeln!(file)?;
+
+    writeln!(file, "== Last port accesses ==")?;
+    for line in machine.io_history.lines() {
+        writeln!(file, "{}", line)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,136 @@
+use iz80::*;
+
+use super::KayproMachine;
+use super::debugger::{self, Debugger};
+use super::disassembler;
+use super::snapshot;
+use super::symbols::Symbols;
+
+/*
+Panes shown around the Kaypro display on the debug layout. The right
+pane has a line for each of the 26 lines of the display with the
+registers, the code around PC, the stack and the floppy controller. The
+bottom pane is a hex view of the memory selected with the "v" command
+of the debugger.
+*/
+
+pub const RIGHT_PANE_WIDTH: usize = 40;
+pub const RIGHT_PANE_ROWS: usize = 26;
+pub const BOTTOM_PANE_ROWS: usize = 9;
+
+const DISASSEMBLY_BEFORE: usize = 4;
+const DISASSEMBLY_ROWS: usize = 12;
+const STACK_ROWS: usize = 4;
+
+pub struct DebugPanes {
+    pub right: Vec<String>,
+    pub bottom: Vec<String>,
+}
+
+impl DebugPanes {
+    pub fn new(cpu: &mut Cpu, machine: &KayproMachine, debugger: &Debugger, symbols: &Symbols) -> DebugPanes {
+        DebugPanes {
+            right: right_pane(cpu, machine, debugger, symbols),
+            bottom: bottom_pane(machine, debugger),
+        }
+    }
+}
+
+fn right_pane(cpu: &mut Cpu, machine: &KayproMachine, debugger: &Debugger, symbols: &Symbols) -> Vec<String> {
+    // The alternate registers can't be read while halted or with an NMI
+    // pending, as when stepping the HALT of the ROM waiting for the FDC
+    let alternate = if snapshot::can_save(cpu, machine) {
+        Some(snapshot::alternate_registers(cpu))
+    } else {
+        None
+    };
+
+    let regs = cpu.registers();
+    let pc = regs.pc();
+    let sp = regs.get16(Reg16::SP);
+    let rom_rank = machine.is_rom_rank();
+    let flags = debugger::flags_text(regs.get8(Reg8::F));
+
+    let mut lines = vec![
+        "== Registers ==".to_owned(),
+        format!("PC {:04x}  SP {:04x}  {}  {}", pc, sp, flags,
+            if rom_rank {"ROM"} else {"RAM"}),
+        format!("AF {:04x}  BC {:04x}  DE {:04x}  HL {:04x}",
+            regs.get16(Reg16::AF), regs.get16(Reg16::BC),
+            regs.get16(Reg16::DE), regs.get16(Reg16::HL)),
+        format!("IX {:04x}  IY {:04x}  I {:02x}  R {:02x}",
+            regs.get16(Reg16::IX), regs.get16(Reg16::IY),
+            regs.get8(Reg8::I), regs.get8(Reg8::R)),
+        match alternate {
+            Some(alt) => format!("AF'{:04x}  BC'{:04x}  DE'{:04x}  HL'{:04x}",
+                alt[0], alt[1], alt[2], alt[3]),
+            None => "AF'----  BC'----  DE'----  HL'----".to_owned(),
+        },
+        match symbols.describe(pc, rom_rank) {
+            Some(name) => format!("== Code {} ==", name),
+            None => "== Code ==".to_owned(),
+        },
+    ];
+
+    let peek = |address| machine.peek(address);
+    let symbol = |address| symbols.name(address, rom_rank);
+    for instruction in disassembler::disassemble_around(&peek, &symbol, pc, DISASSEMBLY_BEFORE, DISASSEMBLY_ROWS) {
+        let marker = if instruction.address == pc {'>'} else {' '};
+        let breakpoint = if debugger.has_breakpoint(instruction.address, rom_rank) {'*'} else {' '};
+        lines.push(format!("{}{}{:04x} {}", marker, breakpoint, instruction.address, instruction.text));
+    }
+
+    lines.push("== Stack ==".to_owned());
+    for i in 0..STACK_ROWS as u16 {
+        let address = sp.wrapping_add(i * 2);
+        lines.push(format!("{:04x}: {:04x}", address, machine.peek16(address)));
+    }
+
+    lines.push("== FDC ==".to_owned());
+    lines.push(machine.floppy_controller.describe_state());
+
+    lines.resize(RIGHT_PANE_ROWS, String::new());
+    lines.iter()
+        .map(|line| format!("{:width$.width$}", line, width = RIGHT_PANE_WIDTH))
+        .collect()
+}
+
+fn bottom_pane(machine: &KayproMachine, debugger: &Debugger) -> Vec<String> {
+    let location = debugger.memory_view;
+    let mut lines = vec![format!("== Memory {} ==", location.describe())];
+    for row in 1..BOTTOM_PANE_ROWS as u16 {
+        lines.push(debugger::memory_line(machine, location, (row - 1) * 16, 16));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::floppy_controller::FloppyController;
+    use crate::keyboard_unix::Keyboard;
+
+    #[test]
+    fn alternate_registers_with_an_nmi_pending() {
+        let mut machine = KayproMachine::new(FloppyController::new(false, false),
+            Keyboard::new_headless(&[]), false, false);
+        let mut cpu = Cpu::new_z80();
+        let debugger = Debugger::new(false);
+        let symbols = Symbols::new();
+        cpu.registers().set16(Reg16::SP, 0x8000);
+        cpu.registers().set_pc(0x1234);
+        let panes = DebugPanes::new(&mut cpu, &machine, &debugger, &symbols);
+        assert!(panes.right[4].starts_with("AF'0000"));
+
+        cpu.signal_nmi();
+        machine.nmi_pending = true;
+        let panes = DebugPanes::new(&mut cpu, &machine, &debugger, &symbols);
+        assert!(panes.right[4].starts_with("AF'----"));
+        assert_eq!(cpu.registers().get16(Reg16::SP), 0x8000);
+
+        // The NMI is served by the ROM, its handler is a RET
+        cpu.execute_instruction(&mut machine);
+        assert_eq!(machine.peek16(0x7ffe), 0x1234);
+        assert_eq!(cpu.registers().pc(), 0x1234);
+    }
+}
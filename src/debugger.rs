@@ -0,0 +1,371 @@
+use std::io::{stdout, Write};
+
+use iz80::*;
+use super::KayproMachine;
+use super::address;
+use super::disassembler;
+use super::symbols::Symbols;
+
+/*
+Monitor to debug the code running on the emulated machine. It stops the
+emulation on request (F12 or --debug), on breakpoints or after stepping,
+and reads commands from the host terminal.
+
+Addresses are hexadecimal. They can be prefixed with "rom:" or "ram:"
+to select a memory bank instead of the one currently active:
+    rom: 0x0000-0x2fff is the ROM, 0x3000-0x3fff is the VRAM
+    ram: 64Kb of RAM
+Breakpoints with a bank prefix only stop when that bank is selected.
+The names of the loaded symbols can be used instead of addresses.
+*/
+
+const HELP: &str =
+"Debugger commands, addresses in hex with optional rom: or ram: prefix:
+  r                Show registers
+  s [count]        Step instructions
+  n                Step over calls, restarts and repeating instructions
+  c                Continue execution
+  b [addr]         Set a breakpoint at addr, or list the breakpoints
+  d addr           Delete the breakpoint at addr
+  m addr [len]     Dump memory
+  l [addr] [count] Disassemble, from PC by default
+  e addr bytes...  Edit memory
+  w [watchpoint]   Set a watchpoint, or list the watchpoints. Syntax:
+                   write|in|out|io [rom:|ram:]start[-end] [=value] [changed] [log]
+  wd index         Delete a watchpoint
+  v addr           Set the address of the memory pane in the debug layout
+  h                Show this help";
+
+const DEFAULT_DUMP_LEN: u16 = 0x80;
+const DEFAULT_LIST_COUNT: usize = 16;
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct Location {
+    address: u16,
+    rom_rank: Option<bool>, // None for the current bank
+}
+
+impl Location {
+    fn parse(text: &str, symbols: &Symbols) -> Option<Location> {
+        let (rom_rank, number) = address::split_bank(text);
+        match address::parse_hex(number) {
+            Ok(address) => Some(Location {address, rom_rank}),
+            Err(_) => {
+                let (address, symbol_rank) = symbols.address(number)?;
+                Some(Location {address, rom_rank: rom_rank.or(symbol_rank)})
+            }
+        }
+    }
+
+    fn matches(&self, pc: u16, rom_rank: bool) -> bool {
+        self.address == pc && self.rom_rank.is_none_or(|bank| bank == rom_rank)
+    }
+
+    pub fn peek(&self, machine: &KayproMachine, offset: u16) -> u8 {
+        let address = self.address.wrapping_add(offset);
+        match self.rom_rank {
+            Some(rom_rank) => machine.peek_bank(rom_rank, address),
+            None => machine.peek(address),
+        }
+    }
+
+    fn poke(&self, machine: &mut KayproMachine, offset: u16, value: u8) {
+        let address = self.address.wrapping_add(offset);
+        match self.rom_rank {
+            Some(rom_rank) => machine.poke_bank(rom_rank, address, value),
+            None => machine.poke_bank(machine.is_rom_rank(), address, value),
+        }
+    }
+
+    fn bank(&self, machine: &KayproMachine) -> bool {
+        self.rom_rank.unwrap_or_else(|| machine.is_rom_rank())
+    }
+
+    fn symbol(&self, symbols: &Symbols) -> Option<String> {
+        // Breakpoints without bank, look for symbols of both banks
+        match self.rom_rank {
+            Some(rom_rank) => symbols.name(self.address, rom_rank),
+            None => symbols.name(self.address, true)
+                .or_else(|| symbols.name(self.address, false)),
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self.rom_rank {
+            Some(true) => format!("rom:{:04x}", self.address),
+            Some(false) => format!("ram:{:04x}", self.address),
+            None => format!("{:04x}", self.address),
+        }
+    }
+}
+
+pub struct Debugger {
+    stop_requested: bool,
+    // Set when there are no more commands to read, the emulation goes on
+    // without stopping
+    input_ended: bool,
+    pending_steps: u32,
+    step_over_target: Option<u16>,
+    breakpoints: Vec<Location>,
+    pub memory_view: Location,
+}
+
+impl Debugger {
+    pub fn new(stop_requested: bool) -> Debugger {
+        Debugger {
+            stop_requested,
+            input_ended: false,
+            pending_steps: 0,
+            step_over_target: None,
+            breakpoints: Vec::new(),
+            memory_view: Location {address: 0, rom_rank: None},
+        }
+    }
+
+    pub fn request_stop(&mut self) {
+        self.stop_requested = true;
+    }
+
+    /// Called before each instruction, returns true if the monitor has to
+    /// be entered.
+    pub fn should_stop(&mut self, pc: u16, rom_rank: bool) -> bool {
+        if self.input_ended {
+            return false;
+        }
+        if self.pending_steps > 0 {
+            self.pending_steps -= 1;
+            if self.pending_steps == 0 {
+                return true;
+            }
+        }
+        if self.stop_requested {
+            return true;
+        }
+        if self.step_over_target == Some(pc) {
+            self.step_over_target = None;
+            return true;
+        }
+        self.has_breakpoint(pc, rom_rank)
+    }
+
+    pub fn has_breakpoint(&self, address: u16, rom_rank: bool) -> bool {
+        self.breakpoints.iter().any(|b| b.matches(address, rom_rank))
+    }
+
+    /// Reads and executes commands until the execution is resumed.
+    pub fn monitor(&mut self, cpu: &mut Cpu, machine: &mut KayproMachine, symbols: &Symbols) {
+        self.stop_requested = false;
+        self.pending_steps = 0;
+        self.step_over_target = None;
+
+        println!();
+        print_registers(cpu, machine, symbols);
+        loop {
+            print!("dbg> ");
+            stdout().flush().unwrap();
+            let line = match machine.keyboard.read_line() {
+                Some(line) => line,
+                None => {
+                    println!();
+                    println!("End of input, resuming the emulation");
+                    self.input_ended = true;
+                    return;
+                }
+            };
+            let params: Vec<&str> = line.split_whitespace().collect();
+            if params.is_empty() {
+                continue;
+            }
+
+            match params[0] {
+                "r" => print_registers(cpu, machine, symbols),
+                "s" => {
+                    let count = params.get(1)
+                        .and_then(|count| count.parse::<u32>().ok())
+                        .unwrap_or(1);
+                    self.pending_steps = count.max(1);
+                    return;
+                }
+                "n" => {
+                    let pc = cpu.registers().pc();
+                    match step_over_length(machine, pc) {
+                        Some(length) => self.step_over_target = Some(pc.wrapping_add(length)),
+                        None => self.pending_steps = 1,
+                    }
+                    return;
+                }
+                "c" => return,
+                "b" => {
+                    if params.len() < 2 {
+                        for breakpoint in self.breakpoints.iter() {
+                            match breakpoint.symbol(symbols) {
+                                Some(name) => println!("Breakpoint at {} {}", breakpoint.describe(), name),
+                                None => println!("Breakpoint at {}", breakpoint.describe()),
+                            }
+                        }
+                    } else if let Some(location) = Location::parse(params[1], symbols) {
+                        if !self.breakpoints.contains(&location) {
+                            self.breakpoints.push(location);
+                        }
+                    } else {
+                        println!("Invalid address");
+                    }
+                }
+                "d" => {
+                    match params.get(1).and_then(|text| Location::parse(text, symbols)) {
+                        Some(location) => self.breakpoints.retain(|b| *b != location),
+                        None => println!("Invalid address"),
+                    }
+                }
+                "m" => {
+                    let location = params.get(1).and_then(|text| Location::parse(text, symbols));
+                    let len = params.get(2).and_then(|text| address::parse_hex(text).ok())
+                        .unwrap_or(DEFAULT_DUMP_LEN);
+                    match location {
+                        Some(location) => dump_memory(machine, location, len),
+                        None => println!("Invalid address"),
+                    }
+                }
+                "l" => {
+                    let location = match params.get(1) {
+                        Some(text) => Location::parse(text, symbols),
+                        None => Some(Location {address: cpu.registers().pc(), rom_rank: None}),
+                    };
+                    let count = params.get(2)
+                        .and_then(|count| count.parse::<usize>().ok())
+                        .unwrap_or(DEFAULT_LIST_COUNT);
+                    match location {
+                        Some(location) => list_code(machine, location, count, symbols),
+                        None => println!("Invalid address"),
+                    }
+                }
+                "e" => {
+                    let location = params.get(1).and_then(|text| Location::parse(text, symbols));
+                    let values: Option<Vec<u8>> = params.iter().skip(2)
+                        .map(|text| address::parse_hex(text).ok().filter(|v| *v <= 0xff).map(|v| v as u8))
+                        .collect();
+                    match (location, values) {
+                        (Some(location), Some(values)) => {
+                            for (offset, value) in values.iter().enumerate() {
+                                location.poke(machine, offset as u16, *value);
+                            }
+                        }
+                        _ => println!("Invalid address or values"),
+                    }
+                }
+                "w" => {
+                    if params.len() < 2 {
+                        for (index, spec) in machine.watchpoints.list().iter().enumerate() {
+                            println!("Watchpoint {}: {}", index, spec);
+                        }
+                    } else if let Err(err) = machine.watchpoints.add(&params[1..].join(" ")) {
+                        println!("{}", err);
+                    }
+                }
+                "wd" => {
+                    let removed = params.get(1)
+                        .and_then(|index| index.parse::<usize>().ok())
+                        .is_some_and(|index| machine.watchpoints.remove(index));
+                    if !removed {
+                        println!("Invalid watchpoint index");
+                    }
+                }
+                "v" => {
+                    match params.get(1).and_then(|text| Location::parse(text, symbols)) {
+                        Some(location) => self.memory_view = location,
+                        None => println!("Invalid address"),
+                    }
+                }
+                "h" | "?" => println!("{}", HELP),
+                _ => println!("Unknown command, h for help"),
+            }
+        }
+    }
+}
+
+/// The flags set in F by name, as "SZ-H--NC"
+pub fn flags_text(f: u8) -> String {
+    "SZ5H3PNC".chars().enumerate()
+        .map(|(i, name)| if f & (0x80 >> i) != 0 {name} else {'-'})
+        .collect()
+}
+
+fn print_registers(cpu: &mut Cpu, machine: &KayproMachine, symbols: &Symbols) {
+    let regs = cpu.registers();
+    let flags = flags_text(regs.get8(Reg8::F));
+    println!("PC:{:04x} AF:{:04x} BC:{:04x} DE:{:04x} HL:{:04x} SP:{:04x} IX:{:04x} IY:{:04x} I:{:02x} R:{:02x} Flags:{} Bank:{}",
+        regs.pc(),
+        regs.get16(Reg16::AF),
+        regs.get16(Reg16::BC),
+        regs.get16(Reg16::DE),
+        regs.get16(Reg16::HL),
+        regs.get16(Reg16::SP),
+        regs.get16(Reg16::IX),
+        regs.get16(Reg16::IY),
+        regs.get8(Reg8::I),
+        regs.get8(Reg8::R),
+        flags,
+        if machine.is_rom_rank() {"ROM"} else {"RAM"});
+    let location = Location {address: regs.pc(), rom_rank: None};
+    list_code(machine, location, 1, symbols);
+}
+
+fn list_code(machine: &KayproMachine, location: Location, count: usize, symbols: &Symbols) {
+    let rom_rank = location.bank(machine);
+    let peek = |address| machine.peek_bank(rom_rank, address);
+    let symbol = |address| symbols.name(address, rom_rank);
+    let mut address = location.address;
+    for _ in 0..count {
+        if let Some(name) = symbols.name(address, rom_rank) {
+            println!("{}:", name);
+        }
+        let instruction = disassembler::disassemble(&peek, &symbol, address);
+        let bytes: String = (0..instruction.length)
+            .map(|i| format!("{:02x} ", peek(address.wrapping_add(i))))
+            .collect();
+        println!("{:04x}: {:12} {}", address, bytes, instruction.text);
+        address = address.wrapping_add(instruction.length);
+    }
+}
+
+fn dump_memory(machine: &KayproMachine, location: Location, len: u16) {
+    let mut offset = 0;
+    while offset < len {
+        println!("{}", memory_line(machine, location, offset, (len - offset).min(16)));
+        offset = offset.saturating_add(16);
+    }
+}
+
+/// Up to 16 bytes starting at location + offset, in hex and ASCII
+pub fn memory_line(machine: &KayproMachine, location: Location, offset: u16, len: u16) -> String {
+    let mut hex = String::new();
+    let mut ascii = String::new();
+    for i in 0..16 {
+        if i < len {
+            let value = location.peek(machine, offset.wrapping_add(i));
+            hex += &format!("{:02x} ", value);
+            ascii.push(if (0x20..0x7f).contains(&value) {value as char} else {'.'});
+        } else {
+            hex += "   ";
+        }
+    }
+    format!("{:04x}: {}|{}|", location.address.wrapping_add(offset), hex, ascii)
+}
+
+/*
+Length of the instruction at pc when stepping over it makes sense:
+calls and restarts return to the next instruction, and the repeating
+block instructions loop on themselves.
+*/
+fn step_over_length(machine: &KayproMachine, pc: u16) -> Option<u16> {
+    let opcode = machine.peek(pc);
+    match opcode {
+        0xcd | 0xc4 | 0xcc | 0xd4 | 0xdc | 0xe4 | 0xec | 0xf4 | 0xfc => Some(3), // CALL
+        0xc7 | 0xcf | 0xd7 | 0xdf | 0xe7 | 0xef | 0xf7 | 0xff => Some(1), // RST
+        0xed => match machine.peek(pc.wrapping_add(1)) {
+            0xb0..=0xb3 | 0xb8..=0xbb => Some(2), // LDIR, CPIR, INIR, OTIR...
+            _ => None,
+        },
+        _ => None,
+    }
+}
@@ -0,0 +1,413 @@
+/*
+Z80 disassembler, used by the debugger views.
+
+The decoding follows the x/y/z/p/q decomposition of the opcodes in
+"Decoding Z80 opcodes" by Cristian Dinu. The undocumented opcodes are
+decoded as well. Numbers are shown in hex with the 'h' suffix, as in
+the iz80 traces. The 16 bits values and the jump targets are replaced
+by the symbol names when available.
+*/
+
+const R: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+const RP: [&str; 4] = ["BC", "DE", "HL", "SP"];
+const RP2: [&str; 4] = ["BC", "DE", "HL", "AF"];
+const CC: [&str; 8] = ["NZ", "Z", "NC", "C", "PO", "PE", "P", "M"];
+const ALU: [&str; 8] = ["ADD A, ", "ADC A, ", "SUB ", "SBC A, ", "AND ", "XOR ", "OR ", "CP "];
+const ROT: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SLL", "SRL"];
+const IM: [&str; 8] = ["0", "0/1", "1", "2", "0", "0/1", "1", "2"];
+const BLOCK: [[&str; 4]; 4] = [
+    ["LDI", "CPI", "INI", "OUTI"],
+    ["LDD", "CPD", "IND", "OUTD"],
+    ["LDIR", "CPIR", "INIR", "OTIR"],
+    ["LDDR", "CPDR", "INDR", "OTDR"],
+];
+
+pub struct Instruction {
+    pub address: u16,
+    pub length: u16,
+    pub text: String,
+}
+
+struct Decoder<'a> {
+    peek: &'a dyn Fn(u16) -> u8,
+    symbol: &'a dyn Fn(u16) -> Option<String>,
+    start: u16,
+    position: u16,
+    index: Option<&'static str>,
+    displacement: Option<i8>,
+}
+
+impl<'a> Decoder<'a> {
+    fn fetch(&mut self) -> u8 {
+        let value = (self.peek)(self.position);
+        self.position = self.position.wrapping_add(1);
+        value
+    }
+
+    fn n(&mut self) -> String {
+        format!("{:02x}h", self.fetch())
+    }
+
+    fn nn(&mut self) -> String {
+        let low = self.fetch() as u16;
+        let high = self.fetch() as u16;
+        self.address(low + (high << 8))
+    }
+
+    fn relative(&mut self) -> String {
+        let d = self.fetch() as i8;
+        let target = self.position.wrapping_add(d as u16);
+        self.address(target)
+    }
+
+    fn address(&self, address: u16) -> String {
+        (self.symbol)(address).unwrap_or_else(|| format!("{:04x}h", address))
+    }
+
+    fn hl(&self) -> &'static str {
+        self.index.unwrap_or("HL")
+    }
+
+    fn indirect(&mut self) -> String {
+        match self.index {
+            None => "(HL)".to_owned(),
+            Some(index) => {
+                let d = match self.displacement {
+                    Some(d) => d,
+                    None => {
+                        let d = self.fetch() as i8;
+                        self.displacement = Some(d);
+                        d
+                    }
+                };
+                if d < 0 {
+                    format!("({}-{:02x}h)", index, -(d as i16))
+                } else {
+                    format!("({}+{:02x}h)", index, d)
+                }
+            }
+        }
+    }
+
+    // 8 bit register, with H and L replaced by the index halves
+    fn r(&mut self, r: u8) -> String {
+        match (r, self.index) {
+            (6, _) => self.indirect(),
+            (4, Some(index)) => format!("{}H", index),
+            (5, Some(index)) => format!("{}L", index),
+            _ => R[r as usize].to_owned(),
+        }
+    }
+
+    fn rp(&self, p: u8) -> String {
+        if p == 2 {self.hl().to_owned()} else {RP[p as usize].to_owned()}
+    }
+
+    fn rp2(&self, p: u8) -> String {
+        if p == 2 {self.hl().to_owned()} else {RP2[p as usize].to_owned()}
+    }
+
+    fn decode(&mut self) -> String {
+        let opcode = self.fetch();
+        let x = opcode >> 6;
+        let y = (opcode >> 3) & 7;
+        let z = opcode & 7;
+        let p = y >> 1;
+        let q = y & 1;
+
+        match x {
+            0 => match z {
+                0 => match y {
+                    0 => "NOP".to_owned(),
+                    1 => "EX AF, AF'".to_owned(),
+                    2 => format!("DJNZ {}", self.relative()),
+                    3 => format!("JR {}", self.relative()),
+                    _ => format!("JR {}, {}", CC[y as usize - 4], self.relative()),
+                },
+                1 => if q == 0 {
+                    format!("LD {}, {}", self.rp(p), self.nn())
+                } else {
+                    format!("ADD {}, {}", self.hl(), self.rp(p))
+                },
+                2 => match (q, p) {
+                    (0, 0) => "LD (BC), A".to_owned(),
+                    (0, 1) => "LD (DE), A".to_owned(),
+                    (0, 2) => format!("LD ({}), {}", self.nn(), self.hl()),
+                    (0, _) => format!("LD ({}), A", self.nn()),
+                    (_, 0) => "LD A, (BC)".to_owned(),
+                    (_, 1) => "LD A, (DE)".to_owned(),
+                    (_, 2) => format!("LD {}, ({})", self.hl(), self.nn()),
+                    (_, _) => format!("LD A, ({})", self.nn()),
+                },
+                3 => format!("{} {}", if q == 0 {"INC"} else {"DEC"}, self.rp(p)),
+                4 => format!("INC {}", self.r(y)),
+                5 => format!("DEC {}", self.r(y)),
+                6 => {
+                    let r = self.r(y);
+                    format!("LD {}, {}", r, self.n())
+                }
+                _ => ["RLCA", "RRCA", "RLA", "RRA", "DAA", "CPL", "SCF", "CCF"][y as usize].to_owned(),
+            },
+            1 => {
+                if z == 6 && y == 6 {
+                    "HALT".to_owned()
+                } else if z == 6 || y == 6 {
+                    // With (IX+d) the other register is not replaced
+                    let index = self.index;
+                    let indirect = self.indirect();
+                    self.index = None;
+                    let (dst, src) = if y == 6 {
+                        (indirect, self.r(z))
+                    } else {
+                        (self.r(y), indirect)
+                    };
+                    self.index = index;
+                    format!("LD {}, {}", dst, src)
+                } else {
+                    let dst = self.r(y);
+                    format!("LD {}, {}", dst, self.r(z))
+                }
+            }
+            2 => format!("{}{}", ALU[y as usize], self.r(z)),
+            _ => match z {
+                0 => format!("RET {}", CC[y as usize]),
+                1 => if q == 0 {
+                    format!("POP {}", self.rp2(p))
+                } else {
+                    match p {
+                        0 => "RET".to_owned(),
+                        1 => "EXX".to_owned(),
+                        2 => format!("JP ({})", self.hl()),
+                        _ => format!("LD SP, {}", self.hl()),
+                    }
+                },
+                2 => format!("JP {}, {}", CC[y as usize], self.nn()),
+                3 => match y {
+                    0 => format!("JP {}", self.nn()),
+                    1 => self.decode_cb(),
+                    2 => format!("OUT ({}), A", self.n()),
+                    3 => format!("IN A, ({})", self.n()),
+                    4 => format!("EX (SP), {}", self.hl()),
+                    5 => "EX DE, HL".to_owned(),
+                    6 => "DI".to_owned(),
+                    _ => "EI".to_owned(),
+                },
+                4 => format!("CALL {}, {}", CC[y as usize], self.nn()),
+                5 => if q == 0 {
+                    format!("PUSH {}", self.rp2(p))
+                } else {
+                    match p {
+                        0 => format!("CALL {}", self.nn()),
+                        2 => self.decode_ed(),
+                        _ => self.decode_index(if p == 1 {"IX"} else {"IY"}),
+                    }
+                },
+                6 => format!("{}{}", ALU[y as usize], self.n()),
+                _ => format!("RST {:02x}h", y * 8),
+            },
+        }
+    }
+
+    fn decode_cb(&mut self) -> String {
+        // With an index prefix the displacement goes before the opcode
+        if self.index.is_some() {
+            self.indirect();
+        }
+        let opcode = self.fetch();
+        let x = opcode >> 6;
+        let y = (opcode >> 3) & 7;
+        let z = opcode & 7;
+
+        let operand = if self.index.is_some() {
+            let indirect = self.indirect();
+            if z != 6 && x != 1 {
+                // Undocumented, the result is also copied to a register
+                format!("{}, {}", indirect, R[z as usize])
+            } else {
+                indirect
+            }
+        } else {
+            self.r(z)
+        };
+
+        match x {
+            0 => format!("{} {}", ROT[y as usize], operand),
+            1 => format!("BIT {}, {}", y, operand),
+            2 => format!("RES {}, {}", y, operand),
+            _ => format!("SET {}, {}", y, operand),
+        }
+    }
+
+    fn decode_ed(&mut self) -> String {
+        // The index prefixes don't apply to ED opcodes
+        self.index = None;
+        let opcode = self.fetch();
+        let x = opcode >> 6;
+        let y = (opcode >> 3) & 7;
+        let z = opcode & 7;
+        let p = y >> 1;
+        let q = y & 1;
+
+        match x {
+            1 => match z {
+                0 => if y == 6 {"IN (C)".to_owned()} else {format!("IN {}, (C)", R[y as usize])},
+                1 => if y == 6 {"OUT (C), 0".to_owned()} else {format!("OUT (C), {}", R[y as usize])},
+                2 => format!("{} HL, {}", if q == 0 {"SBC"} else {"ADC"}, RP[p as usize]),
+                3 => if q == 0 {
+                    format!("LD ({}), {}", self.nn(), RP[p as usize])
+                } else {
+                    format!("LD {}, ({})", RP[p as usize], self.nn())
+                },
+                4 => "NEG".to_owned(),
+                5 => if y == 1 {"RETI".to_owned()} else {"RETN".to_owned()},
+                6 => format!("IM {}", IM[y as usize]),
+                _ => ["LD I, A", "LD R, A", "LD A, I", "LD A, R", "RRD", "RLD", "NOP*", "NOP*"][y as usize].to_owned(),
+            },
+            2 if z <= 3 && y >= 4 => BLOCK[y as usize - 4][z as usize].to_owned(),
+            _ => "NOP*".to_owned(),
+        }
+    }
+
+    fn decode_index(&mut self, index: &'static str) -> String {
+        let next = (self.peek)(self.position);
+        if next == 0xdd || next == 0xed || next == 0xfd {
+            // The prefix is ignored
+            return "NOP*".to_owned();
+        }
+        self.index = Some(index);
+        self.decode()
+    }
+}
+
+/// Decodes the instruction at address, reading the memory with peek and
+/// naming the addresses with symbol.
+pub fn disassemble(peek: &dyn Fn(u16) -> u8, symbol: &dyn Fn(u16) -> Option<String>, address: u16) -> Instruction {
+    let mut decoder = Decoder {
+        peek,
+        symbol,
+        start: address,
+        position: address,
+        index: None,
+        displacement: None,
+    };
+    let text = decoder.decode();
+    Instruction {
+        address,
+        length: decoder.position.wrapping_sub(decoder.start),
+        text,
+    }
+}
+
+/// Decodes count instructions around address. As the instructions have
+/// variable length, the start is the earliest location from where the
+/// decoding reaches address, up to before instructions back.
+pub fn disassemble_around(peek: &dyn Fn(u16) -> u8, symbol: &dyn Fn(u16) -> Option<String>, address: u16, before: usize, count: usize) -> Vec<Instruction> {
+    let mut start = address;
+    for distance in (1..=(before as u16 * 4)).rev() {
+        let candidate = address.wrapping_sub(distance);
+        let mut position = candidate;
+        let mut instructions = 0;
+        while position != address && position.wrapping_sub(candidate) < distance {
+            position = position.wrapping_add(disassemble(peek, &|_| None, position).length);
+            instructions += 1;
+        }
+        if position == address && instructions <= before {
+            start = candidate;
+            break;
+        }
+    }
+
+    let mut instructions = Vec::new();
+    let mut position = start;
+    while instructions.len() < count {
+        let instruction = disassemble(peek, symbol, position);
+        position = position.wrapping_add(instruction.length);
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn disassemble_bytes(bytes: &[u8]) -> (String, u16) {
+        let memory = bytes.to_vec();
+        let peek = move |address: u16| *memory.get(address.wrapping_sub(0x0100) as usize).unwrap_or(&0);
+        let symbol = |address: u16| if address == 0x0200 {Some("TARGET".to_string())} else {None};
+        let instruction = disassemble(&peek, &symbol, 0x0100);
+        (instruction.text, instruction.length)
+    }
+
+    fn check(cases: &[(&[u8], &str)]) {
+        for (bytes, text) in cases {
+            assert_eq!(disassemble_bytes(bytes), (text.to_string(), bytes.len() as u16), "{:02x?}", bytes);
+        }
+    }
+
+    #[test]
+    fn cb_prefix() {
+        check(&[
+            (&[0xcb, 0x07], "RLC A"),
+            (&[0xcb, 0x7e], "BIT 7, (HL)"),
+            (&[0xcb, 0x36], "SLL (HL)"),
+        ]);
+    }
+
+    #[test]
+    fn ed_prefix() {
+        check(&[
+            (&[0xed, 0xb0], "LDIR"),
+            (&[0xed, 0x4a], "ADC HL, BC"),
+            (&[0xed, 0x5e], "IM 2"),
+            (&[0xed, 0x4c], "NEG"),
+            (&[0xed, 0x70], "IN (C)"),
+            (&[0xed, 0x71], "OUT (C), 0"),
+            (&[0xed, 0x00], "NOP*"),
+        ]);
+    }
+
+    #[test]
+    fn index_prefixes() {
+        check(&[
+            (&[0xdd, 0x21, 0x34, 0x12], "LD IX, 1234h"),
+            (&[0xfd, 0x7e, 0x05], "LD A, (IY+05h)"),
+            (&[0xdd, 0x36, 0xfe, 0x42], "LD (IX-02h), 42h"),
+            (&[0xdd, 0xe9], "JP (IX)"),
+            (&[0xdd, 0x44], "LD B, IXH"),
+            (&[0xfd, 0x6f], "LD IYL, A"),
+        ]);
+        // A prefix followed by another prefix is a NOP on its own
+        assert_eq!(disassemble_bytes(&[0xdd, 0xdd, 0x00]), ("NOP*".to_string(), 1));
+    }
+
+    #[test]
+    fn index_bit_prefixes() {
+        check(&[
+            (&[0xdd, 0xcb, 0x05, 0x06], "RLC (IX+05h)"),
+            (&[0xfd, 0xcb, 0xfb, 0x46], "BIT 0, (IY-05h)"),
+            (&[0xdd, 0xcb, 0x01, 0x00], "RLC (IX+01h), B"),
+            (&[0xfd, 0xcb, 0x02, 0xc7], "SET 0, (IY+02h), A"),
+        ]);
+    }
+
+    #[test]
+    fn relative_jumps() {
+        check(&[
+            (&[0x18, 0x00], "JR 0102h"),
+            (&[0x18, 0xfe], "JR 0100h"),
+            (&[0x20, 0x10], "JR NZ, 0112h"),
+            (&[0x38, 0xfe], "JR C, 0100h"),
+            (&[0x10, 0x80], "DJNZ 0082h"),
+        ]);
+    }
+
+    #[test]
+    fn symbols() {
+        check(&[
+            (&[0xc3, 0x00, 0x02], "JP TARGET"),
+            (&[0x21, 0x00, 0x02], "LD HL, TARGET"),
+            (&[0xcd, 0x34, 0x12], "CALL 1234h"),
+        ]);
+    }
+}
@@ -1,7 +1,12 @@
+use std::fmt;
+use std::io::{Error, Result};
+
 use super::media::*;
+use super::profiler::DiskActivity;
+use super::snapshot::{SnapshotReader, SnapshotWriter};
 
 static DISK_CPM22: &[u8] = include_bytes!("../disks/cpm22-rom232.img");
-static DISK_BLANK: &[u8] = include_bytes!("../disks/blank.img");
+pub static DISK_BLANK: &[u8] = include_bytes!("../disks/blank.img");
 
 pub enum Drive {
     A = 0,
@@ -27,7 +32,11 @@ pub struct FloppyController {
 
     pub raise_nmi: bool,
     pub trace: bool,
-    pub trace_rw: bool
+    pub trace_rw: bool,
+
+    error: Option<FdcError>,
+    write_fault: bool, // The current write is not stored
+    pub activity: Option<DiskActivity>,
 }
 
 #[derive(Copy, Clone)]
@@ -35,7 +44,7 @@ pub struct FloppyController {
 pub enum FDCStatus {
     _NotReady = 0x80,
     _WriteProtected = 0x40,
-    _WriteFault = 0x20,
+    WriteFault = 0x20,
     SeekErrorOrRecordNotFound = 0x10,
     _CRCError = 0x08,
     LostDataOrTrack0 = 0x04,
@@ -44,6 +53,36 @@ pub enum FDCStatus {
     NoError = 0x00,
 }
 
+/// Errors on commands not emulated or on the host files, the emulation
+/// goes on with an error status on the controller.
+pub enum FdcError {
+    MultipleSectors(u8),
+    DeletedDataMark(u8),
+    InterruptConditions(u8),
+    UnknownCommand(u8),
+    Flush(String, Error),
+    WritesLost(String, Error),
+}
+
+impl fmt::Display for FdcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FdcError::MultipleSectors(command) =>
+                write!(f, "FDC: Multiple sector command ${:02x} not supported", command),
+            FdcError::DeletedDataMark(command) =>
+                write!(f, "FDC: Deleted data mark on ${:02x} not supported", command),
+            FdcError::InterruptConditions(command) =>
+                write!(f, "FDC: Force interrupt conditions on ${:02x} not supported", command),
+            FdcError::UnknownCommand(command) =>
+                write!(f, "FDC: ${:02x} command not implemented", command),
+            FdcError::Flush(name, err) =>
+                write!(f, "Error writing disk '{}': {}", name, err),
+            FdcError::WritesLost(name, err) =>
+                write!(f, "Changes to disk '{}' lost: {}", name, err),
+        }
+    }
+}
+
 impl FloppyController {
     pub fn new(trace: bool, trace_rw: bool) -> FloppyController {
         FloppyController {
@@ -63,6 +102,8 @@ impl FloppyController {
                     format: MediaFormat::SsDd,
                     write_min: usize::MAX,
                     write_max: 0,
+                    write_error: None,
+                    lost_writes: None,
                 },
                 Media {
                     file: None,
@@ -71,6 +112,8 @@ impl FloppyController {
                     format: MediaFormat::SsDd,
                     write_min: usize::MAX,
                     write_max: 0,
+                    write_error: None,
+                    lost_writes: None,
                 },
             ],
 
@@ -82,6 +125,10 @@ impl FloppyController {
             raise_nmi: false,
             trace,
             trace_rw,
+
+            error: None,
+            write_fault: false,
+            activity: None,
         }
     }
 
@@ -105,8 +152,43 @@ impl FloppyController {
         &mut self.media[self.drive as usize]
     }
 
+    /// Last error, to be shown to the user
+    pub fn take_error(&mut self) -> Option<FdcError> {
+        self.error.take().or_else(|| self.media.iter_mut()
+            .find_map(|media| media.lost_writes.take())
+            .map(|(name, err)| FdcError::WritesLost(name, err)))
+    }
+
+    fn set_error(&mut self, error: FdcError) {
+        if self.trace {
+            trace!("fdc", "{}", error);
+        }
+        self.error = Some(error);
+    }
+
+    /// Writes the pending changes of both disks to the host files, a
+    /// failure on a disk doesn't stop the other one
+    pub fn flush_all(&mut self) -> Vec<FdcError> {
+        let mut errors = Vec::new();
+        for media in self.media.iter_mut() {
+            if let Err(err) = media.flush_disk() {
+                errors.push(FdcError::Flush(media.name.clone(), err));
+            }
+        }
+        errors
+    }
+
+    /// Writes the pending changes of the selected disk to the host file
+    fn flush_selected(&mut self) {
+        let media = self.media_selected();
+        if let Err(err) = media.flush_disk() {
+            let name = media.name.clone();
+            self.set_error(FdcError::Flush(name, err));
+        }
+    }
+
     pub fn set_motor(&mut self, motor_on: bool) {
-        self.media_selected().flush_disk();
+        self.flush_selected();
         self.motor_on = motor_on;
     }
 
@@ -119,18 +201,96 @@ impl FloppyController {
     }
 
     pub fn set_drive(&mut self, drive: u8) {
-        self.media_selected().flush_disk();
+        self.flush_selected();
+        self.drive = drive;
+    }
+
+    pub fn save_state(&mut self, w: &mut SnapshotWriter) {
+        // The persistent images are saved by reference, with the changes
+        // on the host files
+        for error in self.flush_all() {
+            self.set_error(error);
+        }
+
+        w.bool(self.motor_on);
+        w.u8(self.drive);
+        w.bool(self.side_2);
+        w.u8(self.track);
+        w.u8(self.sector);
+        w.bool(self.single_density);
+        w.u8(self.data);
+        w.u8(self.status);
+        w.u32(self.read_index as u32);
+        w.u32(self.read_last as u32);
+        w.bytes(&self.data_buffer);
+        w.bool(self.raise_nmi);
+
+        for media in self.media.iter_mut() {
+            media.save_state(w);
+        }
+    }
+
+    /// Restores the state saved with save_state. Everything is read,
+    /// and the disk images opened, before changing the controller.
+    pub fn load_state(&mut self, r: &mut SnapshotReader) -> Result<()> {
+        // The images may be opened again, with the changes pending. A
+        // failure is reported when replacing the images.
+        for media in self.media.iter_mut() {
+            let _ = media.flush_disk();
+        }
+        let motor_on = r.bool()?;
+        let drive = r.u8()? & 1;
+        let side_2 = r.bool()?;
+        let track = r.u8()?;
+        let sector = r.u8()?;
+        let single_density = r.bool()?;
+        let data = r.u8()?;
+        let status = r.u8()?;
+        let read_index = r.u32()? as usize;
+        let read_last = r.u32()? as usize;
+        let data_buffer = r.bytes()?;
+        let raise_nmi = r.bool()?;
+        let media_a = Media::read_state(r)?;
+        let media_b = Media::read_state(r)?;
+
+        // The transfer in progress has to be within a sector of the disk
+        let media_len = [&media_a, &media_b][drive as usize].content.len();
+        if read_index > read_last
+                || read_last - read_index > SECTOR_SIZE
+                || read_last > media_len
+                || data_buffer.len() > SECTOR_SIZE {
+            return Err(Error::other("Invalid floppy controller state in snapshot"));
+        }
+
+        self.motor_on = motor_on;
         self.drive = drive;
+        self.side_2 = side_2;
+        self.track = track;
+        self.sector = sector;
+        self.single_density = single_density;
+        self.data = data;
+        self.status = status;
+        self.read_index = read_index;
+        self.read_last = read_last;
+        self.data_buffer = data_buffer;
+        self.raise_nmi = raise_nmi;
+        self.media_a_mut().replace(media_a);
+        self.media_b_mut().replace(media_b);
+        // The content restored to the images in host files
+        for error in self.flush_all() {
+            self.set_error(error);
+        }
+        Ok(())
     }
 
     pub fn put_command(&mut self, command: u8) {
-        self.media_selected().flush_disk();
+        self.flush_selected();
 
         if (command & 0xf0) == 0x00 {
             // RESTORE command, type I
             // 0000_hVrr
             if self.trace {
-                println!("FDC: Restore");
+                trace!("fdc", "FDC: Restore");
             }
             self.read_index = 0;
             self.read_last = 0;
@@ -143,7 +303,7 @@ impl FloppyController {
             // 0001_hVrr
             let track = self.data;
             if self.trace {
-                println!("FDC: Seek track {}", track);
+                trace!("fdc", "FDC: Seek track {}", track);
             }
             if self.media_selected().is_valid_track(track) {
                 self.track = track;
@@ -156,10 +316,13 @@ impl FloppyController {
             // READ SECTOR command, type II
             // 100mFEFx
             if command & 0x10 != 0 {
-                panic!("Multiple sector reads not supported")
+                self.set_error(FdcError::MultipleSectors(command));
+                self.status = FDCStatus::SeekErrorOrRecordNotFound as u8;
+                self.raise_nmi = true;
+                return;
             }
             if self.trace || self.trace_rw {
-                println!("FDC: Read sector (Si:{}, Tr:{}, Se:{})", self.side_2, self.track, self.sector);
+                trace!("fdc", "FDC: Read sector (Si:{}, Tr:{}, Se:{})", self.side_2, self.track, self.sector);
             }
 
             let side_2 = self.side_2;
@@ -170,6 +333,7 @@ impl FloppyController {
                 self.read_index = index;
                 self.read_last = last;
                 self.status = FDCStatus::Busy as u8;
+                self.record_activity(false);
             } else {
                 self.status = FDCStatus::SeekErrorOrRecordNotFound as u8;
             }
@@ -179,13 +343,29 @@ impl FloppyController {
             // WRITE SECTOR command, type II
             // 101mFEFa
             if command & 0x10 != 0 {
-                panic!("Multiple sector writes not supported")
+                self.set_error(FdcError::MultipleSectors(command));
+                self.status = FDCStatus::SeekErrorOrRecordNotFound as u8;
+                self.raise_nmi = true;
+                return;
             }
             if command & 0x01 != 0 {
-                panic!("Delete data mark not supported")
+                self.set_error(FdcError::DeletedDataMark(command));
+                self.status = FDCStatus::WriteFault as u8;
+                self.raise_nmi = true;
+                return;
+            }
+            // A previous write that didn't reach the host file fails this
+            // one. The data is still transferred, for the ROM to get the
+            // error at the end.
+            self.write_fault = false;
+            if let Some(err) = self.media_selected().take_write_error() {
+                if self.trace {
+                    trace!("fdc", "FDC: Write fault, {}", err);
+                }
+                self.write_fault = true;
             }
             if self.trace || self.trace_rw {
-                println!("FDC: Write sector (Si:{}, Tr:{}, Se:{})", self.side_2, self.track, self.sector);
+                trace!("fdc", "FDC: Write sector (Si:{}, Tr:{}, Se:{})", self.side_2, self.track, self.sector);
             }
 
             let side_2 = self.side_2;
@@ -196,6 +376,7 @@ impl FloppyController {
                 self.read_index = index;
                 self.read_last = last;
                 self.status = FDCStatus::Busy as u8;
+                self.record_activity(true);
             } else {
                 self.status = FDCStatus::SeekErrorOrRecordNotFound as u8;
             }
@@ -211,7 +392,7 @@ impl FloppyController {
             let (valid, sector_id) = self.media_selected().read_address(side_2, track, sector);
             if valid {
                 if self.trace {
-                    println!("FDC: Read address ({},{},{})", side_2, track, sector);
+                    trace!("fdc", "FDC: Read address ({},{},{})", side_2, track, sector);
                 }
                 self.sector = self.media_selected().inc_sector(sector);
                 self.status = FDCStatus::NoError as u8;
@@ -224,7 +405,7 @@ impl FloppyController {
                 self.data_buffer.push(0xad); // CRC 2
             } else {
                 if self.trace {
-                    println!("FDC: Read address ({},{},{}) = Error", side_2, track, sector);
+                    trace!("fdc", "FDC: Read address ({},{},{}) = Error", side_2, track, sector);
                 }
                 self.status = FDCStatus::SeekErrorOrRecordNotFound as u8;
                 self.data_buffer.push(0);
@@ -241,26 +422,42 @@ impl FloppyController {
             // 1101_IIII
             let interrupts = command & 0x0f;
             if self.trace {
-                println!("FDC: Force interrupt {}", interrupts);
+                trace!("fdc", "FDC: Force interrupt {}", interrupts);
             }
 
-            if interrupts == 0 {
-                // The current command is terminated and busy is reset.
-                self.read_index = 0;
-                self.read_last = 0;
-                self.data_buffer.clear();
-                self.status &= !(FDCStatus::Busy as u8);
-            } else {
-                panic!("FDC: Interrupt forced with non zero I");
+            // The current command is terminated and busy is reset.
+            self.read_index = 0;
+            self.read_last = 0;
+            self.data_buffer.clear();
+            self.status &= !(FDCStatus::Busy as u8);
+            if interrupts != 0 {
+                // Only the immediate interrupt is emulated
+                self.set_error(FdcError::InterruptConditions(command));
+                self.raise_nmi = interrupts & 0x08 != 0;
             }
         } else {
-            if self.trace {
-                println!("FDC: ${:02x} command not implemented", command);
-            }
-            panic!();
+            self.set_error(FdcError::UnknownCommand(command));
+            self.status = FDCStatus::SeekErrorOrRecordNotFound as u8;
+            self.raise_nmi = true;
         }
     }
 
+    fn record_activity(&mut self, write: bool) {
+        if let Some(activity) = self.activity.as_mut() {
+            activity.record(self.drive, self.side_2, self.track, write);
+        }
+    }
+
+    /// Short description of the controller registers, for the debugger
+    pub fn describe_state(&self) -> String {
+        format!("{}: {} side {} {} T{:02} S{:02} st:{:02x} dt:{:02x}",
+            if self.drive == 0 {"A"} else {"B"},
+            if self.single_density {"SD"} else {"DD"},
+            if self.side_2 {2} else {1},
+            if self.motor_on {"on "} else {"off"},
+            self.track, self.sector, self.status, self.data)
+    }
+
     pub fn get_status(&mut self) -> u8 {
         // Consume data if queued
         self.get_data();
@@ -271,7 +468,7 @@ impl FloppyController {
     pub fn put_track(&mut self, value: u8) {
         self.track = value;
         if self.trace {
-            println!("FDC: Set track {}", value);
+            trace!("fdc", "FDC: Set track {}", value);
         }
     }
 
@@ -282,7 +479,7 @@ impl FloppyController {
     pub fn put_sector(&mut self, value: u8) {
         self.sector = value;
         if self.trace {
-            println!("FDC: Set sector {}", value);
+            trace!("fdc", "FDC: Set sector {}", value);
         }
     }
 
@@ -297,23 +494,29 @@ impl FloppyController {
             // Store byte
             let index = self.read_index;
             let data = self.data;
-            self.media_selected().write_byte(index, data);
+            if !self.write_fault {
+                self.media_selected().write_byte(index, data);
+            }
             self.read_index += 1;
             self.raise_nmi = true;
             if self.read_index == self.read_last {
                 // We are done writing
-                self.media_selected().flush_disk();
+                self.flush_selected();
                 if self.trace {
-                    println!("FDC: Set data completed ${:02x} {}-{}-{}", self.data, self.read_index, self.read_last, self.sector);
+                    trace!("fdc", "FDC: Set data completed ${:02x} {}-{}-{}", self.data, self.read_index, self.read_last, self.sector);
                 }
-                self.status = FDCStatus::NoError as u8;
+                self.status = if self.write_fault {
+                    FDCStatus::WriteFault as u8
+                } else {
+                    FDCStatus::NoError as u8
+                };
                 self.read_index = 0;
                 self.read_last = 0;
             }
         }
 
         //if self.trace {
-        //    println!("FDC: Set data ${:02x}", value);
+        //    trace!("fdc", "FDC: Set data ${:02x}", value);
         //}
     }
 
@@ -331,7 +534,7 @@ impl FloppyController {
             if self.read_index == self.read_last {
                 // We are done reading
                 if self.trace {
-                    println!("FDC: Get data completed ${:02x} {}-{}-{}", self.data, self.read_index, self.read_last, self.sector);
+                    trace!("fdc", "FDC: Get data completed ${:02x} {}-{}-{}", self.data, self.read_index, self.read_last, self.sector);
                 }
                 self.status = FDCStatus::NoError as u8;
                 self.read_index = 0;
@@ -341,7 +544,7 @@ impl FloppyController {
         }
 
         //if self.trace {
-        //    println!("FDC: Get data ${:02x} {}-{}-{}", self.data, self.read_index, self.read_last, self.sector);
+        //    trace!("fdc", "FDC: Get data ${:02x} {}-{}-{}", self.data, self.read_index, self.read_last, self.sector);
         //}
         self.data
     }
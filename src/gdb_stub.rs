@@ -0,0 +1,619 @@
+use std::io::{Read, Write, Result, ErrorKind};
+use std::net::{TcpListener, TcpStream};
+
+use iz80::*;
+use super::KayproMachine;
+use super::snapshot;
+
+/*
+Server for the GDB remote serial protocol, to debug the emulated Z80
+from gdb or z88dk-gdb. It listens on localhost and supports the
+registers, memory, breakpoints, write watchpoints, step and continue.
+
+The registers are sent in the order of the gdb z80 target, all of
+them 16 bits little endian:
+    AF BC DE HL SP PC IX IY AF' BC' DE' HL' IR
+The alternate registers are read running EXX and EX AF,AF' on a
+scratch machine, they are reported as unavailable while the CPU is
+halted or has an NMI pending, as the NMI would be served there.
+
+Memory is accessed with the currently selected bank.
+*/
+
+const REGISTER_COUNT: usize = 13;
+const SIGNAL_INT: u8 = 2;
+const SIGNAL_TRAP: u8 = 5;
+
+pub struct GdbStub {
+    listener: TcpListener,
+    stream: Option<TcpStream>,
+    no_ack: bool,
+    stop_requested: bool,
+    stop_signal: u8,
+    stepping: bool,
+    resumed: bool,
+    breakpoints: Vec<u16>,
+}
+
+impl GdbStub {
+    pub fn new(port: u16) -> Result<GdbStub> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        Ok(GdbStub {
+            listener,
+            stream: None,
+            no_ack: false,
+            stop_requested: false,
+            stop_signal: SIGNAL_TRAP,
+            stepping: false,
+            resumed: false,
+            breakpoints: Vec::new(),
+        })
+    }
+
+    /// Blocks until a debugger connects. The emulation stays stopped
+    /// until the debugger resumes it.
+    pub fn wait_connection(&mut self) -> Result<()> {
+        let (stream, _) = self.listener.accept()?;
+        self.connected(stream)?;
+        self.listener.set_nonblocking(true)
+    }
+
+    fn connected(&mut self, stream: TcpStream) -> Result<()> {
+        stream.set_nodelay(true)?;
+        stream.set_nonblocking(true)?;
+        self.stream = Some(stream);
+        self.no_ack = false;
+        self.stop_requested = true;
+        self.resumed = false;
+        Ok(())
+    }
+
+    fn disconnected(&mut self) {
+        self.stream = None;
+        self.stop_requested = false;
+        self.stepping = false;
+        self.breakpoints.clear();
+    }
+
+    /// Called periodically while running to accept new connections and
+    /// to detect the interrupt requests from the debugger.
+    pub fn poll(&mut self) {
+        if self.stream.is_none() {
+            if let Ok((stream, _)) = self.listener.accept() {
+                if self.connected(stream).is_err() {
+                    self.disconnected();
+                }
+            }
+            return;
+        }
+
+        let mut buf = [0; 64];
+        let res = self.stream.as_mut().map(|stream| stream.read(&mut buf));
+        match res {
+            Some(Ok(0)) => self.disconnected(),
+            Some(Ok(size)) => {
+                if buf[..size].contains(&0x03) {
+                    self.request_stop(SIGNAL_INT);
+                }
+            }
+            Some(Err(ref err)) if err.kind() == ErrorKind::WouldBlock => {}
+            _ => self.disconnected(),
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    pub fn request_stop(&mut self, signal: u8) {
+        self.stop_requested = true;
+        self.stop_signal = signal;
+    }
+
+    pub fn request_trap(&mut self) {
+        self.request_stop(SIGNAL_TRAP);
+    }
+
+    /// Called before each instruction, returns true if the emulation has
+    /// to stop and serve the debugger.
+    pub fn should_stop(&mut self, pc: u16) -> bool {
+        if self.stream.is_none() {
+            return false;
+        }
+        if self.stepping || self.stop_requested {
+            return true;
+        }
+        if self.breakpoints.contains(&pc) {
+            self.stop_signal = SIGNAL_TRAP;
+            return true;
+        }
+        false
+    }
+
+    /// Serves the debugger requests until the execution is resumed.
+    /// Returns false if the debugger requested to kill the emulation.
+    pub fn serve(&mut self, cpu: &mut Cpu, machine: &mut KayproMachine) -> bool {
+        self.stop_requested = false;
+        self.stepping = false;
+        if let Some(stream) = self.stream.as_mut() {
+            let _ = stream.set_nonblocking(false);
+        }
+
+        if self.resumed {
+            // Report the stop to the debugger that resumed the execution
+            let reply = format!("S{:02x}", self.stop_signal);
+            if self.send_packet(&reply).is_err() {
+                self.disconnected();
+                return true;
+            }
+        }
+        self.stop_signal = SIGNAL_TRAP;
+
+        loop {
+            let packet = match self.receive_packet() {
+                Ok(packet) => packet,
+                Err(_) => {
+                    self.disconnected();
+                    return true;
+                }
+            };
+
+            let reply = match packet.as_bytes().first() {
+                Some(b'?') => format!("S{:02x}", SIGNAL_TRAP),
+                Some(b'g') => read_registers(cpu, machine),
+                Some(b'G') => {
+                    write_registers(cpu, machine, &packet[1..]);
+                    "OK".to_owned()
+                }
+                Some(b'p') => read_register(cpu, machine, &packet[1..]),
+                Some(b'P') => write_register(cpu, machine, &packet[1..]),
+                Some(b'm') => read_memory(machine, &packet[1..]),
+                Some(b'M') => write_memory(machine, &packet[1..]),
+                Some(b'Z') => self.insert_point(machine, &packet[1..]),
+                Some(b'z') => self.remove_point(machine, &packet[1..]),
+                Some(b'c') | Some(b's') => {
+                    if let Some(address) = parse_hex(&packet[1..]) {
+                        cpu.registers().set_pc(address as u16);
+                    }
+                    self.stepping = packet.starts_with('s');
+                    self.resumed = true;
+                    break;
+                }
+                Some(b'D') => {
+                    let _ = self.send_packet("OK");
+                    self.disconnected();
+                    return true;
+                }
+                Some(b'k') => {
+                    self.disconnected();
+                    return false;
+                }
+                Some(b'H') => "OK".to_owned(),
+                Some(b'T') => "OK".to_owned(),
+                _ => {
+                    if packet.starts_with("qSupported") {
+                        "PacketSize=1000;QStartNoAckMode+".to_owned()
+                    } else if packet == "QStartNoAckMode" {
+                        let _ = self.send_packet("OK");
+                        self.no_ack = true;
+                        continue;
+                    } else if packet == "qAttached" {
+                        "1".to_owned()
+                    } else {
+                        // Not supported
+                        "".to_owned()
+                    }
+                }
+            };
+
+            if self.send_packet(&reply).is_err() {
+                self.disconnected();
+                return true;
+            }
+        }
+
+        if let Some(stream) = self.stream.as_mut() {
+            let _ = stream.set_nonblocking(true);
+        }
+        true
+    }
+
+    fn insert_point(&mut self, machine: &mut KayproMachine, args: &str) -> String {
+        match parse_point(args) {
+            Some((b'0', address, _)) | Some((b'1', address, _)) => {
+                if !self.breakpoints.contains(&address) {
+                    self.breakpoints.push(address);
+                }
+                "OK".to_owned()
+            }
+            Some((b'2', address, len)) => {
+                let spec = watch_spec(address, len);
+                match machine.watchpoints.add(&spec) {
+                    Ok(_) => "OK".to_owned(),
+                    Err(_) => "E01".to_owned(),
+                }
+            }
+            _ => "".to_owned(),
+        }
+    }
+
+    fn remove_point(&mut self, machine: &mut KayproMachine, args: &str) -> String {
+        match parse_point(args) {
+            Some((b'0', address, _)) | Some((b'1', address, _)) => {
+                self.breakpoints.retain(|b| *b != address);
+                "OK".to_owned()
+            }
+            Some((b'2', address, len)) => {
+                let spec = watch_spec(address, len);
+                let index = machine.watchpoints.list().iter().position(|w| *w == spec);
+                if let Some(index) = index {
+                    machine.watchpoints.remove(index);
+                }
+                "OK".to_owned()
+            }
+            _ => "".to_owned(),
+        }
+    }
+
+    fn receive_packet(&mut self) -> Result<String> {
+        let stream = self.stream.as_mut()
+            .ok_or_else(|| std::io::Error::from(ErrorKind::NotConnected))?;
+        let mut byte = [0; 1];
+
+        loop {
+            // Wait for the start of a packet, skip acks and interrupts
+            loop {
+                stream.read_exact(&mut byte)?;
+                if byte[0] == b'$' {
+                    break;
+                }
+            }
+
+            let mut data = Vec::new();
+            loop {
+                stream.read_exact(&mut byte)?;
+                if byte[0] == b'#' {
+                    break;
+                }
+                data.push(byte[0]);
+            }
+            let mut checksum = [0; 2];
+            stream.read_exact(&mut checksum)?;
+
+            let expected = std::str::from_utf8(&checksum).ok()
+                .and_then(|text| u8::from_str_radix(text, 16).ok());
+            let computed = data.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+            if self.no_ack {
+                return Ok(String::from_utf8_lossy(&data).into_owned());
+            } else if expected == Some(computed) {
+                stream.write_all(b"+")?;
+                return Ok(String::from_utf8_lossy(&data).into_owned());
+            } else {
+                stream.write_all(b"-")?;
+            }
+        }
+    }
+
+    fn send_packet(&mut self, data: &str) -> Result<()> {
+        let stream = self.stream.as_mut()
+            .ok_or_else(|| std::io::Error::from(ErrorKind::NotConnected))?;
+        let checksum = data.bytes().fold(0u8, |acc, b| acc.wrapping_add(b));
+        let packet = format!("${}#{:02x}", data, checksum);
+        stream.write_all(packet.as_bytes())?;
+        stream.flush()
+    }
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 16).ok()
+}
+
+fn parse_point(args: &str) -> Option<(u8, u16, u16)> {
+    let params: Vec<&str> = args.split(',').collect();
+    if params.len() < 3 || params[0].len() != 1 {
+        return None;
+    }
+    let address = parse_hex(params[1])? as u16;
+    let len = parse_hex(params[2])? as u16;
+    Some((params[0].as_bytes()[0], address, len))
+}
+
+fn watch_spec(address: u16, len: u16) -> String {
+    let end = address.wrapping_add(len.max(1) - 1);
+    format!("write {:04x}-{:04x}", address, end)
+}
+
+fn le16(value: u16) -> String {
+    format!("{:02x}{:02x}", value & 0xff, value >> 8)
+}
+
+fn parse_le16(text: &str) -> Option<u16> {
+    if text.len() < 4 {
+        return None;
+    }
+    let low = u8::from_str_radix(&text[0..2], 16).ok()?;
+    let high = u8::from_str_radix(&text[2..4], 16).ok()?;
+    Some(low as u16 + ((high as u16) << 8))
+}
+
+fn register_values(cpu: &mut Cpu, machine: &KayproMachine) -> [Option<u16>; REGISTER_COUNT] {
+    let alternate = if snapshot::can_save(cpu, machine) {
+        Some(snapshot::alternate_registers(cpu))
+    } else {
+        None
+    };
+    let regs = cpu.registers();
+    [
+        Some(regs.get16(Reg16::AF)),
+        Some(regs.get16(Reg16::BC)),
+        Some(regs.get16(Reg16::DE)),
+        Some(regs.get16(Reg16::HL)),
+        Some(regs.get16(Reg16::SP)),
+        Some(regs.pc()),
+        Some(regs.get16(Reg16::IX)),
+        Some(regs.get16(Reg16::IY)),
+        alternate.map(|a| a[0]),
+        alternate.map(|a| a[1]),
+        alternate.map(|a| a[2]),
+        alternate.map(|a| a[3]),
+        Some(((regs.get8(Reg8::I) as u16) << 8) + regs.get8(Reg8::R) as u16),
+    ]
+}
+
+fn set_register(cpu: &mut Cpu, machine: &KayproMachine, index: usize, value: u16) -> bool {
+    let regs = cpu.registers();
+    match index {
+        0 => regs.set16(Reg16::AF, value),
+        1 => regs.set16(Reg16::BC, value),
+        2 => regs.set16(Reg16::DE, value),
+        3 => regs.set16(Reg16::HL, value),
+        4 => regs.set16(Reg16::SP, value),
+        5 => regs.set_pc(value),
+        6 => regs.set16(Reg16::IX, value),
+        7 => regs.set16(Reg16::IY, value),
+        8..=11 => {
+            if !snapshot::can_save(cpu, machine) {
+                return false;
+            }
+            let mut alternate = snapshot::alternate_registers(cpu);
+            alternate[index - 8] = value;
+            snapshot::set_alternate_registers(cpu, &alternate);
+        }
+        12 => {
+            regs.set8(Reg8::I, (value >> 8) as u8);
+            regs.set8(Reg8::R, value as u8);
+        }
+        _ => return false,
+    }
+    true
+}
+
+fn read_registers(cpu: &mut Cpu, machine: &KayproMachine) -> String {
+    register_values(cpu, machine).iter()
+        .map(|value| match value {
+            Some(value) => le16(*value),
+            None => "xxxx".to_owned(),
+        })
+        .collect()
+}
+
+fn write_registers(cpu: &mut Cpu, machine: &KayproMachine, data: &str) {
+    for index in 0..REGISTER_COUNT {
+        if let Some(value) = data.get(index * 4..).and_then(parse_le16) {
+            set_register(cpu, machine, index, value);
+        }
+    }
+}
+
+fn read_register(cpu: &mut Cpu, machine: &KayproMachine, args: &str) -> String {
+    let index = parse_hex(args).map(|index| index as usize);
+    match index {
+        Some(index) if index < REGISTER_COUNT => {
+            match register_values(cpu, machine)[index] {
+                Some(value) => le16(value),
+                None => "xxxx".to_owned(),
+            }
+        }
+        _ => "E01".to_owned(),
+    }
+}
+
+fn write_register(cpu: &mut Cpu, machine: &KayproMachine, args: &str) -> String {
+    let parsed = args.split_once('=')
+        .and_then(|(index, value)| Some((parse_hex(index)? as usize, parse_le16(value)?)));
+    match parsed {
+        Some((index, value)) if set_register(cpu, machine, index, value) => "OK".to_owned(),
+        _ => "E01".to_owned(),
+    }
+}
+
+fn read_memory(machine: &KayproMachine, args: &str) -> String {
+    let parsed = args.split_once(',')
+        .and_then(|(address, len)| Some((parse_hex(address)? as u16, parse_hex(len)?)));
+    match parsed {
+        Some((address, len)) => (0..len.min(0x10000))
+            .map(|offset| format!("{:02x}", machine.peek(address.wrapping_add(offset as u16))))
+            .collect(),
+        None => "E01".to_owned(),
+    }
+}
+
+fn write_memory(machine: &mut KayproMachine, args: &str) -> String {
+    let parsed = args.split_once(':').and_then(|(location, data)| {
+        let (address, len) = location.split_once(',')?;
+        Some((parse_hex(address)? as u16, parse_hex(len)? as usize, data))
+    });
+    match parsed {
+        Some((address, len, data)) if data.len() >= len * 2 => {
+            let rom_rank = machine.is_rom_rank();
+            for offset in 0..len {
+                let value = match data.get(offset * 2..offset * 2 + 2)
+                        .and_then(|byte| u8::from_str_radix(byte, 16).ok()) {
+                    Some(value) => value,
+                    None => return "E01".to_owned(),
+                };
+                machine.poke_bank(rom_rank, address.wrapping_add(offset as u16), value);
+            }
+            "OK".to_owned()
+        }
+        _ => "E01".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::floppy_controller::FloppyController;
+    use crate::keyboard_unix::Keyboard;
+    use std::time::Duration;
+
+    fn machine() -> KayproMachine {
+        KayproMachine::new(FloppyController::new(false, false),
+            Keyboard::new_headless(&[]), false, false)
+    }
+
+    #[test]
+    fn alternate_registers_unavailable() {
+        let mut machine = machine();
+        let mut cpu = Cpu::new_z80();
+        cpu.registers().set16(Reg16::SP, 0x8000);
+        cpu.registers().set_pc(0x1234);
+        assert_eq!(&read_registers(&mut cpu, &machine)[32..48], "0000000000000000");
+
+        // Pending NMI, the registers are read without serving it
+        cpu.signal_nmi();
+        machine.nmi_pending = true;
+        let registers = read_registers(&mut cpu, &machine);
+        assert_eq!(&registers[16..24], "00803412");
+        assert_eq!(&registers[32..48], "xxxxxxxxxxxxxxxx");
+        assert_eq!(read_register(&mut cpu, &machine, "8"), "xxxx");
+        assert_eq!(write_register(&mut cpu, &machine, "9=3412"), "E01");
+        cpu.execute_instruction(&mut machine);
+        machine.nmi_pending = false;
+        assert_eq!(machine.peek16(0x7ffe), 0x1234);
+
+        // Halted
+        machine.poke(0x9000, 0x76);
+        cpu.registers().set_pc(0x9000);
+        cpu.execute_instruction(&mut machine);
+        assert!(cpu.is_halted());
+        assert_eq!(&read_registers(&mut cpu, &machine)[32..48], "xxxxxxxxxxxxxxxx");
+    }
+
+    fn packet(data: &str) -> String {
+        let checksum = data.bytes().fold(0u8, |acc, b| acc.wrapping_add(b));
+        format!("${}#{:02x}", data, checksum)
+    }
+
+    // A stub connected to a client on localhost
+    fn connected_stub() -> (GdbStub, TcpStream) {
+        let mut stub = GdbStub::new(0).unwrap();
+        let address = stub.listener.local_addr().unwrap();
+        let client = TcpStream::connect(address).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let (stream, _) = stub.listener.accept().unwrap();
+        stub.connected(stream).unwrap();
+        stub.stream.as_ref().unwrap().set_nonblocking(false).unwrap();
+        (stub, client)
+    }
+
+    fn read_text(client: &mut TcpStream, len: usize) -> String {
+        let mut buf = vec![0; len];
+        client.read_exact(&mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn packet_framing() {
+        let (mut stub, mut client) = connected_stub();
+
+        // Acks and interrupts before the packet are skipped
+        client.write_all(b"+\x03$g#67").unwrap();
+        assert_eq!(stub.receive_packet().unwrap(), "g");
+        assert_eq!(read_text(&mut client, 1), "+");
+
+        // A bad checksum is rejected and the packet sent again
+        client.write_all(b"$m0,1#00").unwrap();
+        client.write_all(packet("m0,1").as_bytes()).unwrap();
+        assert_eq!(stub.receive_packet().unwrap(), "m0,1");
+        assert_eq!(read_text(&mut client, 2), "-+");
+
+        stub.send_packet("OK").unwrap();
+        assert_eq!(read_text(&mut client, 6), "$OK#9a");
+        stub.send_packet("").unwrap();
+        assert_eq!(read_text(&mut client, 4), "$#00");
+
+        // Without acks the checksum is not checked
+        stub.no_ack = true;
+        client.write_all(b"$c#00").unwrap();
+        assert_eq!(stub.receive_packet().unwrap(), "c");
+        client.write_all(b"$s#73").unwrap();
+        assert_eq!(stub.receive_packet().unwrap(), "s");
+    }
+
+    #[test]
+    fn serve_requests() {
+        let mut machine = machine();
+        let mut cpu = Cpu::new_z80();
+        let (mut stub, mut client) = connected_stub();
+        let requests = [
+            ("?", "S05"),
+            ("M9000,2:abcd", "OK"),
+            ("m9000,2", "abcd"),
+            ("m9000", "E01"),
+            ("M9000,2:ab", "E01"),
+            ("M9000,1:zz", "E01"),
+            ("P3=3412", "OK"),
+            ("p3", "3412"),
+            ("p20", "E01"),
+            ("P0=zz", "E01"),
+            ("Z0,0100,1", "OK"),
+            ("Z2,0200,2", "OK"),
+            ("Z9,0300,1", ""),
+            ("Z0,zz,1", ""),
+            ("vUnknown", ""),
+        ];
+        for (request, _) in requests.iter() {
+            client.write_all(packet(request).as_bytes()).unwrap();
+        }
+        client.write_all(packet("c").as_bytes()).unwrap();
+        assert!(stub.serve(&mut cpu, &mut machine));
+
+        for (request, reply) in requests.iter() {
+            let expected = format!("+{}", packet(reply));
+            assert_eq!(read_text(&mut client, expected.len()), expected, "{}", request);
+        }
+        assert_eq!(read_text(&mut client, 1), "+");
+        assert_eq!(cpu.registers().get16(Reg16::HL), 0x1234);
+        assert!(stub.breakpoints.contains(&0x0100));
+        assert!(stub.should_stop(0x0100));
+        assert_eq!(machine.watchpoints.list(), vec!["write 0200-0201".to_owned()]);
+
+        // Removed with z, the registers are read with g
+        for request in ["z0,0100,1", "z2,0200,2", "g"].iter() {
+            client.write_all(packet(request).as_bytes()).unwrap();
+        }
+        client.write_all(packet("s").as_bytes()).unwrap();
+        assert!(stub.serve(&mut cpu, &mut machine));
+        assert_eq!(read_text(&mut client, 7), packet("S05"));
+        assert_eq!(read_text(&mut client, 2 * (1 + 6)), format!("+{}+{}", packet("OK"), packet("OK")));
+        let registers = read_text(&mut client, 1 + REGISTER_COUNT * 4 + 4);
+        assert_eq!(&registers[2 + 12..2 + 16], "3412");
+        assert!(stub.breakpoints.is_empty());
+        assert!(machine.watchpoints.list().is_empty());
+    }
+
+    #[test]
+    fn parse_arguments() {
+        assert_eq!(parse_point("0,1a2b,1"), Some((b'0', 0x1a2b, 1)));
+        assert_eq!(parse_point("2,8000,4"), Some((b'2', 0x8000, 4)));
+        assert_eq!(parse_point("2,8000"), None);
+        assert_eq!(parse_point("10,8000,1"), None);
+        assert_eq!(watch_spec(0x8000, 4), "write 8000-8003");
+        assert_eq!(watch_spec(0x8000, 0), "write 8000-8000");
+        assert_eq!(le16(0x1234), "3412");
+        assert_eq!(parse_le16("3412"), Some(0x1234));
+        assert_eq!(parse_le16("341"), None);
+        assert_eq!(parse_le16("zz12"), None);
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{stderr, BufWriter, Read, Result, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::cpm_disk;
+
+/*
+Services of the emulator for the programs on the guest, enabled with
+--host-services on the unused ports 0x1a and 0x1b. The ROMs probe the
+port 0x14 to detect the model, so it is avoided. A service is
+selected writing its number on the command port 0x1a, then the data is
+written or read on the data port 0x1b:
+    1 EXIT:   the next byte written is the exit code of the emulator
+    2 STDERR: the bytes written are printed on the host stderr, the
+              CP/M line ends converted to the host ones
+    3 TIME:   the bytes read are the host time, UTC, as year (low byte
+              first), month, day, hour, minute and second
+    4 ARGS:   the bytes read are the text of the host arguments given
+              with --host-args, ended with a zero
+    5 OPEN READ:  the bytes written are the name of a host file, ended
+              with a zero, to open it for reading. Then a byte is read,
+              0 if the file was opened or 0xff on error.
+    6 READ:   reads a record of the file. The first byte read is the
+              number of bytes of the record, 0 at the end of the file,
+              then 128 bytes padded with ^Z.
+    7 OPEN WRITE: as OPEN READ, to create the file for writing.
+    8 WRITE:  the bytes written are added to the file
+    9 CLOSE:  closes the file, then a byte is read, 0 if the file was
+              written or 0xff on error.
+The host files are relative to the directory given with --host-dir,
+the names with paths are rejected.
+They are opened for reading with the name as given or in lowercase, and
+created with the name in lowercase. The R.COM and W.COM utilities, on
+the utils folder, use them to copy files from and to the host. They are
+added to the embedded CP/M disk when the services are enabled.
+Reading the command port returns 'K' when the services are enabled, to
+detect them. Reading the data port with nothing to read returns 0.
+
+Example:
+    ld a, 1
+    out (1ah), a    ; EXIT
+    ld a, 0
+    out (1bh), a    ; with code 0
+*/
+
+const SIGNATURE: u8 = b'K';
+
+const EXIT: u8 = 1;
+const STDERR: u8 = 2;
+const TIME: u8 = 3;
+const ARGS: u8 = 4;
+const OPEN_READ: u8 = 5;
+const READ: u8 = 6;
+const OPEN_WRITE: u8 = 7;
+const WRITE: u8 = 8;
+const CLOSE: u8 = 9;
+
+const RECORD_SIZE: usize = 128;
+const DONE: u8 = 0;
+const FAILED: u8 = 0xff;
+
+static UTILITIES: [(&[u8; 11], &[u8]); 2] = [
+    (b"R       COM", include_bytes!("../utils/r.com")),
+    (b"W       COM", include_bytes!("../utils/w.com")),
+];
+
+enum HostFile {
+    Reading(File),
+    Writing(BufWriter<File>),
+}
+
+pub struct HostServices {
+    args: String,
+    dir: PathBuf,
+    command: u8,
+    to_read: VecDeque<u8>,
+    exit_code: Option<u8>,
+    name: Vec<u8>,
+    file: Option<HostFile>,
+    // Set when a write fails, to be reported on close
+    write_failed: bool,
+}
+
+impl HostServices {
+    pub fn new(args: &str, dir: &str) -> HostServices {
+        HostServices {
+            args: args.to_owned(),
+            dir: PathBuf::from(dir),
+            command: 0,
+            to_read: VecDeque::new(),
+            exit_code: None,
+            name: Vec::new(),
+            file: None,
+            write_failed: false,
+        }
+    }
+
+    /// Exit code requested by the guest, if any
+    pub fn exit_code(&self) -> Option<u8> {
+        self.exit_code
+    }
+
+    pub fn get_status(&self) -> u8 {
+        SIGNATURE
+    }
+
+    pub fn put_command(&mut self, command: u8) {
+        self.command = command;
+        self.to_read.clear();
+        match command {
+            TIME => self.to_read.extend(time_bytes()),
+            ARGS => {
+                self.to_read.extend(self.args.bytes());
+                self.to_read.push_back(0);
+            }
+            OPEN_READ | OPEN_WRITE => {
+                self.name.clear();
+                self.file = None;
+                self.write_failed = false;
+            }
+            READ => self.read_record(),
+            CLOSE => {
+                let result = match self.file.take() {
+                    Some(HostFile::Writing(mut file)) => file.flush(),
+                    _ => Ok(()),
+                };
+                let failed = result.is_err() || self.write_failed;
+                self.write_failed = false;
+                self.to_read.push_back(if failed {FAILED} else {DONE});
+            }
+            _ => {},
+        }
+    }
+
+    pub fn put_data(&mut self, value: u8) {
+        match self.command {
+            EXIT => self.exit_code = Some(value),
+            STDERR if value != b'\r' => {
+                let _ = stderr().write_all(&[value]);
+            },
+            OPEN_READ | OPEN_WRITE if value != 0 => self.name.push(value),
+            OPEN_READ | OPEN_WRITE => {
+                self.file = self.open(self.command == OPEN_WRITE);
+                self.to_read.push_back(if self.file.is_some() {DONE} else {FAILED});
+            },
+            WRITE => if let Some(HostFile::Writing(file)) = self.file.as_mut() {
+                if file.write_all(&[value]).is_err() {
+                    // The error is reported on close
+                    self.file = None;
+                    self.write_failed = true;
+                }
+            },
+            _ => {},
+        }
+    }
+
+    pub fn get_data(&mut self) -> u8 {
+        self.to_read.pop_front().unwrap_or(0)
+    }
+
+    fn open(&self, write: bool) -> Option<HostFile> {
+        let name = String::from_utf8_lossy(&self.name).to_string();
+        // Only the files of the host directory, not the paths out of it
+        if name.is_empty() || name.contains(['/', '\\']) || name.contains("..")
+                || Path::new(&name).is_absolute() {
+            return None;
+        }
+        let lowercase = self.dir.join(name.to_lowercase());
+        if write {
+            File::create(lowercase).ok().map(|file| HostFile::Writing(BufWriter::new(file)))
+        } else {
+            File::open(self.dir.join(name)).or_else(|_| File::open(lowercase))
+                .ok().map(HostFile::Reading)
+        }
+    }
+
+    fn read_record(&mut self) {
+        let mut record = [0x1a; RECORD_SIZE];
+        let mut size = 0;
+        if let Some(HostFile::Reading(file)) = self.file.as_mut() {
+            while size < RECORD_SIZE {
+                match file.read(&mut record[size..]) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => size += n,
+                }
+            }
+        }
+        self.to_read.push_back(size as u8);
+        self.to_read.extend(record.iter());
+    }
+}
+
+/// Adds the R.COM and W.COM utilities to a CP/M disk image
+pub fn add_utilities(image: &mut [u8]) -> Result<()> {
+    for (name, data) in UTILITIES.iter() {
+        cpm_disk::add_file(image, name, data)?;
+    }
+    Ok(())
+}
+
+fn time_bytes() -> Vec<u8> {
+    let seconds = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs()).unwrap_or(0);
+    let days = (seconds / 86400) as i64;
+    let time = seconds % 86400;
+    let (year, month, day) = civil_from_days(days);
+    vec![year as u8, (year >> 8) as u8, month, day,
+        (time / 3600) as u8, (time / 60 % 60) as u8, (time % 60) as u8]
+}
+
+// Date from the days since 1970-01-01, as in the algorithm of
+// Howard Hinnant for the proleptic Gregorian calendar
+fn civil_from_days(days: i64) -> (u16, u8, u8) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 {mp + 3} else {mp - 9};
+    let year = yoe + era * 400 + if month <= 2 {1} else {0};
+    (year as u16, month as u8, day as u8)
+}
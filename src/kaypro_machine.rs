@@ -1,9 +1,13 @@
-use std::fs::{File};
-use std::io::{Write};
+use std::fs::{self, File};
+use std::io::{Error, Write, Result};
 
 use iz80::Machine;
 use super::FloppyController;
+use super::crash_report::IoHistory;
+use super::host_services::HostServices;
 use super::keyboard_unix::Keyboard;
+use super::snapshot::{SnapshotReader, SnapshotWriter};
+use super::watchpoints::Watchpoints;
 
 /* Memory map:
 
@@ -54,8 +58,8 @@ const IO_PORT_NAMES: [&str; 32] = [
     /* 0x17 */"-",
     /* 0x18 */"-",
     /* 0x19 */"-",
-    /* 0x1a */"-",
-    /* 0x1b */"-",
+    /* 0x1a */"Host services, command/status register.",
+    /* 0x1b */"Host services, data register.",
     /* 0x1c */"PIO 2 channel A data register: ",
     /* 0x1d */"PIO 2 channel A control register.",
     /* 0x1e */"PIO 2 channel B data register.",
@@ -63,14 +67,12 @@ const IO_PORT_NAMES: [&str; 32] = [
     ];
 
 
-//static ROM: &[u8] = include_bytes!("../roms/81-149c.rom");
-static ROM: &[u8] = include_bytes!("../roms/81-232.rom");
-//static ROM: &[u8] = include_bytes!("../roms/kplus83.rom");
-//static ROM: &[u8] = include_bytes!("../roms/omni2.u47");
-//static ROM: &[u8] = include_bytes!("../roms/kaypro_ii_roadrunner_1_5.bin");
-//static ROM: &[u8] = include_bytes!("../roms/trom34_3.rom");
+// Other ROMs can be loaded with --rom
+static DEFAULT_ROM: &[u8] = include_bytes!("../roms/81-232.rom");
+const MAX_ROM_SIZE: usize = 0x3000;
 
 pub struct KayproMachine {
+    rom: Vec<u8>,
     ram: [u8; 65536],
     pub vram: [u8; 4096],
     pub vram_dirty: bool,
@@ -81,27 +83,78 @@ pub struct KayproMachine {
 
     pub keyboard: Keyboard,
     pub floppy_controller: FloppyController,
+    pub watchpoints: Watchpoints,
+    pub io_history: IoHistory,
+    // Set by the main loop from the signal of the NMI until the CPU
+    // serves it, iz80 doesn't tell
+    pub nmi_pending: bool,
+    // Only with --host-services
+    pub host_services: Option<HostServices>,
 }
 
 impl KayproMachine {
-    pub fn new(floppy_controller: FloppyController,
+    pub fn new(floppy_controller: FloppyController, keyboard: Keyboard,
             trace_io: bool, trace_system_bits: bool) -> KayproMachine {
         KayproMachine {
+            rom: DEFAULT_ROM.to_vec(),
             ram: [0; 65536],
             vram: [0; 4096],
             vram_dirty: false,
             system_bits: SystemBit::Bank as u8 | SystemBit::MotorsOff as u8,
             trace_io,
             trace_system_bits,
-            keyboard: Keyboard::new(),
+            keyboard,
             floppy_controller,
+            watchpoints: Watchpoints::new(),
+            io_history: IoHistory::new(),
+            nmi_pending: false,
+            host_services: None,
         }
     }
 
+    pub fn load_rom(&mut self, filename: &str) -> Result<()> {
+        let rom = fs::read(filename)?;
+        if rom.is_empty() || rom.len() > MAX_ROM_SIZE {
+            return Err(Error::other("Invalid ROM size"));
+        }
+        self.rom = rom;
+        Ok(())
+    }
+
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
     pub fn is_rom_rank(&self) -> bool {
         self.system_bits & SystemBit::Bank as u8 != 0
     }
 
+    /// Reads memory as seen with the ROM bank selected or not,
+    /// regardless of the current bank.
+    pub fn peek_bank(&self, rom_rank: bool, address: u16) -> u8 {
+        if address < 0x3000 && rom_rank {
+            self.rom[(address as usize) % self.rom.len()]
+        } else if address < 0x4000 && rom_rank {
+            self.vram[address as usize - 0x3000]
+        } else {
+            self.ram[address as usize]
+        }
+    }
+
+    /// Writes memory as seen with the ROM bank selected or not,
+    /// regardless of the current bank.
+    pub fn poke_bank(&mut self, rom_rank: bool, address: u16, value: u8) {
+        if address < 0x3000 && rom_rank {
+            // Writes to ROM go to the RAM
+            self.ram[address as usize] = value;
+        } else if address < 0x4000 && rom_rank {
+            self.vram[address as usize - 0x3000] = value;
+            self.vram_dirty = true;
+        } else {
+            self.ram[address as usize] = value;
+        }
+    }
+
     fn update_system_bits(&mut self, bits: u8) {
         self.system_bits = bits;
         if bits & SystemBit::DriveA as u8 != 0 {
@@ -124,6 +177,29 @@ impl KayproMachine {
         }
     }
 
+    pub fn save_state(&mut self, w: &mut SnapshotWriter) {
+        w.bytes(&self.ram);
+        w.bytes(&self.vram);
+        w.u8(self.system_bits);
+        self.floppy_controller.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut SnapshotReader) -> Result<()> {
+        let mut ram = [0; 65536];
+        let mut vram = [0; 4096];
+        r.bytes_into(&mut ram)?;
+        r.bytes_into(&mut vram)?;
+        let system_bits = r.u8()?;
+        self.floppy_controller.load_state(r)?;
+
+        // The system bits are already applied to the floppy controller
+        self.ram = ram;
+        self.vram = vram;
+        self.system_bits = system_bits;
+        self.vram_dirty = true;
+        Ok(())
+    }
+
     pub fn save_bios(&self) {
         let start = self.ram[1] as usize +
             ((self.ram[2] as usize) << 8) - 3;
@@ -136,40 +212,37 @@ impl KayproMachine {
 
 impl Machine for KayproMachine {
     fn peek(&self, address: u16) -> u8 {
-        if address < 0x3000 && self.is_rom_rank() {
-            ROM[(address as usize) % ROM.len()]
-        } else if address < 0x4000 && self.is_rom_rank() {
-            self.vram[address as usize - 0x3000]
-        } else {
-            self.ram[address as usize]
-        }
+        self.peek_bank(self.is_rom_rank(), address)
     }
 
     fn poke(&mut self, address: u16, value: u8) {
-        if address < 0x3000 && self.is_rom_rank() {
+        let rom_rank = self.is_rom_rank();
+        if !self.watchpoints.is_empty() {
             // Writes to ROM go to the RAM
-            self.ram[address as usize] = value;
-        } else if address < 0x4000 && self.is_rom_rank() {
-            self.vram[address as usize - 0x3000] = value;
-            self.vram_dirty = true;
-        } else {
-            self.ram[address as usize] = value;
+            let previous = self.peek_bank(rom_rank && address >= 0x3000, address);
+            self.watchpoints.check_write(address, previous, value, rom_rank);
         }
+        self.poke_bank(rom_rank, address, value);
     }
 
     fn port_out(&mut self, address: u16, value: u8) {
 
         let port = address as u8 & 0b_1001_1111; // Pins used
+        self.io_history.record(true, port, value);
+        if !self.watchpoints.is_empty() {
+            let rom_rank = self.is_rom_rank();
+            self.watchpoints.check_port_out(port, value, rom_rank);
+        }
         if port >= 0x80 {
             // Pin 7 is tied to enable of the 3-8 decoder
             if self.trace_io {
-                println!("OUT(0x{:02x} 'Ignored', 0x{:02x})", port, value);
+                trace!("io", "OUT(0x{:02x} 'Ignored', 0x{:02x})", port, value);
             }
             return
         }
 
         if self.trace_io && port != 0x1c {
-            println!("OUT(0x{:02x} '{}', 0x{:02x}): ", port, IO_PORT_NAMES[port as usize], value);
+            trace!("io", "OUT(0x{:02x} '{}', 0x{:02x}): ", port, IO_PORT_NAMES[port as usize], value);
         }
         match port {
             // Floppy controller
@@ -177,6 +250,14 @@ impl Machine for KayproMachine {
             0x11 => self.floppy_controller.put_track(value),
             0x12 => self.floppy_controller.put_sector(value),
             0x13 => self.floppy_controller.put_data(value),
+            // Host services
+            0x1a | 0x1b => if let Some(host_services) = self.host_services.as_mut() {
+                if port == 0x1a {
+                    host_services.put_command(value);
+                } else {
+                    host_services.put_data(value);
+                }
+            },
             // System bits
             0x1c => self.update_system_bits(value),
             _ => {}
@@ -187,7 +268,7 @@ impl Machine for KayproMachine {
         let port = address as u8 & 0b_1001_1111; // Pins used
         if port > 0x80 { // Pin 7 is tied to enable of the 3-8 decoder
             if self.trace_io {
-                println!("IN(0x{:02x} 'Ignored')", port);
+                trace!("io", "IN(0x{:02x} 'Ignored')", port);
             }
             return 0x00
         }
@@ -202,26 +283,37 @@ impl Machine for KayproMachine {
             0x11 => self.floppy_controller.get_track(),
             0x12 => self.floppy_controller.get_sector(),
             0x13 => self.floppy_controller.get_data(),
+            // Host services
+            0x1a | 0x1b => match self.host_services.as_mut() {
+                Some(host_services) if port == 0x1a => host_services.get_status(),
+                Some(host_services) => host_services.get_data(),
+                None => 0xca,
+            },
             0x1c => self.system_bits,
             _ => 0xca,
         }; 
 
         if self.trace_io && port != 0x13 && port != 0x07 && port != 0x1c {
-            println!("IN(0x{:02x} '{}') = 0x{:02x}", port, IO_PORT_NAMES[port as usize], value);
+            trace!("io", "IN(0x{:02x} '{}') = 0x{:02x}", port, IO_PORT_NAMES[port as usize], value);
+        }
+        self.io_history.record(false, port, value);
+        if !self.watchpoints.is_empty() {
+            let rom_rank = self.is_rom_rank();
+            self.watchpoints.check_port_in(port, value, rom_rank);
         }
         value
     }
 }
 
 fn print_system_bits(system_bits: u8) {
-    print!("System bits: ");
-    if system_bits & SystemBit::DriveA as u8 != 0           {print!("DriveA ");}
-    if system_bits & SystemBit::DriveB as u8 != 0           {print!("DriveB ");}
-    if system_bits & SystemBit::Side2 as u8 != 0            {print!("Side2 ");}
-    if system_bits & SystemBit::CentronicsReady  as u8 != 0 {print!("CentronicsReady ");}
-    if system_bits & SystemBit::CentronicsStrobe as u8 != 0 {print!("CentronicsStrobe ");}
-    if system_bits & SystemBit::SingleDensity as u8 != 0    {print!("SingleDensity ");}
-    if system_bits & SystemBit::MotorsOff as u8 != 0        {print!("MotorsOff ");}
-    if system_bits & SystemBit::Bank as u8 != 0             {print!("ROM ");}
-    println!();
+    let mut text = "System bits: ".to_owned();
+    if system_bits & SystemBit::DriveA as u8 != 0           {text += "DriveA ";}
+    if system_bits & SystemBit::DriveB as u8 != 0           {text += "DriveB ";}
+    if system_bits & SystemBit::Side2 as u8 != 0            {text += "Side2 ";}
+    if system_bits & SystemBit::CentronicsReady  as u8 != 0 {text += "CentronicsReady ";}
+    if system_bits & SystemBit::CentronicsStrobe as u8 != 0 {text += "CentronicsStrobe ";}
+    if system_bits & SystemBit::SingleDensity as u8 != 0    {text += "SingleDensity ";}
+    if system_bits & SystemBit::MotorsOff as u8 != 0        {text += "MotorsOff ";}
+    if system_bits & SystemBit::Bank as u8 != 0             {text += "ROM ";}
+    trace!("system", "{}", text);
 }
@@ -1,3 +1,4 @@
+use std::collections::VecDeque;
 use std::io::{Read, stdin};
 use std::thread;
 use std::time::Duration;
@@ -6,6 +7,15 @@ use termios::*;
 
 const STDIN_FD: i32 = 0;
 
+// Polls of the keyboard on a window to consider that the program is
+// waiting for a key. The headless input is typed only then, as some
+// programs discard the keys pressed while busy, like DIR on CP/M.
+const HEADLESS_WAITING_POLLS: u64 = 64;
+// Windows waiting for a key with no input left to end the emulation
+const HEADLESS_IDLE_WINDOWS: u64 = 100;
+// Windows waiting for a key with no input left to consider it stable
+const HEADLESS_SETTLED_WINDOWS: u64 = 4;
+
 #[derive(Copy, Clone)]
 pub enum Command {
     Help,
@@ -13,8 +23,14 @@ pub enum Command {
     SelectDiskA,
     SelectDiskB,
     ShowStatus,
+    DebugLayout,
     TraceCPU,
     SaveMemory,
+    SaveState,
+    LoadState,
+    Rewind,
+    Debug,
+    Reload,
 }
 
 pub struct Keyboard {
@@ -22,6 +38,11 @@ pub struct Keyboard {
     key: u8,
     pub commands: Vec<Command>,
     key_available: bool,
+    headless: bool,
+    input: VecDeque<u8>,
+    polls: u64,
+    waiting: bool,
+    idle_windows: u64,
 }
 
 impl Keyboard {
@@ -34,12 +55,72 @@ impl Keyboard {
             key: 0,
             commands: Vec::<Command>::new(),
             key_available: false,
+            headless: false,
+            input: VecDeque::new(),
+            polls: 0,
+            waiting: false,
+            idle_windows: 0,
         };
 
         c.setup_host_terminal(false);
         c
     }
 
+    /// Keyboard that doesn't touch the terminal. The keys are taken one
+    /// by one from the input, as typed on the host terminal, with the
+    /// line ends as RETURN.
+    pub fn new_headless(input: &[u8]) -> Keyboard {
+        let mut keys = VecDeque::new();
+        for (i, &byte) in input.iter().enumerate() {
+            match byte {
+                b'\n' if i > 0 && input[i-1] == b'\r' => {},
+                b'\n' => keys.push_back(b'\r'),
+                _ => keys.push_back(byte),
+            }
+        }
+        Keyboard {
+            initial_termios: None,
+            key: 0,
+            commands: Vec::<Command>::new(),
+            key_available: false,
+            headless: true,
+            input: keys,
+            polls: 0,
+            waiting: false,
+            idle_windows: 0,
+        }
+    }
+
+    /// Adds keys to type on headless mode
+    pub fn type_keys(&mut self, keys: &[u8]) {
+        self.input.extend(keys);
+        self.idle_windows = 0;
+    }
+
+    /// Called periodically on headless mode to check if the program is
+    /// waiting for a key
+    pub fn end_window(&mut self) {
+        self.waiting = self.polls >= HEADLESS_WAITING_POLLS;
+        self.polls = 0;
+        if self.waiting && self.input.is_empty() && !self.key_available {
+            self.idle_windows += 1;
+        } else {
+            self.idle_windows = 0;
+        }
+    }
+
+    /// True when all the headless input has been typed and the program
+    /// has been waiting for a key for a while
+    pub fn is_idle(&self) -> bool {
+        self.idle_windows > HEADLESS_IDLE_WINDOWS
+    }
+
+    /// True when all the headless input has been typed and the program
+    /// is waiting for a key
+    pub fn is_waiting_for_key(&self) -> bool {
+        self.idle_windows > HEADLESS_SETTLED_WINDOWS
+    }
+
     fn setup_host_terminal(&self, blocking: bool) {
         if let Some(mut initial) = self.initial_termios {
             initial.c_iflag &= !(IXON | ICRNL);
@@ -52,7 +133,9 @@ impl Keyboard {
 
     pub fn is_key_pressed(&mut self) -> bool {
         self.consume_input();
-        if !self.key_available {
+        if self.headless {
+            self.polls += 1;
+        } else if !self.key_available {
             // Avoid 100% CPU usage waiting for input.
             thread::sleep(Duration::from_nanos(100));
         }
@@ -69,17 +152,48 @@ impl Keyboard {
         self.key
     }
 
-    pub fn read_line(&mut self) -> String {
+    /// Reads a line for the debugger or the prompts, None at the end of
+    /// the input
+    pub fn read_line(&mut self) -> Option<String> {
+        if self.headless {
+            if self.input.is_empty() {
+                return None;
+            }
+            let mut line = Vec::new();
+            while let Some(byte) = self.input.pop_front() {
+                if byte == b'\r' {
+                    break;
+                }
+                line.push(byte);
+            }
+            // Echoed as the terminal would do
+            let line = String::from_utf8_lossy(&line).to_string();
+            println!("{}", line);
+            return Some(line.trim().to_string());
+        }
         if let Some(initial) = self.initial_termios {
             tcsetattr(STDIN_FD, TCSANOW, &initial).unwrap();
         }
         let mut buffer = String::new();
-        stdin().read_line(&mut buffer).unwrap();
+        let size = stdin().read_line(&mut buffer).unwrap_or(0);
         self.setup_host_terminal(false);
-        buffer.trim().to_string()
+        if size == 0 {
+            return None;
+        }
+        Some(buffer.trim().to_string())
     }
 
     pub fn consume_input(&mut self) {
+        if self.headless {
+            // A key at a time, when the program is waiting for it
+            if self.waiting && !self.key_available && !self.input.is_empty() {
+                let input: Vec<u8> = self.input.iter().take(16).copied().collect();
+                let size = self.parse_key(&input);
+                self.input.drain(..size);
+                self.waiting = false;
+            }
+            return;
+        }
         let mut buf = [0;100];
         let size = stdin().read(&mut buf).unwrap_or(0);
         if size > 0 {
@@ -88,9 +202,16 @@ impl Keyboard {
     }
 
     fn parse_input(&mut self, size: usize, input: &[u8]) {
-        if size == 0 {
-            // No new keys
-        } else if size > 2 && input[0] == 0x1b {
+        let mut i = 0;
+        while i < size {
+            i += self.parse_key(&input[i..size]);
+        }
+    }
+
+    // Parses the first key of the input, returns the bytes used
+    fn parse_key(&mut self, input: &[u8]) -> usize {
+        let size = input.len();
+        if size > 2 && input[0] == 0x1b {
             // Escape sequences
             // See 5.4 in the ECMA-48 spec
             let mut seq = "".to_owned();
@@ -119,6 +240,9 @@ impl Keyboard {
                 "OQ" => { // F2
                     self.commands.push(Command::ShowStatus);
                 }
+                "OR" => { // F3
+                    self.commands.push(Command::DebugLayout);
+                },
                 "OS" => { // F4
                     self.commands.push(Command::Quit);
                 }
@@ -134,6 +258,21 @@ impl Keyboard {
                 "[19~" => { // F8
                     self.commands.push(Command::TraceCPU);
                 }
+                "[20~" => { // F9
+                    self.commands.push(Command::SaveState);
+                }
+                "[21~" => { // F10
+                    self.commands.push(Command::LoadState);
+                }
+                "[21;2~" => { // Shift+F10
+                    self.commands.push(Command::Reload);
+                }
+                "[23~" => { // F11
+                    self.commands.push(Command::Rewind);
+                }
+                "[24~" => { // F12
+                    self.commands.push(Command::Debug);
+                }
                 "[3~" => {
                     // "Delete" key mapped to "DEL"
                     self.key = 0x7f;
@@ -166,14 +305,15 @@ impl Keyboard {
                 }
                 _ => {}
             }
-            // Parse the rest
-            self.parse_input(size-i, &input[i..]);
+            i
         } else if size >= 2 && input[0] == 0xc3 && input[1] == 0xb1 {
             self.key = b':'; // ñ is on the : position
             self.key_available = true;
+            2
         } else if size >= 2 && input[0] == 0xc3 && input[1] == 0x91 {
             self.key = b';'; // Ñ is on the ; position
             self.key_available = true;
+            2
         } else {
             self.key = input[0];
             self.key = match self.key {
@@ -181,8 +321,7 @@ impl Keyboard {
                 _ => self.key & 0x7f,
             };
             self.key_available = true;
-            // Parse the rest
-            self.parse_input(size-1, &input[1..]);
+            1
         }
     }
 }
@@ -190,8 +329,9 @@ impl Keyboard {
 
 impl Drop for Keyboard {
     fn drop(&mut self) {
+        // Called also when unwinding a panic, it must not panic again
         if let Some(initial) = self.initial_termios {
-            tcsetattr(STDIN_FD, TCSANOW, &initial).unwrap();
+            let _ = tcsetattr(STDIN_FD, TCSANOW, &initial);
         }
     }
 }
@@ -0,0 +1,238 @@
+use std::fs;
+
+use iz80::*;
+
+use super::KayproMachine;
+use super::address;
+use super::cpm_memory::{self, TPA};
+
+/*
+Loader of a program straight into the TPA, without copying it to a disk
+image. The file is a binary loaded at 0x0100, or at the address given as
+in "prog.com@8000", or an Intel HEX file with the .hex extension.
+
+The bytes are written when the CCP asks for a command line, with the
+BDOS call 10 from the CCP. With jump, the call is replaced by a jump to
+the entry point: the load address, or the start address of the HEX
+file, with an empty command line. The return address is changed to
+0x0000, the program returns to CP/M with a warm boot.
+
+The file can be read again with reload, to be loaded on the next prompt
+of the CCP.
+*/
+
+const C_READSTR: u8 = 10;
+const FCB1: u16 = 0x005c;
+const FCB2: u16 = 0x006c;
+const COMMAND_TAIL: u16 = 0x0080;
+
+// Bytes to write, and their address
+type Chunk = (u16, Vec<u8>);
+
+pub struct Loader {
+    filename: String,
+    address: Option<u16>,
+    jump: bool,
+    chunks: Vec<Chunk>,
+    entry: u16,
+    pending: bool,
+}
+
+impl Loader {
+    pub fn new(spec: &str, jump: bool) -> Result<Loader, String> {
+        let (filename, address) = match spec.rsplit_once('@') {
+            Some((filename, address)) => {
+                let address = address::parse_hex(address)
+                    .map_err(|_| format!("Invalid load address '{}'", address))?;
+                (filename, Some(address))
+            }
+            None => (spec, None),
+        };
+        let mut loader = Loader {
+            filename: filename.to_owned(),
+            address,
+            jump,
+            chunks: Vec::new(),
+            entry: TPA,
+            pending: false,
+        };
+        loader.reload()?;
+        Ok(loader)
+    }
+
+    /// Reads the file again, to be loaded on the next prompt of the CCP
+    pub fn reload(&mut self) -> Result<(), String> {
+        let content = fs::read(&self.filename)
+            .map_err(|err| format!("Error loading file '{}': {}", self.filename, err))?;
+        let is_hex = self.filename.to_ascii_lowercase().ends_with(".hex");
+        let (chunks, entry) = if is_hex {
+            let text = String::from_utf8_lossy(&content);
+            let (chunks, start) = parse_hex(&text)
+                .map_err(|err| format!("{}: {}", self.filename, err))?;
+            let lowest = chunks.iter().map(|(address, _)| *address).min().unwrap_or(TPA);
+            (chunks, start.unwrap_or(lowest))
+        } else {
+            let address = self.address.unwrap_or(TPA);
+            (vec![(address, content)], address)
+        };
+        for (address, data) in chunks.iter() {
+            if *address as usize + data.len() > 0x10000 {
+                return Err(format!("{}: the data at {:04x} goes past 0xffff", self.filename, address));
+            }
+        }
+        self.chunks = chunks;
+        self.entry = entry;
+        self.pending = true;
+        Ok(())
+    }
+
+    /// Called before each instruction, loads the file when the CCP asks
+    /// for a command line
+    pub fn trace(&mut self, cpu: &mut Cpu, machine: &mut KayproMachine) -> Option<String> {
+        if !self.pending || machine.is_rom_rank() || cpu.registers().pc() != 0x0005
+                || cpu.registers().get8(Reg8::C) != C_READSTR {
+            return None;
+        }
+        let ccp = cpm_memory::ccp_start(machine);
+        let sp = cpu.registers().get16(Reg16::SP);
+        let return_address = machine.peek16(sp);
+        if !cpm_memory::is_ccp(ccp, return_address) {
+            return None;
+        }
+        self.pending = false;
+
+        for (address, data) in self.chunks.iter() {
+            if *address < TPA || *address as usize + data.len() > ccp as usize {
+                return Some(format!("{} doesn't fit on the TPA, {:04x}-{:04x}",
+                    self.filename, TPA, ccp - 1));
+            }
+        }
+        for (address, data) in self.chunks.iter() {
+            for (i, &value) in data.iter().enumerate() {
+                machine.poke_bank(false, address + i as u16, value);
+            }
+        }
+        if self.jump {
+            // An empty command line, as seen by the program
+            machine.poke_bank(false, COMMAND_TAIL, 0);
+            machine.poke_bank(false, COMMAND_TAIL + 1, 0);
+            for fcb in [FCB1, FCB2].iter() {
+                machine.poke_bank(false, *fcb, 0);
+                for i in 1..12 {
+                    machine.poke_bank(false, fcb + i, b' ');
+                }
+            }
+            machine.poke16(sp, 0x0000);
+            cpu.registers().set_pc(self.entry);
+        }
+        None
+    }
+}
+
+// Data records and start address of an Intel HEX file
+fn parse_hex(text: &str) -> Result<(Vec<Chunk>, Option<u16>), String> {
+    let mut chunks: Vec<Chunk> = Vec::new();
+    let mut start = None;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let invalid = || format!("line {}: invalid HEX record", i + 1);
+        let hex = line.strip_prefix(':').ok_or_else(invalid)?;
+        if !hex.is_ascii() || hex.len() % 2 != 0 {
+            return Err(invalid());
+        }
+        let bytes = (0..hex.len()).step_by(2)
+            .map(|j| u8::from_str_radix(&hex[j..j + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| invalid())?;
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(invalid());
+        }
+        if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            return Err(format!("line {}: bad checksum", i + 1));
+        }
+        let address = ((bytes[1] as u16) << 8) | bytes[2] as u16;
+        let data = &bytes[4..bytes.len() - 1];
+        match bytes[3] {
+            0x00 => match chunks.last_mut() {
+                // Consecutive records are joined
+                Some((last, last_data)) if *last as usize + last_data.len() == address as usize =>
+                    last_data.extend_from_slice(data),
+                _ => chunks.push((address, data.to_vec())),
+            },
+            0x01 => break,
+            // Extended addresses are only valid for the first 64K
+            0x02 | 0x04 if data.iter().all(|b| *b == 0) => {},
+            0x03 | 0x05 if data.len() == 4 => start = Some(((data[2] as u16) << 8) | data[3] as u16),
+            _ => return Err(format!("line {}: unsupported HEX record type {:02x}", i + 1, bytes[3])),
+        }
+    }
+    Ok((chunks, start))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Record with its checksum
+    fn record(kind: u8, address: u16, data: &[u8]) -> String {
+        let mut bytes = vec![data.len() as u8, (address >> 8) as u8, address as u8, kind];
+        bytes.extend_from_slice(data);
+        let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        bytes.push(sum.wrapping_neg());
+        let hex: String = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        format!(":{}\n", hex)
+    }
+
+    #[test]
+    fn data_records() {
+        let text = ":03010000C3000138\n:00000001FF\n";
+        assert_eq!(parse_hex(text), Ok((vec![(0x0100, vec![0xc3, 0x00, 0x01])], None)));
+
+        // Consecutive records are joined, the blank lines skipped
+        let text = record(0, 0x0100, &[1, 2]) + "\n" + &record(0, 0x0102, &[3])
+            + &record(0, 0x0200, &[4]) + &record(1, 0, &[]);
+        assert_eq!(parse_hex(&text), Ok((vec![(0x0100, vec![1, 2, 3]), (0x0200, vec![4])], None)));
+    }
+
+    #[test]
+    fn end_of_file() {
+        // Nothing is read after the EOF record, not even invalid lines
+        let text = record(0, 0x0100, &[1]) + &record(1, 0, &[]) + &record(0, 0x0200, &[2]) + "junk\n";
+        assert_eq!(parse_hex(&text), Ok((vec![(0x0100, vec![1])], None)));
+        // Or without one
+        assert_eq!(parse_hex(&record(0, 0x0100, &[1])), Ok((vec![(0x0100, vec![1])], None)));
+    }
+
+    #[test]
+    fn start_address() {
+        let text = ":0400000300001234B3\n".to_owned() + &record(1, 0, &[]);
+        assert_eq!(parse_hex(&text), Ok((vec![], Some(0x1234))));
+        let text = record(5, 0, &[0, 0, 0x80, 0x00]) + &record(2, 0, &[0, 0]) + &record(4, 0, &[0, 0]);
+        assert_eq!(parse_hex(&text), Ok((vec![], Some(0x8000))));
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(parse_hex(":03010000C3000139\n"), Err("line 1: bad checksum".to_owned()));
+        let text = record(0, 0x0100, &[1]) + ":00000001FE\n";
+        assert_eq!(parse_hex(&text), Err("line 2: bad checksum".to_owned()));
+    }
+
+    #[test]
+    fn malformed_lines() {
+        for line in ["03010000C3000138", ":03010000C300013", ":03010000C30001XY",
+                ":02010000C3000138", ":0000", ":é3010000C3000138"].iter() {
+            assert_eq!(parse_hex(line), Err("line 1: invalid HEX record".to_owned()), "{}", line);
+        }
+        // Above the first 64K
+        assert_eq!(parse_hex(&record(4, 0, &[0, 1])),
+            Err("line 1: unsupported HEX record type 04".to_owned()));
+        assert_eq!(parse_hex(&record(3, 0, &[0x12, 0x34])),
+            Err("line 1: unsupported HEX record type 03".to_owned()));
+        assert_eq!(parse_hex(&record(6, 0, &[])),
+            Err("line 1: unsupported HEX record type 06".to_owned()));
+    }
+}
@@ -1,24 +1,88 @@
-use clap::{Arg, App};
+use std::fs;
+use std::io::{self, Read};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::process;
+
+use clap::{Arg, App, SubCommand};
 use iz80::*;
 
+// First, for the trace! macro to be available on the other modules
+#[macro_use]
+mod trace_log;
+
+mod address;
+mod assembler;
+mod bdos_trace;
+mod bios_trace;
+mod coverage;
+mod cpm_disk;
+mod cpm_memory;
+mod cpu_trace;
+mod crash_report;
+mod debug_panes;
+mod debugger;
+mod disassembler;
 mod kaypro_machine;
+mod loader;
+mod pending_returns;
 mod floppy_controller;
+mod gdb_stub;
+mod host_services;
 mod keyboard_unix;
 mod media;
+mod profiler;
+mod rewind;
+mod rom_trace;
+mod runner;
 mod screen;
+mod script;
+mod snapshot;
+mod symbols;
+mod watchpoints;
 
 use self::kaypro_machine::KayproMachine;
 use self::floppy_controller::FloppyController;
 use self::screen::Screen;
-use self::keyboard_unix::Command;
+use self::bdos_trace::BdosTrace;
+use self::bios_trace::BiosTrace;
+use self::coverage::{Coverage, CoverageFormat};
+use self::crash_report::History;
+use self::debugger::Debugger;
+use self::debug_panes::DebugPanes;
+use self::gdb_stub::GdbStub;
+use self::host_services::HostServices;
+use self::keyboard_unix::{Command, Keyboard};
+use self::loader::Loader;
+use self::profiler::{DiskActivity, Profiler};
+use self::rewind::{Rewind, INSTRUCTIONS_PER_MS};
+use self::rom_trace::RomTrace;
+use self::runner::Runner;
+use self::script::{Script, Status};
+use self::symbols::Symbols;
+use self::trace_log::TraceFormat;
 
 // Welcome message
 const WELCOME: &str =
 "Kaypro https://github.com/ivanizag/izkaypro
 Emulation of the Kaypro II computer";
 
+// The debug panes are rebuilt every few screen refreshes
+const DEBUG_PANES_REFRESHES: u64 = 32;
+
+// Instructions between the checks of the keyboard on headless mode
+const HEADLESS_WINDOW: u64 = 2*1024;
+
+// Entry point of the NMI handler
+const NMI_ADDRESS: u16 = 0x0066;
 
 fn main() {
+    // The exit code is returned for the keyboard to restore the terminal
+    // before exiting
+    process::exit(run());
+}
+
+fn run() -> i32 {
     // Parse arguments
     let matches = App::new(WELCOME)
         .arg(Arg::with_name("DISKA")
@@ -29,6 +93,11 @@ fn main() {
             .help("Disk B: image file. Default is a blank disk")
             .required(false)
             .index(2))
+        .arg(Arg::with_name("rom")
+            .long("rom")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("ROM image to use instead of the embedded 81-232"))
         .arg(Arg::with_name("cpu_trace")
             .short("c")
             .long("cpu-trace")
@@ -57,10 +126,194 @@ fn main() {
             .short("b")
             .long("bdos-trace")
             .help("Traces calls to the CP/M BDOS entrypoints"))
+        .arg(Arg::with_name("bdos_filter")
+            .long("bdos-filter")
+            .value_name("FUNCTIONS")
+            .takes_value(true)
+            .requires("bdos_trace")
+            .help("Limits the BDOS trace to a comma separated list of function numbers or names"))
+        .arg(Arg::with_name("bios_trace")
+            .long("bios-trace")
+            .help("Traces calls to the CP/M BIOS entrypoints"))
+        .arg(Arg::with_name("trace_file")
+            .long("trace-file")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("Writes the traces to a file, keeping the screen in place"))
+        .arg(Arg::with_name("trace_format")
+            .long("trace-format")
+            .value_name("FORMAT")
+            .takes_value(true)
+            .possible_values(&["text", "jsonl"])
+            .default_value("text")
+            .help("Format of the traces, jsonl writes a JSON object per line"))
+        .arg(Arg::with_name("profile")
+            .long("profile")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("Writes a report of the hot spots, the BDOS and BIOS calls and the disk activity on exit"))
+        .arg(Arg::with_name("coverage")
+            .long("coverage")
+            .value_name("RANGE")
+            .takes_value(true)
+            .help("Records the code coverage of an address range, as \"[rom:|ram:]start-end\""))
+        .arg(Arg::with_name("coverage_listing")
+            .long("coverage-listing")
+            .value_name("FILE")
+            .takes_value(true)
+            .requires("coverage")
+            .help("Assembler listing to annotate with the coverage, like a z80asm .prn"))
+        .arg(Arg::with_name("coverage_format")
+            .long("coverage-format")
+            .value_name("FORMAT")
+            .takes_value(true)
+            .possible_values(&["listing", "lcov"])
+            .default_value("listing")
+            .help("Format of the coverage, lcov needs a listing"))
+        .arg(Arg::with_name("coverage_out")
+            .long("coverage-out")
+            .value_name("FILE")
+            .takes_value(true)
+            .requires("coverage")
+            .help("File for the coverage written on exit [default: coverage.txt or lcov.info]"))
+        .arg(Arg::with_name("debug")
+            .short("d")
+            .long("debug")
+            .help("Starts stopped on the debugger"))
+        .arg(Arg::with_name("debug_layout")
+            .long("debug-layout")
+            .help("Shows the debug panes next to the screen"))
+        .arg(Arg::with_name("headless")
+            .long("headless")
+            .help("Runs without using the terminal until the program waits for a key with no input left, then prints the screen"))
+        .arg(Arg::with_name("input")
+            .long("input")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("Keys to type on headless mode, - for stdin"))
+        .arg(Arg::with_name("dump_screen")
+            .long("dump-screen")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("Writes the screen at exit of headless mode to a file instead of stdout"))
+        .arg(Arg::with_name("timeout")
+            .long("timeout")
+            .value_name("TIME")
+            .takes_value(true)
+            .help("Max emulated time on headless mode, as 30s or 500ms, exits with an error when reached"))
+        .arg(Arg::with_name("script")
+            .long("script")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("Runs a script on headless mode to wait for texts, type, insert disks and check the screen"))
+        .arg(Arg::with_name("host_services")
+            .long("host-services")
+            .help("Enables the ports 0x1a and 0x1b for the programs to exit the emulator, print on stderr, get the time and arguments and copy host files"))
+        .arg(Arg::with_name("host_args")
+            .long("host-args")
+            .value_name("TEXT")
+            .takes_value(true)
+            .requires("host_services")
+            .help("Arguments for the programs using the host services"))
+        .arg(Arg::with_name("host_dir")
+            .long("host-dir")
+            .value_name("DIR")
+            .takes_value(true)
+            .requires("host_services")
+            .help("Directory of the host files for the programs using the host services, like R.COM and W.COM"))
+        .arg(Arg::with_name("gdb")
+            .long("gdb")
+            .value_name("PORT")
+            .takes_value(true)
+            .help("Waits for a gdb remote protocol connection on the local TCP port"))
+        .arg(Arg::with_name("watch")
+            .long("watch")
+            .value_name("WATCHPOINT")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Stops or logs on memory writes or port accesses, as \"write|in|out|io [rom:|ram:]start[-end] [=value] [changed] [log]\""))
+        .arg(Arg::with_name("symbols")
+            .long("symbols")
+            .value_name("FILE")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Loads symbols for the traces and the debugger from a .sym or .lst file, prefix with rom: or ram: to limit to a bank"))
+        .arg(Arg::with_name("load")
+            .long("load")
+            .value_name("FILE[@ADDR]")
+            .takes_value(true)
+            .help("Loads a binary file at 0x0100, or at the hex address given, or an Intel HEX file, on the TPA when the CCP is ready. Shift+F10 loads it again"))
+        .arg(Arg::with_name("load_jump")
+            .long("load-jump")
+            .requires("load")
+            .help("Jumps to the program loaded with --load instead of returning to the CCP"))
+        .arg(Arg::with_name("load_state")
+            .long("load-state")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("Restores a machine state saved with F9"))
+        .arg(Arg::with_name("rewind_interval")
+            .long("rewind-interval")
+            .value_name("MS")
+            .takes_value(true)
+            .default_value("500")
+            .help("Emulated milliseconds between the snapshots kept for rewind"))
+        .arg(Arg::with_name("rewind_depth")
+            .long("rewind-depth")
+            .value_name("COUNT")
+            .takes_value(true)
+            .default_value("20")
+            .help("Number of snapshots kept for rewind, 0 to disable"))
+        .arg(Arg::with_name("history_depth")
+            .long("history-depth")
+            .value_name("COUNT")
+            .takes_value(true)
+            .default_value("256")
+            .help("Number of instructions kept for the crash report on HALT or panic, 0 to disable"))
+        .subcommand(SubCommand::with_name("asm")
+            .about("Assembles a Z80 source file, with the z80asm syntax, to a binary")
+            .arg(Arg::with_name("SOURCE")
+                .help("Source file to assemble")
+                .required(true)
+                .index(1))
+            .arg(Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("Binary file, the source name with .com by default"))
+            .arg(Arg::with_name("listing")
+                .short("l")
+                .long("listing")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("Saves the listing, that can be used as symbols"))
+            .arg(Arg::with_name("symbols")
+                .short("s")
+                .long("symbols")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("Saves the symbols, to be used with --symbols"))
+            .arg(Arg::with_name("run")
+                .long("run")
+                .help("Runs the program on the emulator, as with --load and --load-jump, with its symbols")))
+        .subcommand(SubCommand::with_name("run")
+            .about("Runs a CP/M program on headless mode, with the console output on stdout")
+            .arg(Arg::with_name("PROGRAM")
+                .help("Program to copy to a disk on B: and run")
+                .required(true)
+                .index(1))
+            .arg(Arg::with_name("ARGS")
+                .help("Command line for the program")
+                .multiple(true)
+                .index(2)))
         .get_matches();
 
     let disk_a = matches.value_of("DISKA");
     let disk_b = matches.value_of("DISKB");
+    let rom = matches.value_of("rom");
     let mut trace_cpu = matches.is_present("cpu_trace");
     let trace_io = matches.is_present("io_trace");
     let trace_fdc = matches.is_present("fdc_trace");
@@ -68,6 +321,47 @@ fn main() {
     let trace_system_bits = matches.is_present("system_bits");
     let trace_rom = matches.is_present("rom_trace");
     let trace_bdos = matches.is_present("bdos_trace");
+    let trace_bios = matches.is_present("bios_trace");
+    let trace_file = matches.value_of("trace_file");
+    let trace_format = TraceFormat::parse(matches.value_of("trace_format").unwrap_or(""))
+        .unwrap_or(TraceFormat::Text);
+    let profile = matches.value_of("profile");
+    let coverage_listing = matches.value_of("coverage_listing");
+    let coverage_format = CoverageFormat::parse(matches.value_of("coverage_format").unwrap_or(""))
+        .unwrap_or(CoverageFormat::Listing);
+    let coverage_out = matches.value_of("coverage_out").unwrap_or(
+        if coverage_format == CoverageFormat::Lcov {"lcov.info"} else {"coverage.txt"});
+    let debug = matches.is_present("debug");
+    let debug_layout = matches.is_present("debug_layout");
+    let script = matches.value_of("script");
+    let run = matches.subcommand_matches("run");
+    let headless = matches.is_present("headless") || script.is_some() || run.is_some();
+    let input = matches.value_of("input");
+    let dump_screen = matches.value_of("dump_screen");
+    let timeout = matches.value_of("timeout");
+    let host_services = matches.is_present("host_services");
+    let host_args = matches.value_of("host_args");
+    let host_dir = matches.value_of("host_dir").unwrap_or(".");
+    let gdb_port = matches.value_of("gdb");
+    let mut load = matches.value_of("load").map(str::to_owned);
+    let mut load_jump = matches.is_present("load_jump");
+    let load_state = matches.value_of("load_state");
+    let rewind_interval = matches.value_of("rewind_interval").unwrap_or("").parse::<u64>();
+    let rewind_depth = matches.value_of("rewind_depth").unwrap_or("").parse::<usize>();
+    let (rewind_interval, rewind_depth) = match (rewind_interval, rewind_depth) {
+        (Ok(interval), Ok(depth)) => (interval, depth),
+        _ => {
+            eprintln!("Invalid rewind interval or depth");
+            return 1;
+        }
+    };
+    let history_depth = match matches.value_of("history_depth").unwrap_or("").parse::<usize>() {
+        Ok(depth) => depth,
+        Err(_) => {
+            eprintln!("Invalid history depth");
+            return 1;
+        }
+    };
 
     let any_trace = trace_io
         || trace_cpu
@@ -75,49 +369,423 @@ fn main() {
         || trace_fdc_rw
         || trace_rom
         || trace_bdos
+        || trace_bios
         || trace_system_bits;
 
+    // Traces on stdout break the in place update of the screen
+    if let Err(err) = trace_log::init(trace_format, trace_file) {
+        eprintln!("Error creating the trace file '{}': {}", trace_file.unwrap_or(""), err);
+        return 1;
+    }
+    let traces_on_screen = !trace_log::is_file();
+
+    // The program assembled can be run as with --load and --load-jump
+    let mut assembled = None;
+    if let Some(asm) = matches.subcommand_matches("asm") {
+        let source = asm.value_of("SOURCE").unwrap_or("");
+        let output = match asm.value_of("output") {
+            Some(output) => output.to_owned(),
+            None => Path::new(source).with_extension("com").to_string_lossy().to_string(),
+        };
+        match assembler::assemble_file(source, &output, asm.value_of("listing"), asm.value_of("symbols")) {
+            Ok(assembly) => {
+                if !asm.is_present("run") {
+                    return 0;
+                }
+                load = Some(format!("{}@{:04x}", output, assembly.origin));
+                load_jump = true;
+                assembled = Some(assembly);
+            }
+            Err(errors) => {
+                for error in errors {
+                    eprintln!("{}", error);
+                }
+                return 1;
+            }
+        }
+    }
+
+    // The program to run is on a disk for B:, with the command line typed
+    let mut runner = None;
+    let mut run_disk = None;
+    let mut run_keys = Vec::new();
+    let mut run_args = Vec::new();
+    if let Some(run) = run {
+        let program = run.value_of("PROGRAM").unwrap_or("");
+        let args: Vec<&str> = run.values_of("ARGS").map(|args| args.collect()).unwrap_or_default();
+        run_args = args.clone();
+        match Runner::prepare(program, &args) {
+            Ok((disk, keys)) => {
+                run_disk = Some((program, disk));
+                run_keys = keys;
+                runner = Some(Runner::new());
+            }
+            Err(err) => {
+                eprintln!("Error preparing '{}': {}", program, err);
+                return 1;
+            }
+        }
+    }
+
+    // Headless, the keys come from the input instead of the terminal
+    let keyboard = if headless {
+        let keys = match input {
+            Some("-") => {
+                let mut keys = Vec::new();
+                io::stdin().read_to_end(&mut keys).map(|_| keys)
+            }
+            Some(input) => fs::read(input),
+            None => Ok(Vec::new()),
+        };
+        match keys {
+            Ok(keys) => Keyboard::new_headless(&[run_keys, keys].concat()),
+            Err(err) => {
+                eprintln!("Error reading the input '{}': {}", input.unwrap_or(""), err);
+                return 1;
+            }
+        }
+    } else {
+        Keyboard::new()
+    };
+
+    if (input.is_some() || dump_screen.is_some() || timeout.is_some()) && !headless {
+        eprintln!("--input, --dump-screen and --timeout need --headless or --script");
+        return 1;
+    }
+    let timeout = match timeout.map(|text| (text, script::parse_time(text))) {
+        Some((_, Some(ms))) => Some(ms),
+        Some((text, None)) => {
+            eprintln!("Invalid timeout '{}'", text);
+            return 1;
+        }
+        None => None,
+    };
+    let mut timed_out = false;
+    let mut script = match script.map(Script::load) {
+        Some(Ok(script)) => Some(script),
+        Some(Err(err)) => {
+            eprintln!("{}", err);
+            return 1;
+        }
+        None => None,
+    };
+    let mut script_passed = false;
+
     // Init device
     let floppy_controller = FloppyController::new(trace_fdc, trace_fdc_rw);
-    let mut screen = Screen::new(!any_trace);
-    let mut machine = KayproMachine::new(floppy_controller,
+    let mut screen = Screen::new(!(any_trace && traces_on_screen), headless);
+    let mut machine = KayproMachine::new(floppy_controller, keyboard,
         trace_io, trace_system_bits);
     let mut cpu = Cpu::new_z80();
-    cpu.set_trace(trace_cpu);
+    if host_services {
+        // The arguments of the program run are the default ones
+        let args = host_args.map(str::to_owned).unwrap_or_else(|| run_args.join(" "));
+        machine.host_services = Some(HostServices::new(&args, host_dir));
+    }
+
+    // Load the ROM
+    if let Some(rom) = rom {
+        if let Err(err) = machine.load_rom(rom) {
+            eprintln!("Error loading ROM '{}': {}", rom, err);
+            return 1;
+        }
+    }
+    let rom_table = rom_trace::identify(machine.rom());
+    let mut rom_trace = None;
+    if trace_rom {
+        match rom_table {
+            Some(rom_table) => {
+                println!("Tracing the entry points of the ROM {}", rom_table.name);
+                rom_trace = Some(RomTrace::new(rom_table));
+            }
+            None => println!("No ROM trace table for the ROM with CRC32 {:08x}",
+                rom_trace::crc32(machine.rom())),
+        }
+    }
+
+    let mut bios_trace = if trace_bios {Some(BiosTrace::new())} else {None};
+    let mut bdos_trace = None;
+    if trace_bdos {
+        let mut trace = BdosTrace::new();
+        if let Some(filter) = matches.value_of("bdos_filter") {
+            if let Err(err) = trace.set_filter(filter) {
+                eprintln!("{}", err);
+                return 1;
+            }
+        }
+        bdos_trace = Some(trace);
+    }
+
+    // Symbols
+    let mut symbols = Symbols::new();
+    if let Some(rom_table) = rom_table {
+        rom_table.add_symbols(&mut symbols);
+    }
+    if let Some(assembly) = assembled.as_ref() {
+        symbols.add_assembled(&assembly.symbols);
+    }
+    if let Some(files) = matches.values_of("symbols") {
+        for file in files {
+            if let Err(err) = symbols.load(file) {
+                eprintln!("Error loading symbols '{}': {}", file, err);
+                return 1;
+            }
+        }
+    }
+    // With symbols or a trace file, the CPU trace is done here instead of by iz80
+    let own_cpu_trace = symbols.is_loaded() || !traces_on_screen;
+    cpu_trace::set_iz80_trace(&mut cpu, trace_cpu && !own_cpu_trace);
+
+    let mut profiler = None;
+    if profile.is_some() {
+        profiler = Some(Profiler::new());
+        machine.floppy_controller.activity = Some(DiskActivity::new());
+    }
+
+    let mut coverage = None;
+    if let Some(range) = matches.value_of("coverage") {
+        match Coverage::new(range) {
+            Ok(c) => coverage = Some(c),
+            Err(err) => {
+                eprintln!("{}", err);
+                return 1;
+            }
+        }
+        if coverage_format == CoverageFormat::Lcov && coverage_listing.is_none() {
+            eprintln!("The lcov coverage format needs a listing");
+            return 1;
+        }
+    }
+
+    let mut loader = None;
+    if let Some(load) = load {
+        match Loader::new(&load, load_jump) {
+            Ok(l) => loader = Some(l),
+            Err(err) => {
+                eprintln!("{}", err);
+                return 1;
+            }
+        }
+    }
+
+    // Watchpoints
+    if let Some(watches) = matches.values_of("watch") {
+        for watch in watches {
+            if let Err(err) = machine.watchpoints.add(watch) {
+                eprintln!("{}", err);
+                return 1;
+            }
+        }
+    }
 
     // Load disk images
     if let Some(disk_a) = disk_a {
         if  disk_a != "$" {
             if let Err(err) = machine.floppy_controller.media_a_mut().load_disk(disk_a) {
-                println!("Error loading file '{}': {}", disk_a, err);
-                return;
+                eprintln!("Error loading file '{}': {}", disk_a, err);
+                return 1;
             }
         }
     }
     if let Some(disk_b) = disk_b {
         if let Err(err) = machine.floppy_controller.media_b_mut().load_disk(disk_b) {
-            println!("Error loading file '{}': {}", disk_b, err);
-            return;
+            eprintln!("Error loading file '{}': {}", disk_b, err);
+            return 1;
+        }
+    }
+    // The file transfer utilities on the embedded CP/M disk
+    if host_services && matches!(disk_a, None | Some("$")) {
+        if let Err(err) = host_services::add_utilities(&mut machine.floppy_controller.media_a_mut().content) {
+            println!("Error adding R.COM and W.COM to the CP/M disk: {}", err);
+        }
+    }
+    if let Some((program, disk)) = run_disk {
+        if let Err(err) = machine.floppy_controller.media_b_mut().load_transient(program, disk) {
+            eprintln!("Error loading the disk for '{}': {}", program, err);
+            return 1;
         }
     }
 
+    let mut counter: u64 = 1;
+    let mut next_signal: u64 = 0;
+
+    // Restore a saved state
+    if let Some(load_state) = load_state {
+        match snapshot::load_snapshot_file(load_state, &mut cpu, &mut machine) {
+            Ok(nmi_countdown) => next_signal = nmi_after(counter, nmi_countdown),
+            Err(err) => {
+                eprintln!("Error loading state '{}': {}", load_state, err);
+                return 1;
+            }
+        }
+    }
+
+    // Wait for the gdb connection
+    let mut gdb = None;
+    if let Some(gdb_port) = gdb_port {
+        let res = gdb_port.parse::<u16>()
+            .map_err(|err| err.to_string())
+            .and_then(|port| GdbStub::new(port).map_err(|err| err.to_string()));
+        let mut gdb_stub = match res {
+            Ok(gdb_stub) => gdb_stub,
+            Err(err) => {
+                eprintln!("Error opening the gdb port '{}': {}", gdb_port, err);
+                return 1;
+            }
+        };
+        println!("Waiting for gdb connection on port {}", gdb_port);
+        if let Err(err) = gdb_stub.wait_connection() {
+            eprintln!("Error waiting for gdb: {}", err);
+            return 1;
+        }
+        gdb = Some(gdb_stub);
+    }
+
     // Start the cpu
-    println!("{}", WELCOME);
+    if !headless {
+        println!("{}", WELCOME);
+    }
     screen.init();
+    screen.set_debug_layout(debug_layout);
 
-    let instructions_per_refresh = if any_trace {256*1024} else {2*1024};
+    let instructions_per_refresh = if any_trace && traces_on_screen {256*1024} else {2*1024};
 
-    let mut counter: u64 = 1;
-    let mut next_signal: u64 = 0;
+    let mut pending_save: Option<String> = None;
+    let mut rewind = Rewind::new(rewind_interval, rewind_depth);
+    let mut debugger = Debugger::new(debug);
+    let mut history = History::new(history_depth);
     let mut done = false;
     while !done {
 
-        cpu.execute_instruction(&mut machine);
+        if debugger.should_stop(cpu.registers().pc(), machine.is_rom_rank()) {
+            trace_log::flush();
+            if screen.is_debug_layout() {
+                screen.set_panes(DebugPanes::new(&mut cpu, &machine, &debugger, &symbols));
+            }
+            screen.update(&mut machine, true);
+            debugger.monitor(&mut cpu, &mut machine, &symbols);
+            screen.init();
+            screen.update(&mut machine, true);
+        }
+        if let Some(gdb) = gdb.as_mut() {
+            if gdb.should_stop(cpu.registers().pc()) && !gdb.serve(&mut cpu, &mut machine) {
+                break;
+            }
+        }
+
+        trace_log::set_instruction(counter);
+        // With an NMI pending, the instruction executed is the first one
+        // of the handler
+        let pc = if machine.nmi_pending {NMI_ADDRESS} else {cpu.registers().pc()};
+        let traced = if trace_cpu && own_cpu_trace {
+            Some(cpu_trace::before(&machine, &symbols, pc))
+        } else {
+            None
+        };
+        history.record(counter, pc, &machine);
+        if let Some(profiler) = profiler.as_mut() {
+            profiler.record(pc, &mut cpu, &machine);
+        }
+        let coverage_pc = if coverage.is_some() && !cpu.is_halted() {
+            Some((pc, machine.is_rom_rank()))
+        } else {
+            None
+        };
+        // On a panic, the state is saved on the crash report before exiting
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            cpu.execute_instruction(&mut machine);
+        }));
+        machine.nmi_pending = false;
+        if let Err(payload) = result {
+            let message = payload.downcast_ref::<&str>().map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            trace_log::flush();
+            save_crash_report(&format!("Panic: {}", message), counter, &history,
+                &mut cpu, &machine, &debugger, &symbols);
+            flush_disks(&mut machine);
+            panic::resume_unwind(payload);
+        }
         counter += 1;
+        if let (Some(coverage), Some((pc, rom_rank))) = (coverage.as_mut(), coverage_pc) {
+            coverage.record(pc, rom_rank, cpu.registers().pc(), &machine);
+        }
+        if let Some(traced) = traced {
+            cpu_trace::after(&mut cpu, &machine, traced);
+        }
+
+        if matches!(&machine.host_services, Some(host_services) if host_services.exit_code().is_some()) {
+            done = true;
+        }
+
+        if machine.watchpoints.take_break() {
+            match gdb.as_mut() {
+                Some(gdb) if gdb.is_connected() => gdb.request_trap(),
+                _ => debugger.request_stop(),
+            }
+        }
+
+        // Snapshots wait until the CPU is out of HALT
+        if snapshot::can_save(&cpu, &machine) {
+            if let Some(filename) = pending_save.take() {
+                let nmi_countdown = nmi_countdown(counter, next_signal);
+                let res = snapshot::save_snapshot_file(&filename, &mut cpu, &mut machine, nmi_countdown);
+                if let Err(err) = res {
+                    screen.message(&mut machine, &err.to_string())
+                }
+            }
+            if rewind.is_capture_due(counter) {
+                let nmi_countdown = nmi_countdown(counter, next_signal);
+                let data = snapshot::save_snapshot(&mut cpu, &mut machine, nmi_countdown, true);
+                rewind.capture(counter, data);
+            }
+        }
+
+        if headless && counter.is_multiple_of(HEADLESS_WINDOW) {
+            machine.keyboard.end_window();
+            if let Some(ms) = timeout.filter(|ms| counter > ms * INSTRUCTIONS_PER_MS) {
+                eprintln!("Timeout after {} ms of emulated time", ms);
+                timed_out = true;
+                done = true;
+            }
+            match script.as_mut() {
+                Some(script) => match script.run(counter, &mut machine) {
+                    Status::Running => {},
+                    Status::Done => {
+                        script_passed = true;
+                        done = true;
+                    },
+                    Status::Failed(message) => {
+                        println!("{}", message);
+                        done = true;
+                    },
+                },
+                None => if machine.keyboard.is_idle() {
+                    if let Some(runner) = runner.as_ref() {
+                        eprintln!("{}", if runner.has_started() {
+                            "The program is waiting for input"
+                        } else {
+                            "The program didn't start"
+                        });
+                    }
+                    done = true;
+                },
+            }
+        }
 
         // IO refresh
-        if counter % instructions_per_refresh == 0 {
+        if counter.is_multiple_of(instructions_per_refresh) {
+            if let Some(gdb) = gdb.as_mut() {
+                gdb.poll();
+            }
             machine.keyboard.consume_input();
+            if let Some(err) = machine.floppy_controller.take_error() {
+                screen.show_error(&err.to_string());
+            }
+            if screen.is_debug_layout()
+                    && counter.is_multiple_of(instructions_per_refresh * DEBUG_PANES_REFRESHES) {
+                screen.set_panes(DebugPanes::new(&mut cpu, &machine, &debugger, &symbols));
+            }
             screen.update(&mut machine, false);
         }
 
@@ -126,7 +794,6 @@ fn main() {
             for command in commands {
                 match command {
                     Command::Quit => {
-                        machine.floppy_controller.media_selected().flush_disk();
                         done = true;
                     },
                     Command::Help => {
@@ -135,6 +802,13 @@ fn main() {
                     Command::ShowStatus => {
                         screen.show_status = !screen.show_status;
                     },
+                    Command::DebugLayout => {
+                        let debug_layout = !screen.is_debug_layout();
+                        screen.set_debug_layout(debug_layout);
+                        if debug_layout {
+                            screen.set_panes(DebugPanes::new(&mut cpu, &machine, &debugger, &symbols));
+                        }
+                    },
                     Command::SelectDiskA => {
                         let path = screen.prompt(&mut machine, "File to load in Drive A");
                         let res = machine.floppy_controller.media_a_mut().load_disk(path.as_str());
@@ -152,10 +826,43 @@ fn main() {
                     Command::SaveMemory => {
                         machine.save_bios()
                     }
+                    Command::SaveState => {
+                        let path = screen.prompt(&mut machine, "File to save the state");
+                        if !path.is_empty() {
+                            pending_save = Some(path);
+                        }
+                    }
+                    Command::LoadState => {
+                        let path = screen.prompt(&mut machine, "File to load the state");
+                        match snapshot::load_snapshot_file(&path, &mut cpu, &mut machine) {
+                            Ok(nmi_countdown) => next_signal = nmi_after(counter, nmi_countdown),
+                            Err(err) => screen.message(&mut machine, &err.to_string()),
+                        }
+                    }
+                    Command::Rewind => {
+                        if let Some((captured, data)) = rewind.rewind(counter) {
+                            match snapshot::load_snapshot(&mut cpu, &mut machine, &data) {
+                                Ok(nmi_countdown) => {
+                                    counter = captured;
+                                    next_signal = nmi_after(counter, nmi_countdown);
+                                },
+                                Err(err) => screen.message(&mut machine, &err.to_string()),
+                            }
+                        }
+                    }
+                    Command::Debug => {
+                        debugger.request_stop();
+                    }
+                    Command::Reload => match loader.as_mut() {
+                        Some(loader) => if let Err(err) = loader.reload() {
+                            screen.message(&mut machine, &err)
+                        },
+                        None => screen.message(&mut machine, "No file to reload, use --load"),
+                    }
                     Command::TraceCPU => {
                         trace_cpu = !trace_cpu;
-                        cpu.set_trace(trace_cpu);
-                        screen.set_in_place(!trace_cpu && !any_trace);
+                        cpu_trace::set_iz80_trace(&mut cpu, trace_cpu && !own_cpu_trace);
+                        screen.set_in_place(!((trace_cpu || any_trace) && traces_on_screen));
                     },
                 }
             }
@@ -170,97 +877,114 @@ fn main() {
         }
         if next_signal != 0 && counter >= next_signal {
             cpu.signal_nmi();
+            machine.nmi_pending = true;
             next_signal = 0;
         }
         if counter < next_signal && cpu.is_halted() {
             cpu.signal_nmi();
+            machine.nmi_pending = true;
             next_signal = 0;
         }
         if cpu.is_halted() {
             screen.update(&mut machine, true);
-            println!("HALT instruction that will never be interrupted");
+            let pc = cpu.registers().pc().wrapping_sub(1);
+            let message = match symbols.describe(pc, machine.is_rom_rank()) {
+                Some(name) => format!("HALT instruction that will never be interrupted at {:04x} {}", pc, name),
+                None => format!("HALT instruction that will never be interrupted at {:04x}", pc),
+            };
+            println!("{}", message);
+            save_crash_report(&message, counter, &history,
+                &mut cpu, &machine, &debugger, &symbols);
             break;
         }
 
-        // Tracing for ROM 81-149c
-        /*
-        if trace_rom && machine.is_rom_rank(){
-            let dma = machine.peek16(0xfc14);
-            match cpu.registers().pc() {
-                0x004b => println!("EP_COLD"),
-                0x0186 => println!("EP_INITDSK"),
-                0x0006 => println!("EP_INITVID"),
-                0x0009 => println!("EP_INITDEV"),
-                0x01d8 => println!("EP_HOME"),
-                0x01b4 => println!("EP_SELDSK {}", cpu.registers().get8(Reg8::C)),
-                0x01cc => println!("EP_SETTRK {}", cpu.registers().get8(Reg8::C)),
-                0x01bb => println!("EP_SETSEC {}", cpu.registers().get8(Reg8::C)),
-                0x01c7 => println!("EP_SETDMA"),
-                0x01ec => println!("EP_READ {:04x}", dma),
-                0x0207 => println!("EP_WRITE {:04x}", dma),
-                0x03e4 => println!("EP_SECTRAN"),
-                0x040f => println!("EP_DISKON"),
-                0x041e => println!("EP_DISKOFF"),
-                0xfa00 => println!("FUNC: OS start"),
-                _ => {}
+        if let Some(rom_trace) = rom_trace.as_mut() {
+            if machine.is_rom_rank() {
+                rom_trace.trace(&mut cpu, &machine);
             }
         }
-        */
-
-        // Tracing for ROM 81-232
-        if trace_rom && machine.is_rom_rank(){
-            let dma = machine.peek16(0xfc14);
-            match cpu.registers().pc() {
-                0x004b => println!("EP_COLD"),
-                0x0195 => println!("EP_INITDSK"),
-                0x0006 => println!("EP_INITVID"),
-                0x0009 => println!("EP_INITDEV"),
-                0x01e7 => println!("EP_HOME"),
-                0x01c3 => println!("EP_SELDSK {}", cpu.registers().get8(Reg8::C)),
-                0x01db => println!("EP_SETTRK {}", cpu.registers().get8(Reg8::C)),
-                0x01ca => println!("EP_SETSEC {}", cpu.registers().get8(Reg8::C)),
-                0x01d6 => println!("EP_SETDMA"),
-                0x01fb => println!("EP_READ {:04x}", dma),
-                0x0216 => println!("EP_WRITE {:04x}", dma),
-                0x0479 => println!("EP_SECTRAN"),
-                0x04a2 => println!("EP_DISKON"),
-                0x04b1 => println!("EP_DISKOFF"),
-                0xfa00 => println!("FUNC: OS start"),
-                _ => {}
-            }
+
+        if let Some(bios_trace) = bios_trace.as_mut() {
+            bios_trace.trace(&mut cpu, &machine);
         }
 
-        if trace_bdos && !machine.is_rom_rank()
-                && cpu.registers().pc() == 0x0005 {
-            let command = cpu.registers().get8(Reg8::C);
-            if command != 0x06 /*C_RAWIO*/ {
-                let args = cpu.registers().get16(Reg16::DE);
-                let name = if command < BDOS_COMMAND_NAMES.len() as u8 {
-                    BDOS_COMMAND_NAMES[command as usize]
-                } else {
-                    "unknown"
-                };
+        if let Some(bdos_trace) = bdos_trace.as_mut() {
+            bdos_trace.trace(&mut cpu, &machine);
+        }
 
-                println!("BDOS command {}: {}({:04x})", command, name, args);
+        if let Some(loader) = loader.as_mut() {
+            if let Some(err) = loader.trace(&mut cpu, &mut machine) {
+                screen.message(&mut machine, &err);
             }
         }
+
+        if let Some(runner) = runner.as_mut() {
+            if runner.trace(&mut cpu, &machine) {
+                done = true;
+            }
+        }
+    }
+    trace_log::flush();
+    flush_disks(&mut machine);
+    if headless && runner.is_none() {
+        let text = screen::text(&machine).join("\n") + "\n";
+        match dump_screen {
+            Some(dump_screen) => if let Err(err) = fs::write(dump_screen, text) {
+                println!("Error saving the screen '{}': {}", dump_screen, err);
+            },
+            None => print!("{}", text),
+        }
+    }
+    if let (Some(profiler), Some(profile)) = (profiler, profile) {
+        match profiler.write_report(profile, &machine, &symbols) {
+            Ok(()) => println!("Profile saved on {}", profile),
+            Err(err) => println!("Error saving the profile '{}': {}", profile, err),
+        }
+    }
+    if let Some(coverage) = coverage {
+        match coverage.write(coverage_out, coverage_format, coverage_listing, &machine) {
+            Ok(()) => println!("Coverage saved on {}", coverage_out),
+            Err(err) => println!("Error saving the coverage '{}': {}", coverage_out, err),
+        }
+    }
+    if let Some(code) = machine.host_services.as_ref().and_then(|h| h.exit_code()) {
+        return code as i32;
+    }
+    if timed_out || (script.is_some() && !script_passed) {
+        return 1;
+    }
+    if matches!(runner, Some(runner) if !runner.has_returned()) {
+        return 1;
+    }
+    0
+}
+
+fn flush_disks(machine: &mut KayproMachine) {
+    for err in machine.floppy_controller.flush_all() {
+        println!("{}", err);
     }
 }
 
-const BDOS_COMMAND_NAMES: [&str; 50] = [
-    // 0
-    "P_TERMCPM", "C_READ", "C_WRITE", "A_READ", "A_WRITE",
-    "L_WRITE", "C_RAWIO", "A_STATIN", "A_STATOUT", "C_WRITESTR",
-    // 10
-    "C_READSTR", "C_STAT", "S_BDOSVER", "DRV_ALLRESET", "DRV_SET",
-    "F_OPEN", "F_CLOSE", "F_SFIRST", "F_SNEXT", "F_DELETE",
-    // 20
-    "F_READ", "F_WRITE", "F_MAKE", "F_RENAME", "DRV_LOGINVEC",
-    "DRV_GET", "F_DMAOFF", "DRV_ALLOCVEC", "DRV_SETRO", "DRV_ROVEC",
-    // 30
-    "F_ATTRIB", "DRV_DPB", "F_USERNUM", "F_READRAND", "F_WRITERAND",
-    "F_SIZE", "F_RANDREC", "DRV_RESET", "*", "",
-    // 40
-    "F_WRITEZ", "", "", "", "",
-    "F_ERRMODE", "", "", "", "",
-    ];
+fn save_crash_report(reason: &str, counter: u64, history: &History, cpu: &mut Cpu,
+        machine: &KayproMachine, debugger: &Debugger, symbols: &Symbols) {
+    match crash_report::write(reason, counter, history, cpu, machine, debugger, symbols) {
+        Ok(()) => println!("Crash report saved on {}", crash_report::CRASH_REPORT_FILE),
+        Err(err) => println!("Error saving the crash report: {}", err),
+    }
+}
+
+fn nmi_countdown(counter: u64, next_signal: u64) -> u64 {
+    if next_signal == 0 {
+        0
+    } else {
+        next_signal.saturating_sub(counter).max(1)
+    }
+}
+
+fn nmi_after(counter: u64, nmi_countdown: u64) -> u64 {
+    if nmi_countdown == 0 {
+        0
+    } else {
+        counter + nmi_countdown
+    }
+}
@@ -1,5 +1,7 @@
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Write, Seek, SeekFrom, Result, Error, ErrorKind};
+use std::io::{Read, Write, Seek, SeekFrom, Result, Error};
+
+use super::snapshot::{SnapshotReader, SnapshotWriter};
 
 /*
 Notes on the DSDD disks as seen by different components:
@@ -32,7 +34,8 @@ File images:
     and so on.
 */
 
-#[derive(PartialEq, Eq)]
+#[derive(PartialEq, Eq, Copy, Clone)]
+#[repr(u8)]
 pub enum MediaFormat {
     Unformatted,
     SsSd,     // Single-sided, single-density
@@ -40,7 +43,13 @@ pub enum MediaFormat {
     DsDd,     // Double-sided, double-density
 }
 
-const SECTOR_SIZE: usize = 512;
+pub const SECTOR_SIZE: usize = 512;
+
+// How the images are stored on the snapshots. The first two values are
+// the ones of the bool stored before, the files saved then still load.
+const STORAGE_CONTENT: u8 = 0;
+const STORAGE_REFERENCE: u8 = 1;
+const STORAGE_REFERENCE_AND_CONTENT: u8 = 2;
 
 fn detect_media_format(len: usize) -> MediaFormat {
     if len == 102400 {
@@ -63,6 +72,10 @@ pub struct Media {
 
     pub write_min: usize,
     pub write_max: usize,
+    // Failure writing to the host file, for the next write command
+    pub write_error: Option<String>,
+    // Pending writes dropped when the image was replaced, to be reported
+    pub lost_writes: Option<(String, Error)>,
 }
 
 impl Media {
@@ -98,9 +111,8 @@ impl Media {
     }
 
 
-    pub fn load_disk(&mut self, filename: &str) -> Result<()>{
-        self.flush_disk();
-
+    /// Opens a disk image, writable if possible
+    pub fn open(filename: &str) -> Result<Media> {
         // Try opening writable, then read only
         let (mut file, readonly) = match OpenOptions::new()
             .read(true)
@@ -133,32 +145,146 @@ impl Media {
             Some(file)
         };
 
+        let mut media = Media::transient(filename, content)?;
+        media.file = file;
+        Ok(media)
+    }
+
+    /// Image without a host file, the changes are not saved
+    pub fn transient(name: &str, content: Vec<u8>) -> Result<Media> {
         let format = detect_media_format(content.len());
         if format == MediaFormat::Unformatted {
-            return Err(Error::new(ErrorKind::Other, format!("Unrecognized disk image format (len {})", content.len())));
+            return Err(Error::other(format!("Unrecognized disk image format (len {})", content.len())));
         }
+        Ok(Media {
+            file: None,
+            name: name.to_owned(),
+            content,
+            format,
+            write_min: usize::MAX,
+            write_max: 0,
+            write_error: None,
+            lost_writes: None,
+        })
+    }
 
-        self.file = file;
-        self.name = filename.to_owned();
-        self.content = content;
-        self.format = format;
+    pub fn load_disk(&mut self, filename: &str) -> Result<()>{
+        // For the changes to be there when opening the same file again. A
+        // failure is reported when replacing the image.
+        let _ = self.flush_disk();
+        let media = Media::open(filename)?;
+        self.replace(media);
+        Ok(())
+    }
 
+    /// Loads an image without a host file, the changes are not saved
+    pub fn load_transient(&mut self, name: &str, content: Vec<u8>) -> Result<()> {
+        let media = Media::transient(name, content)?;
+        self.replace(media);
         Ok(())
     }
 
-    pub fn flush_disk(&mut self) {
+    /// Inserts another image. A host file that can't be written doesn't
+    /// prevent it, the pending writes are dropped.
+    pub fn replace(&mut self, media: Media) {
+        if let Err(err) = self.flush_disk() {
+            let name = self.name.clone();
+            *self = media;
+            self.lost_writes = Some((name, err));
+        } else {
+            *self = media;
+        }
+    }
+
+    /// Writes the modified bytes to the host file. On error they are kept
+    /// as pending, to try again on the next flush, and the error is kept
+    /// to fail the next write command, until a flush succeeds.
+    pub fn flush_disk(&mut self) -> Result<()> {
         if self.write_max < self.write_min {
             // nothing to write
-            return;
+            return Ok(());
         }
 
         if let Some(ref mut file) = self.file {
-            file.seek(SeekFrom::Start(self.write_min as u64)).unwrap();
-            file.write_all(&self.content[self.write_min..=self.write_max]).unwrap();
+            let modified = &self.content[self.write_min..=self.write_max];
+            let res = file.seek(SeekFrom::Start(self.write_min as u64))
+                .and_then(|_| file.write_all(modified));
+            if let Err(err) = res {
+                self.write_error = Some(err.to_string());
+                return Err(err);
+            }
         }
 
         self.write_max = 0;
         self.write_min = usize::MAX;
+        self.write_error = None;
+        Ok(())
+    }
+
+    pub fn take_write_error(&mut self) -> Option<String> {
+        self.write_error.take()
+    }
+
+    pub fn save_state(&mut self, w: &mut SnapshotWriter) {
+        // Persistent images are stored by reference, the rest with content
+        let storage = match (self.file.is_some(), w.images) {
+            (false, _) => STORAGE_CONTENT,
+            (true, false) => STORAGE_REFERENCE,
+            (true, true) => STORAGE_REFERENCE_AND_CONTENT,
+        };
+        w.string(&self.name);
+        w.u8(storage);
+        w.u8(self.format as u8);
+        if storage != STORAGE_REFERENCE {
+            w.bytes(&self.content);
+        }
+    }
+
+    /// Reads an image saved with save_state, opening the host file for
+    /// the persistent ones
+    pub fn read_state(r: &mut SnapshotReader) -> Result<Media> {
+        let name = r.string()?;
+        let storage = r.u8()?;
+        let format = match r.u8()? {
+            1 => MediaFormat::SsSd,
+            2 => MediaFormat::SsDd,
+            3 => MediaFormat::DsDd,
+            _ => MediaFormat::Unformatted,
+        };
+        let invalid = || Error::other(format!("Invalid disk image for '{}' in snapshot", name));
+
+        if storage == STORAGE_REFERENCE {
+            return Media::open(&name);
+        }
+        let content = r.bytes()?;
+        if format == MediaFormat::Unformatted || detect_media_format(content.len()) != format {
+            return Err(invalid());
+        }
+        match storage {
+            STORAGE_CONTENT => Media::transient(&name, content),
+            STORAGE_REFERENCE_AND_CONTENT => {
+                let mut media = Media::open(&name)?;
+                if media.content.len() != content.len() {
+                    return Err(invalid());
+                }
+                media.restore_content(content);
+                Ok(media)
+            }
+            _ => Err(invalid()),
+        }
+    }
+
+    /// Replaces the content, with the bytes that change pending to be
+    /// written to the host file
+    fn restore_content(&mut self, content: Vec<u8>) {
+        let changed = |(a, b): (&u8, &u8)| a != b;
+        let first = self.content.iter().zip(content.iter()).position(changed);
+        let last = self.content.iter().zip(content.iter()).rposition(changed);
+        if let (Some(first), Some(last)) = (first, last) {
+            self.content = content;
+            self.write_min = self.write_min.min(first);
+            self.write_max = self.write_max.max(last);
+        }
     }
 
     pub fn is_valid_track(&self, track: u8) -> bool {
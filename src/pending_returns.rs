@@ -0,0 +1,84 @@
+use iz80::*;
+
+use super::KayproMachine;
+
+/*
+Calls waiting for their return, for the traces of the BIOS and the BDOS
+to log the values returned. A call returns when the execution gets back
+to the return address pushed by the call, with the stack at the same
+level. The callee may switch to its own stack in between. The calls that
+are not seen returning are discarded.
+*/
+
+struct Pending<T> {
+    call: T,
+    return_address: u16,
+    sp: u16,
+}
+
+pub struct PendingReturns<T> {
+    pending: Vec<Pending<T>>,
+}
+
+impl<T> PendingReturns<T> {
+    pub fn new() -> PendingReturns<T> {
+        PendingReturns {
+            pending: Vec::new(),
+        }
+    }
+
+    /// Returns the call that gets back to pc, with the stack at sp
+    pub fn returned(&mut self, pc: u16, sp: u16) -> Option<T> {
+        let index = self.pending.iter()
+            .rposition(|p| pc == p.return_address && sp == p.sp.wrapping_add(2))?;
+        // The calls made after it didn't return
+        self.pending.truncate(index + 1);
+        self.pending.pop().map(|p| p.call)
+    }
+
+    /// Records a call on its entry point, with the return address on the
+    /// top of the stack. None for the calls without a return to wait for.
+    pub fn called(&mut self, call: Option<T>, sp: u16, machine: &KayproMachine) {
+        // The pending calls made with the stack at this level or deeper
+        // are done
+        self.pending.retain(|p| p.sp > sp);
+        if let Some(call) = call {
+            self.pending.push(Pending {
+                call,
+                return_address: machine.peek16(sp),
+                sp,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::floppy_controller::FloppyController;
+    use crate::keyboard_unix::Keyboard;
+
+    #[test]
+    fn returns() {
+        let mut machine = KayproMachine::new(FloppyController::new(false, false),
+            Keyboard::new_headless(&[]), false, false);
+        let mut pending = PendingReturns::new();
+        machine.poke16(0x8000, 0x1234);
+        machine.poke16(0x7ff0, 0x5678);
+        pending.called(Some("outer"), 0x8000, &machine);
+        pending.called(Some("inner"), 0x7ff0, &machine);
+        pending.called(None, 0x7fe0, &machine);
+
+        // Not with another stack level
+        assert_eq!(pending.returned(0x1234, 0x8000), None);
+        // The inner call never returned
+        assert_eq!(pending.returned(0x1234, 0x8002), Some("outer"));
+        assert_eq!(pending.returned(0x5678, 0x7ff2), None);
+
+        // A new call at the same level replaces the pending one
+        pending.called(Some("first"), 0x8000, &machine);
+        pending.called(Some("second"), 0x8000, &machine);
+        assert_eq!(pending.returned(0x1234, 0x8002), Some("second"));
+        assert_eq!(pending.returned(0x1234, 0x8002), None);
+    }
+}
@@ -0,0 +1,164 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{Result, Write};
+
+use iz80::*;
+
+use super::KayproMachine;
+use super::bdos_trace;
+use super::bios_trace;
+use super::disassembler;
+use super::symbols::Symbols;
+
+/*
+Profile of the execution, written to a file on exit. It counts the
+instructions executed on each address, the calls to the BDOS and BIOS
+functions and the sectors read and written on each track.
+
+The hot spots are the addresses executed the most. With symbol files
+loaded, the counts are also added up by routine, the nearest symbol
+before each address.
+*/
+
+const TOP_COUNT: usize = 30;
+
+/// Sectors read and written by track, kept by the floppy controller
+pub struct DiskActivity {
+    // (drive, side, track) -> (reads, writes)
+    tracks: BTreeMap<(u8, u8, u8), (u64, u64)>,
+}
+
+impl DiskActivity {
+    pub fn new() -> DiskActivity {
+        DiskActivity {
+            tracks: BTreeMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, drive: u8, side_2: bool, track: u8, write: bool) {
+        let counts = self.tracks.entry((drive, side_2 as u8, track)).or_default();
+        if write {
+            counts.1 += 1;
+        } else {
+            counts.0 += 1;
+        }
+    }
+}
+
+pub struct Profiler {
+    ram: Vec<u64>,
+    rom: Vec<u64>, // Only the addresses below 0x4000
+    bdos: [u64; 256],
+    bios: HashMap<usize, u64>,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            ram: vec![0; 0x10000],
+            rom: vec![0; 0x4000],
+            bdos: [0; 256],
+            bios: HashMap::new(),
+        }
+    }
+
+    /// Called before each instruction, with the address of the one to
+    /// be executed
+    pub fn record(&mut self, pc: u16, cpu: &mut Cpu, machine: &KayproMachine) {
+        let regs = cpu.registers();
+        if machine.is_rom_rank() && pc < 0x4000 {
+            self.rom[pc as usize] += 1;
+            return;
+        }
+        self.ram[pc as usize] += 1;
+        if pc == 0x0005 {
+            self.bdos[regs.get8(Reg8::C) as usize] += 1;
+        } else if let Some(entry) = bios_trace::entry_at(machine, pc) {
+            *self.bios.entry(entry).or_default() += 1;
+        }
+    }
+
+    pub fn write_report(&self, filename: &str, machine: &KayproMachine, symbols: &Symbols) -> Result<()> {
+        let mut file = File::create(filename)?;
+        let total: u64 = self.ram.iter().chain(self.rom.iter()).sum();
+        writeln!(file, "izkaypro profile")?;
+        writeln!(file, "Instructions executed: {}", total)?;
+
+        // Addresses executed, as (count, rom_rank, address)
+        let mut addresses: Vec<(u64, bool, u16)> = self.rom.iter().enumerate()
+            .map(|(address, count)| (*count, true, address as u16))
+            .chain(self.ram.iter().enumerate()
+                .map(|(address, count)| (*count, false, address as u16)))
+            .filter(|(count, _, _)| *count > 0)
+            .collect();
+        addresses.sort_by_key(|a| Reverse(a.0));
+
+        if symbols.is_loaded() {
+            let mut routines: HashMap<String, u64> = HashMap::new();
+            for (count, rom_rank, address) in addresses.iter() {
+                let name = match symbols.nearest(*address, *rom_rank) {
+                    Some((_, name)) => name.to_owned(),
+                    None => "(no symbol)".to_owned(),
+                };
+                *routines.entry(name).or_default() += count;
+            }
+            let mut routines: Vec<(String, u64)> = routines.into_iter().collect();
+            routines.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+            writeln!(file)?;
+            writeln!(file, "== Top routines ==")?;
+            for (name, count) in routines.iter().take(TOP_COUNT) {
+                writeln!(file, "{:12} {:5.1}% {}", count, percent(*count, total), name)?;
+            }
+        }
+
+        writeln!(file)?;
+        writeln!(file, "== Hot spots ==")?;
+        for (count, rom_rank, address) in addresses.iter().take(TOP_COUNT) {
+            let peek = |a| machine.peek_bank(*rom_rank, a);
+            let symbol = |a| symbols.name(a, *rom_rank);
+            let instruction = disassembler::disassemble(&peek, &symbol, *address);
+            writeln!(file, "{:12} {:5.1}% {} {:04x} {:16} {}", count, percent(*count, total),
+                if *rom_rank {"ROM"} else {"RAM"}, address,
+                symbols.describe(*address, *rom_rank).unwrap_or_default(), instruction.text)?;
+        }
+
+        writeln!(file)?;
+        writeln!(file, "== BDOS calls ==")?;
+        let mut bdos: Vec<(u8, u64)> = self.bdos.iter().enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(command, count)| (command as u8, *count))
+            .collect();
+        bdos.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        for (command, count) in bdos {
+            writeln!(file, "{:12} {:3} {}", count, command, bdos_trace::command_name(command))?;
+        }
+
+        writeln!(file)?;
+        writeln!(file, "== BIOS calls ==")?;
+        let mut bios: Vec<(usize, u64)> = self.bios.iter().map(|(e, c)| (*e, *c)).collect();
+        bios.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        for (entry, count) in bios {
+            writeln!(file, "{:12} {}", count, bios_trace::entry_name(entry))?;
+        }
+
+        writeln!(file)?;
+        writeln!(file, "== Disk activity, sectors read and written ==")?;
+        if let Some(activity) = machine.floppy_controller.activity.as_ref() {
+            for ((drive, side, track), (reads, writes)) in activity.tracks.iter() {
+                writeln!(file, "{}: side {} track {:2} {:8} {:8}",
+                    (b'A' + drive) as char, side + 1, track, reads, writes)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn percent(count: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        count as f64 * 100.0 / total as f64
+    }
+}
@@ -0,0 +1,62 @@
+use std::collections::VecDeque;
+
+/*
+Rewind keeps the last snapshots in memory, taken at regular intervals
+of emulated time. The emulation is not cycle accurate, the time is
+estimated from the number of instructions executed: the Z80 runs at
+2.5MHz and an average instruction takes about 10 T-states.
+
+Disk images stored in host files are saved with their content, the
+writes to those files are undone as well. Otherwise CP/M would go on
+with the allocation of the disk as it was on the RAM restored.
+*/
+
+pub const INSTRUCTIONS_PER_MS: u64 = 250;
+
+// How far back each rewind request goes
+const REWIND_STEP_MS: u64 = 3000;
+
+pub struct Rewind {
+    interval: u64,
+    depth: usize,
+    next_capture: u64,
+    snapshots: VecDeque<(u64, Vec<u8>)>,
+}
+
+impl Rewind {
+    pub fn new(interval_ms: u64, depth: usize) -> Rewind {
+        let interval = interval_ms.max(1) * INSTRUCTIONS_PER_MS;
+        Rewind {
+            interval,
+            depth,
+            next_capture: interval,
+            snapshots: VecDeque::new(),
+        }
+    }
+
+    pub fn is_capture_due(&self, counter: u64) -> bool {
+        self.depth > 0 && counter >= self.next_capture
+    }
+
+    pub fn capture(&mut self, counter: u64, snapshot: Vec<u8>) {
+        if self.snapshots.len() >= self.depth {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back((counter, snapshot));
+        self.next_capture = counter + self.interval;
+    }
+
+    /// Returns the snapshot to go back a few seconds from counter, or the
+    /// oldest available. The newer snapshots are discarded.
+    pub fn rewind(&mut self, counter: u64) -> Option<(u64, Vec<u8>)> {
+        let target = counter.saturating_sub(REWIND_STEP_MS * INSTRUCTIONS_PER_MS);
+        let index = self.snapshots.iter()
+            .rposition(|(captured, _)| *captured <= target)
+            .unwrap_or(0);
+        self.snapshots.truncate(index + 1);
+
+        let (captured, snapshot) = self.snapshots.back()?;
+        self.next_capture = captured + self.interval;
+        Some((*captured, snapshot.clone()))
+    }
+}
@@ -0,0 +1,284 @@
+use iz80::*;
+
+use super::KayproMachine;
+use super::symbols::Symbols;
+
+/*
+Trace of the calls to the ROM entry points. All the Kaypro ROMs start
+with the same jump table, the entry points are the destinations of those
+jumps, except for INITVID and INITDEV that are traced on the jump table
+itself. The ROMs are identified by the CRC32 of the whole image.
+
+The arguments of READ and WRITE are the values set on the previous calls
+to SELDSK, SETTRK and SETSEC. The DMA address is read from the ROM
+variable, as the boot loader updates it without calling SETDMA. WRITE gets on C the type of write
+as in the CP/M BIOS.
+
+HOME and SECTRAN go to the same routine on the 81-478 ROMs. The entry
+point is the one of the jump table executed just before, or both names
+when the routine is called directly.
+*/
+
+const ENTRY_POINT_NAMES: [&str; 14] = [
+    "EP_COLD", "EP_INITDSK", "EP_INITVID", "EP_INITDEV", "EP_HOME",
+    "EP_SELDSK", "EP_SETTRK", "EP_SETSEC", "EP_SETDMA", "EP_READ",
+    "EP_WRITE", "EP_SECTRAN", "EP_DISKON", "EP_DISKOFF"];
+
+// Size of each jump of the table, JP nnnn
+const JUMP_SIZE: u16 = 3;
+
+const EP_SELDSK: usize = 5;
+const EP_SETTRK: usize = 6;
+const EP_SETSEC: usize = 7;
+const EP_SETDMA: usize = 8;
+const EP_READ: usize = 9;
+const EP_WRITE: usize = 10;
+
+pub struct RomTable {
+    pub name: &'static str,
+    crc: u32,
+    entry_points: [u16; 14], // In the order of ENTRY_POINT_NAMES
+    dma_variable: u16, // Where SETDMA stores the address
+    os_start: Option<u16>,
+}
+
+const ROM_TABLES: [RomTable; 14] = [
+    RomTable {
+        name: "81-149b",
+        crc: 0xc008549e,
+        entry_points: [0x004b, 0x0186, 0x0006, 0x0009, 0x01d8, 0x01b4, 0x01cc,
+            0x01bb, 0x01c7, 0x01ec, 0x0207, 0x03e4, 0x040f, 0x041e],
+        dma_variable: 0xfc14,
+        os_start: Some(0xfa00),
+    },
+    RomTable {
+        name: "81-149c",
+        crc: 0x1272aa65,
+        entry_points: [0x004b, 0x0186, 0x0006, 0x0009, 0x01d8, 0x01b4, 0x01cc,
+            0x01bb, 0x01c7, 0x01ec, 0x0207, 0x03e4, 0x040f, 0x041e],
+        dma_variable: 0xfc14,
+        os_start: Some(0xfa00),
+    },
+    RomTable {
+        name: "81-188e",
+        crc: 0x6cbd6aa0,
+        entry_points: [0x004b, 0x029b, 0x0006, 0x0009, 0x0228, 0x025f, 0x0233,
+            0x023e, 0x0293, 0x0249, 0x0254, 0x07bb, 0x07da, 0x02a1],
+        dma_variable: 0xf717,
+        os_start: None,
+    },
+    RomTable {
+        name: "81-232",
+        crc: 0x4918fb91,
+        entry_points: [0x004b, 0x0195, 0x0006, 0x0009, 0x01e7, 0x01c3, 0x01db,
+            0x01ca, 0x01d6, 0x01fb, 0x0216, 0x0479, 0x04a2, 0x04b1],
+        dma_variable: 0xfc14,
+        os_start: Some(0xfa00),
+    },
+    RomTable {
+        name: "81-277",
+        crc: 0xe4e1831f,
+        entry_points: [0x004b, 0x02a1, 0x0006, 0x0009, 0x022e, 0x0265, 0x0239,
+            0x0244, 0x0299, 0x024f, 0x025a, 0x07b3, 0x07d2, 0x02a7],
+        dma_variable: 0xf717,
+        os_start: None,
+    },
+    RomTable {
+        name: "81-292a",
+        crc: 0x241f27a5,
+        entry_points: [0x004b, 0x0244, 0x0006, 0x0009, 0x0296, 0x0272, 0x028a,
+            0x0279, 0x0285, 0x02aa, 0x02c5, 0x0536, 0x0563, 0x0572],
+        dma_variable: 0xfb14,
+        os_start: None,
+    },
+    RomTable {
+        name: "81-302c",
+        crc: 0x3f9bee20,
+        entry_points: [0x004b, 0x029b, 0x0006, 0x0009, 0x0228, 0x025f, 0x0233,
+            0x023e, 0x0293, 0x0249, 0x0254, 0x07ad, 0x07cc, 0x02a1],
+        dma_variable: 0xf717,
+        os_start: None,
+    },
+    RomTable {
+        name: "81-326",
+        crc: 0x7f0c3f68,
+        entry_points: [0x004b, 0x0ba2, 0x0006, 0x0009, 0x0c38, 0x0c43, 0x0c3e,
+            0x0c33, 0x0c83, 0x0c88, 0x0c9a, 0x0fe0, 0x1093, 0x108c],
+        dma_variable: 0xfe5c,
+        os_start: None,
+    },
+    RomTable {
+        name: "81-478a",
+        crc: 0xde618380,
+        entry_points: [0x004e, 0x1e22, 0x0006, 0x0009, 0x022f, 0x0ba6, 0x0204,
+            0x0209, 0x022a, 0x152b, 0x1548, 0x022f, 0x164e, 0x1627],
+        dma_variable: 0xfd86,
+        os_start: None,
+    },
+    RomTable {
+        name: "81-478b",
+        crc: 0x571bac13,
+        entry_points: [0x004e, 0x1e25, 0x0006, 0x0009, 0x0232, 0x0ba9, 0x0207,
+            0x020c, 0x022d, 0x152e, 0x154b, 0x0232, 0x1651, 0x162a],
+        dma_variable: 0xfd86,
+        os_start: None,
+    },
+    RomTable {
+        name: "81-478c",
+        crc: 0xe96fa2be,
+        entry_points: [0x004e, 0x1e2c, 0x0006, 0x0009, 0x0232, 0x0ba9, 0x0207,
+            0x020c, 0x022d, 0x152e, 0x154b, 0x0232, 0x1651, 0x162a],
+        dma_variable: 0xfd86,
+        os_start: None,
+    },
+    RomTable {
+        name: "kplus83",
+        crc: 0x5e9b817d,
+        entry_points: [0x0058, 0x0537, 0x0006, 0x0009, 0x09eb, 0x0a09, 0x09ee,
+            0x09f3, 0x0a01, 0x0c19, 0x0c5f, 0x09f8, 0x0e0e, 0x0e41],
+        dma_variable: 0xfe76,
+        os_start: None,
+    },
+    RomTable {
+        name: "kplus84",
+        crc: 0x4551905a,
+        entry_points: [0x0058, 0x0568, 0x0006, 0x0009, 0x0ea4, 0x0ec2, 0x0ea7,
+            0x0eac, 0x0eba, 0x10d8, 0x111e, 0x0eb1, 0x12d5, 0x130f],
+        dma_variable: 0xfe8e,
+        os_start: None,
+    },
+    RomTable {
+        name: "pro884mx",
+        crc: 0xfebc6f51,
+        entry_points: [0x004b, 0x0335, 0x0006, 0x0009, 0x03d9, 0x03a2, 0x03c9,
+            0x03b4, 0x03c4, 0x03f6, 0x041c, 0x07af, 0x07ec, 0x07fb],
+        dma_variable: 0xfb14,
+        os_start: None,
+    },
+];
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {(crc >> 1) ^ 0xedb8_8320} else {crc >> 1};
+        }
+    }
+    !crc
+}
+
+/// Table of entry points of a known ROM
+pub fn identify(rom: &[u8]) -> Option<&'static RomTable> {
+    let crc = crc32(rom);
+    ROM_TABLES.iter().find(|table| table.crc == crc)
+}
+
+impl RomTable {
+    pub fn add_symbols(&self, symbols: &mut Symbols) {
+        for (address, name) in self.entry_points.iter().zip(ENTRY_POINT_NAMES.iter()) {
+            symbols.add(*address, name, Some(true));
+        }
+    }
+
+    /// The entry points at pc, only the one of the jump executed before
+    /// when several share the address
+    fn entry_points_at(&self, pc: u16, previous_pc: u16) -> Vec<usize> {
+        let entry_points: Vec<usize> = (0..ENTRY_POINT_NAMES.len())
+            .filter(|index| self.entry_points[*index] == pc)
+            .collect();
+        match entry_points.iter().find(|index| **index as u16 * JUMP_SIZE == previous_pc) {
+            Some(entry_point) => vec![*entry_point],
+            None => entry_points,
+        }
+    }
+}
+
+pub struct RomTrace {
+    table: &'static RomTable,
+    previous_pc: u16,
+    drive: u8,
+    track: u16,
+    sector: u8,
+}
+
+impl RomTrace {
+    pub fn new(table: &'static RomTable) -> RomTrace {
+        RomTrace {
+            table,
+            previous_pc: 0,
+            drive: 0,
+            track: 0,
+            sector: 0,
+        }
+    }
+
+    /// Called before each instruction executed with the ROM selected
+    pub fn trace(&mut self, cpu: &mut Cpu, machine: &KayproMachine) {
+        let regs = cpu.registers();
+        let pc = regs.pc();
+        let bc = regs.get16(Reg16::BC);
+        let c = regs.get8(Reg8::C);
+        let previous_pc = self.previous_pc;
+        self.previous_pc = pc;
+        if self.table.os_start == Some(pc) {
+            trace!("rom", "FUNC: OS start");
+            return;
+        }
+        let entry_points = self.table.entry_points_at(pc, previous_pc);
+        let entry_point = match entry_points[..] {
+            [] => return,
+            [entry_point] => entry_point,
+            _ => {
+                let names: Vec<&str> = entry_points.iter().map(|index| ENTRY_POINT_NAMES[*index]).collect();
+                trace!("rom", "{}", names.join("/"));
+                return;
+            }
+        };
+        let name = ENTRY_POINT_NAMES[entry_point];
+        let dma = machine.peek16(self.table.dma_variable);
+        match entry_point {
+            EP_SELDSK => {
+                self.drive = c;
+                trace!("rom", "{} {} ({}:)", name, c, (b'A' + (c & 0x0f)) as char);
+            }
+            EP_SETTRK => {
+                self.track = bc;
+                trace!("rom", "{} {}", name, bc);
+            }
+            EP_SETSEC => {
+                self.sector = c;
+                trace!("rom", "{} {}", name, c);
+            }
+            EP_SETDMA => trace!("rom", "{} {:04x}", name, bc),
+            EP_READ => trace!("rom", "{} {}: track {} sector {} to {:04x}",
+                name, (b'A' + (self.drive & 0x0f)) as char,
+                self.track, self.sector, dma),
+            EP_WRITE => trace!("rom", "{} {}: track {} sector {} from {:04x}, type {}",
+                name, (b'A' + (self.drive & 0x0f)) as char,
+                self.track, self.sector, dma, c),
+            _ => trace!("rom", "{}", name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(name: &str) -> &'static RomTable {
+        ROM_TABLES.iter().find(|table| table.name == name).unwrap()
+    }
+
+    #[test]
+    fn shared_entry_points() {
+        let table = table("81-478a");
+        let home = table.entry_points[4];
+        assert_eq!(home, table.entry_points[11]);
+        assert_eq!(table.entry_points_at(home, 0x000c), vec![4]);
+        assert_eq!(table.entry_points_at(home, 0x0021), vec![11]);
+        assert_eq!(table.entry_points_at(home, 0x1234), vec![4, 11]);
+        assert_eq!(table.entry_points_at(table.entry_points[5], 0x1234), vec![5]);
+        assert!(table.entry_points_at(0x1234, 0x0000).is_empty());
+    }
+}
@@ -0,0 +1,96 @@
+use std::fs;
+use std::io::{stdout, Result, Write};
+
+use iz80::*;
+
+use super::KayproMachine;
+use super::bios_trace;
+use super::cpm_disk;
+use super::cpm_memory::{self, TPA};
+use super::floppy_controller::DISK_BLANK;
+
+/*
+Runner of a CP/M program from the host command line. The program is
+copied to a blank disk on B: and the command line is typed on the CCP
+once CP/M is booted, as in:
+    B:
+    PROG ARGS
+The console output of the program, the calls to the BIOS CONOUT, is
+written to stdout with the CP/M line ends converted to the host ones.
+
+The program is considered started when the execution reaches 0x0100 on
+RAM. It ends when it gets back to the CCP, with a return or a warm boot.
+The CCP is found below the BDOS, using the BDOS vector at 0x0005.
+*/
+
+pub struct Runner {
+    ccp: Option<u16>,
+    returned: bool,
+}
+
+impl Runner {
+    pub fn new() -> Runner {
+        Runner {
+            ccp: None,
+            returned: false,
+        }
+    }
+
+    /// Disk for B: with the program, and the keys to type to run it
+    pub fn prepare(program: &str, args: &[&str]) -> Result<(Vec<u8>, Vec<u8>)> {
+        let name = cpm_disk::cpm_name(program)?;
+        if &name[8..] != b"COM" {
+            return Err(std::io::Error::other("The program must be a .COM file"));
+        }
+        let data = fs::read(program)?;
+        let mut disk = DISK_BLANK.to_vec();
+        cpm_disk::add_file(&mut disk, &name, &data)?;
+
+        let mut command_line = String::from_utf8_lossy(&name[..8]).trim_end().to_string();
+        for arg in args {
+            command_line.push(' ');
+            command_line.push_str(arg);
+        }
+        let keys = format!("B:\r{}\r", command_line).into_bytes();
+        Ok((disk, keys))
+    }
+
+    pub fn has_started(&self) -> bool {
+        self.ccp.is_some()
+    }
+
+    pub fn has_returned(&self) -> bool {
+        self.returned
+    }
+
+    /// Called before each instruction, returns true when the program
+    /// has ended
+    pub fn trace(&mut self, cpu: &mut Cpu, machine: &KayproMachine) -> bool {
+        if self.returned || (machine.is_rom_rank() && cpu.registers().pc() < 0x4000) {
+            return false;
+        }
+        let pc = cpu.registers().pc();
+        let ccp = match self.ccp {
+            Some(ccp) => ccp,
+            None => {
+                if pc == TPA {
+                    self.ccp = Some(cpm_memory::ccp_start(machine));
+                }
+                return false;
+            }
+        };
+
+        if cpm_memory::is_ccp(ccp, pc) {
+            let _ = stdout().flush();
+            self.returned = true;
+            return true;
+        }
+        if bios_trace::entry_at(machine, pc) == Some(bios_trace::CONOUT) {
+            let c = cpu.registers().get8(Reg8::C) & 0x7f;
+            if c != b'\r' {
+                let _ = stdout().write_all(&[c]);
+            }
+        }
+        false
+    }
+}
@@ -1,11 +1,19 @@
 use std::io::{stdout, Write};
+use std::time::{Duration, Instant};
+
 use super::KayproMachine;
+use super::debug_panes::{DebugPanes, BOTTOM_PANE_ROWS};
 
 pub struct Screen {
     in_place: bool,
+    headless: bool,
     last_system_bits: u8,
     pub show_status: bool,
     pub show_help: bool,
+    debug_layout: bool,
+    panes: Option<DebugPanes>,
+    panes_dirty: bool,
+    error: Option<(String, Instant)>,
 }
 
 #[allow(dead_code)]
@@ -25,31 +33,79 @@ const CONTROL_CHARS_81_234: [char; 32] = [
 
 const SHOWN_SYSTEM_BITS: u8 = 0b0110_0011;
 
+// Time the errors are shown on the bottom line
+const ERROR_DURATION: Duration = Duration::from_secs(10);
+
 impl Screen {
-    pub fn new(in_place: bool) -> Screen {
+    /// Headless, the screen is never shown
+    pub fn new(in_place: bool, headless: bool) -> Screen {
         Screen {
-            in_place,
+            in_place: in_place && !headless,
+            headless,
             last_system_bits: 0,
             show_status: false,
             show_help: false,
+            debug_layout: false,
+            panes: None,
+            panes_dirty: false,
+            error: None,
         }
     }
 
     pub fn init(&self) {
         if self.in_place {
-            for _ in 0..27 {
+            for _ in 0..27 + self.extra_rows() {
                 println!();
             }
         }
     }
 
     pub fn set_in_place(&mut self, in_place: bool) {
-        self.in_place = in_place;
+        self.in_place = in_place && !self.headless;
+    }
+
+    pub fn is_debug_layout(&self) -> bool {
+        self.debug_layout
+    }
+
+    pub fn set_debug_layout(&mut self, debug_layout: bool) {
+        if self.in_place && !self.debug_layout && debug_layout {
+            // Make room for the bottom pane
+            for _ in 0..BOTTOM_PANE_ROWS {
+                println!();
+            }
+        } else if self.in_place && self.debug_layout && !debug_layout {
+            // Remove the bottom pane
+            print!("\x1b[{}A\x1b[J", BOTTOM_PANE_ROWS);
+        }
+        self.debug_layout = debug_layout;
+        if !debug_layout {
+            self.panes = None;
+        }
+    }
+
+    pub fn set_panes(&mut self, panes: DebugPanes) {
+        self.panes = Some(panes);
+        self.panes_dirty = true;
+    }
+
+    // Rows below the Kaypro display
+    fn extra_rows(&self) -> usize {
+        if self.debug_layout {BOTTOM_PANE_ROWS} else {0}
+    }
+
+    /// Shows an error on the bottom line for a few seconds
+    pub fn show_error(&mut self, error: &str) {
+        if self.headless {
+            println!("{}", error);
+        }
+        self.error = Some((error.to_owned(), Instant::now()));
+        self.panes_dirty = true;
     }
 
     pub fn message(&mut self, machine: &mut KayproMachine, message:  &str) {
         if self.in_place {
-            print!("\x1b[{}A", 14);
+            print!("\x1b[{}A", 14 + self.extra_rows());
             println!("//==================================================================================\\\\");
             println!("||                                                                                  ||");
             println!("\\\\================================================ Press enter to continue =========//");
@@ -57,8 +113,10 @@ impl Screen {
             print!("|| {} ", message);
             stdout().flush().unwrap();
             machine.keyboard.read_line();
-            print!("\x1b[{}B", 13);
+            print!("\x1b[{}B", 13 + self.extra_rows());
             self.update(machine, true);
+        } else if self.headless {
+            println!("{}", message);
         } else {
             print!("{}: ", message);
         }
@@ -66,36 +124,53 @@ impl Screen {
 
     pub fn prompt(&mut self, machine: &mut KayproMachine, message: &str) -> String {
         if self.in_place {
-            print!("\x1b[{}A", 20);
+            print!("\x1b[{}A", 20 + self.extra_rows());
             println!("//==================================================================================\\\\");
             println!("||                                                                                  ||");
             println!("\\\\==================================================================================//");
             print!("\x1b[{}A", 2);
             print!("|| {}: ", message);
             stdout().flush().unwrap();
-            let line = machine.keyboard.read_line();
-            print!("\x1b[{}B", 19);
+            let line = machine.keyboard.read_line().unwrap_or_default();
+            print!("\x1b[{}B", 19 + self.extra_rows());
             self.update(machine, true);
             line
         } else {
             print!("{}: ", message);
             stdout().flush().unwrap();
-            machine.keyboard.read_line()
+            machine.keyboard.read_line().unwrap_or_default()
         }
     }
 
     pub fn update(&mut self, machine: &mut KayproMachine, force: bool) {
+        if self.headless {
+            machine.vram_dirty = false;
+            return;
+        }
         let relevant_system_bits = machine.system_bits & SHOWN_SYSTEM_BITS;
-        if !force && !machine.vram_dirty && self.last_system_bits == relevant_system_bits {
+        let error_expired = matches!(&self.error, Some((_, shown)) if shown.elapsed() > ERROR_DURATION);
+        if error_expired {
+            self.error = None;
+        }
+        if !force && !machine.vram_dirty && !self.panes_dirty && !error_expired
+                && self.last_system_bits == relevant_system_bits {
             return;
         }
         self.last_system_bits = relevant_system_bits;
+        self.panes_dirty = false;
 
         // Move cursor up with ansi escape sequence
         if self.in_place {
-            print!("\x1b[{}A", 26);
+            print!("\x1b[{}A", 26 + self.extra_rows());
         }
 
+        // The panes are only shown with the display in place
+        let panes = if self.in_place {self.panes.as_ref()} else {None};
+        let right = |row: usize| match panes {
+            Some(panes) => format!(" {}", panes.right[row]),
+            None => String::new(),
+        };
+
         let mut disk_status = "======".to_owned();
         if self.show_status && machine.floppy_controller.motor_on {
             if machine.floppy_controller.drive == 0 {
@@ -111,14 +186,14 @@ impl Screen {
         }
 
         if self.show_status {
-            println!("//====Last key: 0x{:02x}================================================================\\\\", machine.keyboard.peek_key());
+            println!("//====Last key: 0x{:02x}================================================================\\\\{}", machine.keyboard.peek_key(), right(0));
         } else {
-            println!("//==================================================================================\\\\");
+            println!("//==================================================================================\\\\{}", right(0));
         }
         for row in 0..24 {
             print!("|| ");
             for col in 0..80 {
-                let code = machine.vram[(row * 128 + col) as usize];
+                let code = machine.vram[row * 128 + col];
                 let ch = translate_char(code);
                 if code & 0x80 == 0 {
                     print!("{}", ch);
@@ -127,9 +202,20 @@ impl Screen {
                     print!("\x1b[5m{}\x1b[25m", ch);
                 }
             }
-            println!(" ||");
+            println!(" ||{}", right(row + 1));
+        }
+        match &self.error {
+            Some((error, _)) => {
+                let error: String = error.chars().take(76).collect();
+                println!("\\\\== {} {}//{}", error, "=".repeat(78 - error.chars().count()), right(25));
+            }
+            None => println!("\\\\======{}==================================== F1 for help ==== F4 to exit ====//{}", disk_status, right(25)),
+        }
+        if let Some(panes) = panes {
+            for line in panes.bottom.iter() {
+                println!("   {}\x1b[K", line);
+            }
         }
-        println!("\\\\======{}==================================== F1 for help ==== F4 to exit ====//", disk_status);
         //println!("\\\\==================================================================================//");
 
         if self.show_help {
@@ -140,18 +226,23 @@ impl Screen {
 
     fn update_help (&mut self, machine: &KayproMachine) {
         if self.in_place {
-            print!("\x1b[{}A", 21);
+            print!("\x1b[{}A", 22 + self.extra_rows());
         }
         println!("||        +----------------------------------------------------------------+        ||");
         println!("||        |  izkaypro: Kaypro II emulator for console terminals            |        ||");
         println!("||        |----------------------------------------------------------------|        ||");
         println!("||        |  F1: Show/hide help           | Host keys to Kaypro keys:      |        ||");
         println!("||        |  F2: Show/hide disk status    |  Delete to DEL                 |        ||");
+        println!("||        |  F3: Show/hide debug panes    |                                |        ||");
         println!("||        |  F4: Quit the emulator        |  Insert to LINEFEED            |        ||");
         println!("||        |  F5: Select file for drive A: |                                |        ||");
         println!("||        |  F6: Select file for drive B: |                                |        ||");
         println!("||        |  F7: Save BIOS to file        |                                |        ||");
         println!("||        |  F8: Toggle CPU trace         |                                |        ||");
+        println!("||        |  F9: Save machine state       |                                |        ||");
+        println!("||        |  F10: Load machine state      |                                |        ||");
+        println!("||        |  F11: Rewind a few seconds    |  Shift+F10: Reload the --load  |        ||");
+        println!("||        |  F12: Enter the debugger      |    file on the next CCP prompt |        ||");
         println!("||        +----------------------------------------------------------------+        ||");
         println!("||        |  Loaded images:                                                |        ||");
         println!("||        |  A: {:58} |        ||", machine.floppy_controller.media_a().info());
@@ -159,13 +250,23 @@ impl Screen {
         println!("||        +----------------------------------------------------------------+        ||");
 
         if self.in_place {
-            print!("\x1b[{}B", 21-7);
+            print!("\x1b[{}B", 22-7 + self.extra_rows());
         }
     }
 
 
 }
 
+/// Text of the Kaypro display, without the trailing spaces
+pub fn text(machine: &KayproMachine) -> Vec<String> {
+    (0..24).map(|row| {
+        let line: String = (0..80)
+            .map(|col| translate_char(machine.vram[row * 128 + col]))
+            .collect();
+        line.trim_end().to_string()
+    }).collect()
+}
+
 fn translate_char(code: u8) -> char {
     let index = code & 0x7f;
     if index < 0x20 {
@@ -0,0 +1,278 @@
+use std::fs;
+
+use super::KayproMachine;
+use super::rewind::INSTRUCTIONS_PER_MS;
+use super::screen;
+
+/*
+Scripts to automate the emulator on headless mode, for regression tests
+of CP/M programs. One command per line, # starts a comment:
+    wait "text"        waits until the text is on the screen
+    type "text"        types the text, as keys for the Kaypro
+    insert a|b FILE    loads a disk image on the drive
+    expect "text"      fails if the text is not on the screen once the
+                       program is waiting for a key
+    snapshot FILE      writes the screen text to a file once the program
+                       is waiting for a key
+    timeout TIME       max time for the next waits, as 30s or 500ms of
+                       emulated time, 10s by default
+The texts can have the escapes \r, \n, \t, \e, \\, \" and \xNN. The
+screen text has the lines separated by \n, without trailing spaces.
+Examples:
+    wait "A>"
+    type "DIR B:\r"
+    expect "WS      COM"
+*/
+
+const DEFAULT_TIMEOUT_MS: u64 = 10_000;
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Wait(String),
+    Type(Vec<u8>),
+    Insert(u8, String),
+    Expect(String),
+    Snapshot(String),
+    Timeout(u64),
+}
+
+struct Line {
+    number: usize,
+    command: Command,
+}
+
+pub enum Status {
+    Running,
+    Done,
+    Failed(String),
+}
+
+pub struct Script {
+    filename: String,
+    lines: Vec<Line>,
+    next: usize,
+    timeout_ms: u64,
+    started: Option<u64>,
+}
+
+impl Script {
+    pub fn load(filename: &str) -> Result<Script, String> {
+        let content = fs::read_to_string(filename)
+            .map_err(|err| format!("Error loading script '{}': {}", filename, err))?;
+        let mut lines = Vec::new();
+        for (i, text) in content.lines().enumerate() {
+            let number = i + 1;
+            let command = parse_line(text)
+                .map_err(|err| format!("{}:{}: {}", filename, number, err))?;
+            if let Some(command) = command {
+                lines.push(Line {
+                    number,
+                    command,
+                });
+            }
+        }
+        Ok(Script {
+            filename: filename.to_owned(),
+            lines,
+            next: 0,
+            timeout_ms: DEFAULT_TIMEOUT_MS,
+            started: None,
+        })
+    }
+
+    /// Runs the commands that can be completed now. Called periodically
+    /// with the instruction counter.
+    pub fn run(&mut self, counter: u64, machine: &mut KayproMachine) -> Status {
+        while let Some(line) = self.lines.get(self.next) {
+            let started = *self.started.get_or_insert(counter);
+            let timed_out = counter - started > self.timeout_ms * INSTRUCTIONS_PER_MS;
+            let fail = |message: String| Status::Failed(
+                format!("{}:{}: {}", self.filename, line.number, message));
+            let waiting = machine.keyboard.is_waiting_for_key();
+            match &line.command {
+                Command::Wait(text) => {
+                    if !screen_contains(machine, text) {
+                        if timed_out {
+                            return fail(format!("timeout waiting for \"{}\"", escape(text)));
+                        }
+                        return Status::Running;
+                    }
+                }
+                Command::Type(keys) => {
+                    machine.keyboard.type_keys(keys);
+                }
+                Command::Insert(drive, filename) => {
+                    let media = if *drive == 0 {
+                        machine.floppy_controller.media_a_mut()
+                    } else {
+                        machine.floppy_controller.media_b_mut()
+                    };
+                    if let Err(err) = media.load_disk(filename) {
+                        return fail(format!("error loading file '{}': {}", filename, err));
+                    }
+                }
+                Command::Expect(_) | Command::Snapshot(_) if !waiting => {
+                    if timed_out {
+                        return fail("timeout waiting for the program to wait for a key".to_owned());
+                    }
+                    return Status::Running;
+                }
+                Command::Expect(text) => {
+                    if !screen_contains(machine, text) {
+                        return fail(format!("expected \"{}\" not on the screen", escape(text)));
+                    }
+                }
+                Command::Snapshot(filename) => {
+                    let text = screen::text(machine).join("\n") + "\n";
+                    if let Err(err) = fs::write(filename, text) {
+                        return fail(format!("error saving the screen '{}': {}", filename, err));
+                    }
+                }
+                Command::Timeout(timeout_ms) => {
+                    self.timeout_ms = *timeout_ms;
+                }
+            }
+            self.next += 1;
+            self.started = None;
+        }
+        Status::Done
+    }
+}
+
+fn screen_contains(machine: &KayproMachine, text: &str) -> bool {
+    screen::text(machine).join("\n").contains(text)
+}
+
+fn parse_line(line: &str) -> Result<Option<Command>, String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let (name, rest) = match line.split_once(char::is_whitespace) {
+        Some((name, rest)) => (name, rest.trim()),
+        None => (line, ""),
+    };
+    if rest.is_empty() {
+        return Err(format!("missing argument for '{}'", name));
+    }
+    let command = match name {
+        "wait" => Command::Wait(parse_text(rest)?),
+        "type" => Command::Type(parse_text(rest)?.chars().map(|c| c as u8).collect()),
+        "expect" => Command::Expect(parse_text(rest)?),
+        "snapshot" => Command::Snapshot(parse_text(rest)?),
+        "insert" => {
+            let (drive, filename) = rest.split_once(char::is_whitespace)
+                .ok_or("expected a drive and a file")?;
+            let drive = match drive.to_ascii_lowercase().as_str() {
+                "a" | "a:" => 0,
+                "b" | "b:" => 1,
+                _ => return Err(format!("invalid drive '{}'", drive)),
+            };
+            Command::Insert(drive, parse_text(filename.trim())?)
+        }
+        "timeout" => Command::Timeout(parse_time(rest)
+            .ok_or_else(|| format!("invalid timeout '{}'", rest))?),
+        _ => return Err(format!("unknown command '{}'", name)),
+    };
+    Ok(Some(command))
+}
+
+// Texts in quotes with escapes, or as is without quotes
+fn parse_text(text: &str) -> Result<String, String> {
+    let inner = match text.strip_prefix('"') {
+        Some(inner) => inner.strip_suffix('"').ok_or("missing closing quote")?,
+        None => return Ok(text.to_owned()),
+    };
+    let mut result = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        let escaped = match chars.next() {
+            Some('r') => '\r',
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('e') => '\x1b',
+            Some('\\') => '\\',
+            Some('"') => '"',
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                if hex.len() != 2 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(format!("invalid escape '\\x{}'", hex));
+                }
+                u8::from_str_radix(&hex, 16).unwrap() as char
+            }
+            Some(other) => return Err(format!("invalid escape '\\{}'", other)),
+            None => return Err("escape at the end of the text".to_owned()),
+        };
+        result.push(escaped);
+    }
+    Ok(result)
+}
+
+/// Time in milliseconds, from 30s, 500ms or 30
+pub fn parse_time(text: &str) -> Option<u64> {
+    if let Some(ms) = text.strip_suffix("ms") {
+        ms.trim().parse().ok()
+    } else {
+        let seconds = text.strip_suffix('s').unwrap_or(text);
+        seconds.trim().parse::<u64>().ok().map(|s| s * 1000)
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.escape_default().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn texts() {
+        assert_eq!(parse_text("A>"), Ok("A>".to_owned()));
+        assert_eq!(parse_text("\"DIR B:\\r\""), Ok("DIR B:\r".to_owned()));
+        assert_eq!(parse_text(r#""\r\n\t\e\\\"""#), Ok("\r\n\t\x1b\\\"".to_owned()));
+        assert_eq!(parse_text(r#""\x41\x1b\xff""#), Ok("A\x1b\u{ff}".to_owned()));
+        assert_eq!(parse_text(r#""""#), Ok("".to_owned()));
+        assert_eq!(parse_text(r#""a "quoted" text""#), Ok("a \"quoted\" text".to_owned()));
+
+        assert!(parse_text("\"").is_err());
+        assert!(parse_text("\"open").is_err());
+        assert!(parse_text(r#""\q""#).is_err());
+        assert!(parse_text(r#""end\""#).is_err());
+        assert!(parse_text(r#""\x4""#).is_err());
+        assert!(parse_text(r#""\x+1""#).is_err());
+        assert!(parse_text(r#""\xg0""#).is_err());
+    }
+
+    #[test]
+    fn lines() {
+        assert_eq!(parse_line(""), Ok(None));
+        assert_eq!(parse_line("   # comment"), Ok(None));
+        assert_eq!(parse_line("wait \"A>\""), Ok(Some(Command::Wait("A>".to_owned()))));
+        assert_eq!(parse_line("  wait   A>  "), Ok(Some(Command::Wait("A>".to_owned()))));
+        assert_eq!(parse_line("type \"dir\\r\""), Ok(Some(Command::Type(b"dir\r".to_vec()))));
+        assert_eq!(parse_line("expect \"WS      COM\""), Ok(Some(Command::Expect("WS      COM".to_owned()))));
+        assert_eq!(parse_line("snapshot out.txt"), Ok(Some(Command::Snapshot("out.txt".to_owned()))));
+        assert_eq!(parse_line("insert B: disks/data.img"), Ok(Some(Command::Insert(1, "disks/data.img".to_owned()))));
+        assert_eq!(parse_line("insert a \"my disk.img\""), Ok(Some(Command::Insert(0, "my disk.img".to_owned()))));
+        assert_eq!(parse_line("timeout 30s"), Ok(Some(Command::Timeout(30_000))));
+        assert_eq!(parse_line("timeout 500ms"), Ok(Some(Command::Timeout(500))));
+        assert_eq!(parse_line("timeout 2"), Ok(Some(Command::Timeout(2_000))));
+    }
+
+    #[test]
+    fn malformed_lines() {
+        assert_eq!(parse_line("wait"), Err("missing argument for 'wait'".to_owned()));
+        assert_eq!(parse_line("wait   "), Err("missing argument for 'wait'".to_owned()));
+        assert_eq!(parse_line("press \"x\""), Err("unknown command 'press'".to_owned()));
+        assert_eq!(parse_line("wait \"A>"), Err("missing closing quote".to_owned()));
+        assert_eq!(parse_line("insert c disk.img"), Err("invalid drive 'c'".to_owned()));
+        assert_eq!(parse_line("insert a"), Err("expected a drive and a file".to_owned()));
+        assert_eq!(parse_line("timeout soon"), Err("invalid timeout 'soon'".to_owned()));
+        assert_eq!(parse_line("timeout 10 min"), Err("invalid timeout '10 min'".to_owned()));
+    }
+}
@@ -0,0 +1,486 @@
+use std::fs::File;
+use std::io::{Read, Write, Result, Error, ErrorKind};
+
+use iz80::*;
+use super::KayproMachine;
+use super::cpu_trace;
+
+/*
+Snapshot file format:
+
+    8 bytes: magic "IZKAYPRO"
+    2 bytes: version, little endian
+    CPU registers, including the alternate set
+    Pending NMI countdown, in instructions
+    KayproMachine: RAM, VRAM and system bits
+    FloppyController: registers, transfer state and both media
+
+The media in host files are stored by name. The rewind points, kept in
+memory, store also their content, to undo the writes to the files.
+
+All the multibyte values are little endian. Variable length blocks
+are prefixed with their u32 length.
+
+The iz80 crate does not expose the interrupt flip-flops or the
+interrupt mode. They are not stored, the Kaypro only uses the NMI.
+*/
+
+const SNAPSHOT_MAGIC: &[u8; 8] = b"IZKAYPRO";
+const SNAPSHOT_VERSION: u16 = 1;
+
+pub struct SnapshotWriter {
+    data: Vec<u8>,
+    // Store also the content of the images in host files
+    pub images: bool,
+}
+
+impl SnapshotWriter {
+    fn new(images: bool) -> SnapshotWriter {
+        SnapshotWriter {
+            data: Vec::new(),
+            images,
+        }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(if value {1} else {0});
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.u32(value.len() as u32);
+        self.data.extend_from_slice(value);
+    }
+
+    pub fn string(&mut self, value: &str) {
+        self.bytes(value.as_bytes());
+    }
+}
+
+pub struct SnapshotReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> SnapshotReader<'a> {
+    fn new(data: &'a [u8]) -> SnapshotReader<'a> {
+        SnapshotReader {
+            data,
+            position: 0,
+        }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.position + len > self.data.len() {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Snapshot truncated"));
+        }
+        let slice = &self.data[self.position..self.position + len];
+        self.position += len;
+        Ok(slice)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16> {
+        let mut buf = [0; 2];
+        buf.copy_from_slice(self.take(2)?);
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    pub fn bytes(&mut self) -> Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    pub fn bytes_into(&mut self, dest: &mut [u8]) -> Result<()> {
+        let data = self.bytes()?;
+        if data.len() != dest.len() {
+            return Err(Error::other(format!("Snapshot block of {} bytes, expected {}", data.len(), dest.len())));
+        }
+        dest.copy_from_slice(&data);
+        Ok(())
+    }
+
+    pub fn string(&mut self) -> Result<String> {
+        String::from_utf8(self.bytes()?)
+            .map_err(|_| Error::other("Invalid string in snapshot"))
+    }
+}
+
+/*
+The iz80 Cpu only gives access to the main register set. To reach
+the alternate registers we execute EXX and EX AF,AF' on a scratch
+machine that serves just those opcodes.
+*/
+struct ScratchMachine<'a> {
+    origin: u16,
+    code: &'a [u8],
+}
+
+impl<'a> Machine for ScratchMachine<'a> {
+    fn peek(&self, address: u16) -> u8 {
+        let offset = address.wrapping_sub(self.origin) as usize;
+        if offset < self.code.len() {
+            self.code[offset]
+        } else {
+            0x00 // NOP
+        }
+    }
+
+    fn poke(&mut self, _address: u16, _value: u8) {}
+
+    fn port_in(&mut self, _address: u16) -> u8 {
+        0xff
+    }
+
+    fn port_out(&mut self, _address: u16, _value: u8) {}
+}
+
+const OPCODE_EXX: u8 = 0xd9;
+const OPCODE_EX_AF: u8 = 0x08;
+
+fn execute_scratch(cpu: &mut Cpu, code: &[u8]) {
+    let pc = cpu.registers().pc();
+    let mut scratch = ScratchMachine {
+        origin: pc,
+        code,
+    };
+    // The scratch instructions are not part of the emulation, keep them
+    // out of the trace
+    let trace = cpu_trace::is_iz80_trace();
+    cpu.set_trace(false);
+    for _ in code {
+        cpu.execute_instruction(&mut scratch);
+    }
+    cpu.set_trace(trace);
+    cpu.registers().set_pc(pc);
+}
+
+const SAVED_REG16: [Reg16; 7] = [
+    Reg16::AF, Reg16::BC, Reg16::DE, Reg16::HL,
+    Reg16::IX, Reg16::IY, Reg16::SP];
+const SHADOW_REG16: [Reg16; 4] = [
+    Reg16::AF, Reg16::BC, Reg16::DE, Reg16::HL];
+
+/// Returns true if the CPU state can be captured now. The alternate
+/// registers can't be reached while the CPU is halted, and with an NMI
+/// pending the scratch machine would serve it instead.
+pub fn can_save(cpu: &Cpu, machine: &KayproMachine) -> bool {
+    !cpu.is_halted() && !machine.nmi_pending
+}
+
+/// Returns AF', BC', DE' and HL'. See can_save().
+pub fn alternate_registers(cpu: &mut Cpu) -> [u16; 4] {
+    let mut values = [0; 4];
+    execute_scratch(cpu, &[OPCODE_EXX, OPCODE_EX_AF]);
+    for (index, rr) in SHADOW_REG16.iter().enumerate() {
+        values[index] = cpu.registers().get16(*rr);
+    }
+    execute_scratch(cpu, &[OPCODE_EXX, OPCODE_EX_AF]);
+    values
+}
+
+/// Sets AF', BC', DE' and HL'. See can_save().
+pub fn set_alternate_registers(cpu: &mut Cpu, values: &[u16; 4]) {
+    execute_scratch(cpu, &[OPCODE_EXX, OPCODE_EX_AF]);
+    for (index, rr) in SHADOW_REG16.iter().enumerate() {
+        cpu.registers().set16(*rr, values[index]);
+    }
+    execute_scratch(cpu, &[OPCODE_EXX, OPCODE_EX_AF]);
+}
+
+fn save_cpu(cpu: &mut Cpu, w: &mut SnapshotWriter) {
+    let regs = cpu.registers();
+    for rr in SAVED_REG16.iter() {
+        w.u16(regs.get16(*rr));
+    }
+    w.u8(regs.get8(Reg8::I));
+    w.u8(regs.get8(Reg8::R));
+    w.u16(regs.pc());
+
+    for value in alternate_registers(cpu).iter() {
+        w.u16(*value);
+    }
+}
+
+struct CpuState {
+    main: [u16; 7],
+    i: u8,
+    r: u8,
+    pc: u16,
+    shadow: [u16; 4],
+}
+
+fn read_cpu(r: &mut SnapshotReader) -> Result<CpuState> {
+    let mut state = CpuState {
+        main: [0; 7],
+        i: 0,
+        r: 0,
+        pc: 0,
+        shadow: [0; 4],
+    };
+    for value in state.main.iter_mut() {
+        *value = r.u16()?;
+    }
+    state.i = r.u8()?;
+    state.r = r.u8()?;
+    state.pc = r.u16()?;
+    for value in state.shadow.iter_mut() {
+        *value = r.u16()?;
+    }
+    Ok(state)
+}
+
+fn load_cpu(cpu: &mut Cpu, machine: &mut KayproMachine, state: &CpuState) {
+    if cpu.is_halted() || machine.nmi_pending {
+        // Leave the HALT state and drop the pending NMI through an NMI
+        // served by the scratch machine
+        cpu.signal_nmi();
+        execute_scratch(cpu, &[0x00]);
+        machine.nmi_pending = false;
+    }
+
+    set_alternate_registers(cpu, &state.shadow);
+
+    let regs = cpu.registers();
+    for (index, rr) in SAVED_REG16.iter().enumerate() {
+        regs.set16(*rr, state.main[index]);
+    }
+    regs.set8(Reg8::I, state.i);
+    regs.set8(Reg8::R, state.r);
+    regs.set_pc(state.pc);
+}
+
+/// Serializes the full emulation state. It needs can_save(). With
+/// images, the content of the disk images in host files is stored, to
+/// write it back to the files when restored.
+pub fn save_snapshot(cpu: &mut Cpu, machine: &mut KayproMachine, nmi_countdown: u64, images: bool) -> Vec<u8> {
+    let mut w = SnapshotWriter::new(images);
+    w.data.extend_from_slice(SNAPSHOT_MAGIC);
+    w.u16(SNAPSHOT_VERSION);
+
+    save_cpu(cpu, &mut w);
+    w.u64(nmi_countdown);
+    machine.save_state(&mut w);
+    w.data
+}
+
+/// Restores the emulation state. Returns the pending NMI countdown.
+pub fn load_snapshot(cpu: &mut Cpu, machine: &mut KayproMachine, data: &[u8]) -> Result<u64> {
+    let mut r = SnapshotReader::new(data);
+    if r.take(SNAPSHOT_MAGIC.len())? != SNAPSHOT_MAGIC {
+        return Err(Error::other("Not an izkaypro snapshot"));
+    }
+    let version = r.u16()?;
+    if version != SNAPSHOT_VERSION {
+        return Err(Error::other(format!("Unsupported snapshot version {}", version)));
+    }
+
+    let cpu_state = read_cpu(&mut r)?;
+    let nmi_countdown = r.u64()?;
+    machine.load_state(&mut r)?;
+    load_cpu(cpu, machine, &cpu_state);
+    Ok(nmi_countdown)
+}
+
+pub fn save_snapshot_file(filename: &str, cpu: &mut Cpu, machine: &mut KayproMachine, nmi_countdown: u64) -> Result<()> {
+    let data = save_snapshot(cpu, machine, nmi_countdown, false);
+    let mut file = File::create(filename)?;
+    file.write_all(&data)
+}
+
+pub fn load_snapshot_file(filename: &str, cpu: &mut Cpu, machine: &mut KayproMachine) -> Result<u64> {
+    let mut data = Vec::new();
+    File::open(filename)?.read_to_end(&mut data)?;
+    load_snapshot(cpu, machine, &data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use crate::floppy_controller::{FloppyController, DISK_BLANK};
+    use crate::keyboard_unix::Keyboard;
+    use crate::media::Media;
+
+    fn new_machine() -> KayproMachine {
+        KayproMachine::new(FloppyController::new(false, false),
+            Keyboard::new_headless(&[]), false, false)
+    }
+
+    #[test]
+    fn pending_nmi_blocks_the_alternate_registers() {
+        let mut machine = new_machine();
+        let mut cpu = Cpu::new_z80();
+        set_alternate_registers(&mut cpu, &[0x1111, 0x2222, 0x3333, 0x4444]);
+        cpu.registers().set16(Reg16::SP, 0x8000);
+        cpu.registers().set_pc(0x1234);
+
+        cpu.signal_nmi();
+        machine.nmi_pending = true;
+        assert!(!can_save(&cpu, &machine));
+
+        // The NMI is still there for the machine to serve, the handler
+        // of the ROM at 0066h is a RET
+        cpu.execute_instruction(&mut machine);
+        machine.nmi_pending = false;
+        assert_eq!(machine.peek16(0x7ffe), 0x1234);
+        assert_eq!(cpu.registers().pc(), 0x1234);
+        assert_eq!(cpu.registers().get16(Reg16::SP), 0x8000);
+        assert!(can_save(&cpu, &machine));
+        assert_eq!(alternate_registers(&mut cpu), [0x1111, 0x2222, 0x3333, 0x4444]);
+    }
+
+    #[test]
+    fn rewind_points_restore_the_host_images() {
+        let path = env::temp_dir().join(format!("izkaypro-rewind-{}.img", process::id()));
+        let filename = path.to_str().unwrap();
+        fs::write(&path, DISK_BLANK).unwrap();
+        let original = DISK_BLANK[0x1000];
+        let mut machine = new_machine();
+        let mut cpu = Cpu::new_z80();
+        machine.floppy_controller.media_b_mut().load_disk(filename).unwrap();
+
+        let point = save_snapshot(&mut cpu, &mut machine, 0, true);
+        let file = save_snapshot(&mut cpu, &mut machine, 0, false);
+        assert!(point.len() > file.len() + DISK_BLANK.len());
+        let media = machine.floppy_controller.media_b_mut();
+        media.write_byte(0x1000, !original);
+        media.flush_disk().unwrap();
+        assert_eq!(fs::read(&path).unwrap()[0x1000], !original);
+
+        load_snapshot(&mut cpu, &mut machine, &point).unwrap();
+        assert_eq!(machine.floppy_controller.media_b_mut().read_byte(0x1000), original);
+        assert_eq!(fs::read(&path).unwrap(), DISK_BLANK);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut machine = new_machine();
+        let mut cpu = Cpu::new_z80();
+        for (index, rr) in SAVED_REG16.iter().enumerate() {
+            cpu.registers().set16(*rr, 0x1010 * (index as u16 + 1));
+        }
+        cpu.registers().set8(Reg8::I, 0x12);
+        cpu.registers().set_pc(0x4321);
+        set_alternate_registers(&mut cpu, &[0xa1a1, 0xb2b2, 0xc3c3, 0xd4d4]);
+        machine.poke(0x8000, 0x55);
+        machine.vram[10] = b'K';
+        machine.floppy_controller.media_b_mut()
+            .load_transient("test", DISK_BLANK.to_vec()).unwrap();
+        machine.floppy_controller.media_b_mut().write_byte(0x2000, 0x77);
+        // A sector being read from B:
+        machine.floppy_controller.set_drive(1);
+        machine.floppy_controller.put_command(0x80);
+        machine.floppy_controller.get_data();
+
+        let data = save_snapshot(&mut cpu, &mut machine, 1234, false);
+        assert_eq!(&data[..10], b"IZKAYPRO\x01\x00");
+
+        let mut restored = new_machine();
+        let mut restored_cpu = Cpu::new_z80();
+        assert_eq!(load_snapshot(&mut restored_cpu, &mut restored, &data).unwrap(), 1234);
+        assert_eq!(restored_cpu.registers().pc(), 0x4321);
+        assert_eq!(restored_cpu.registers().get16(Reg16::SP), 0x7070);
+        assert_eq!(restored_cpu.registers().get8(Reg8::I), 0x12);
+        assert_eq!(alternate_registers(&mut restored_cpu), [0xa1a1, 0xb2b2, 0xc3c3, 0xd4d4]);
+        assert_eq!(restored.peek(0x8000), 0x55);
+        assert_eq!(restored.vram[10], b'K');
+        assert_eq!(restored.floppy_controller.media_b_mut().read_byte(0x2000), 0x77);
+        assert_eq!(restored.floppy_controller.get_data(), DISK_BLANK[1]);
+        assert_eq!(machine.floppy_controller.get_data(), DISK_BLANK[1]);
+
+        // Saved again, it is the same
+        let again = save_snapshot(&mut restored_cpu, &mut restored, 1234, false);
+        let original = save_snapshot(&mut cpu, &mut machine, 1234, false);
+        let diff = again.iter().zip(original.iter()).position(|(a, b)| a != b);
+        assert!(again == original, "first difference at {:?}", diff);
+    }
+
+    #[test]
+    fn invalid_snapshots() {
+        let mut cpu = Cpu::new_z80();
+        let mut machine = new_machine();
+        let data = save_snapshot(&mut cpu, &mut machine, 0, false);
+        for len in [0, 8, 10, 100, 70000, data.len() - 1] {
+            assert!(load_snapshot(&mut cpu, &mut machine, &data[..len]).is_err(), "len {}", len);
+        }
+        let mut version = data.clone();
+        version[8] = 2;
+        assert!(load_snapshot(&mut cpu, &mut machine, &version).is_err());
+        assert!(load_snapshot(&mut cpu, &mut machine, &data).is_ok());
+    }
+
+    fn fdc_state(read_index: u32, read_last: u32, data_buffer: &[u8]) -> Vec<u8> {
+        let mut w = SnapshotWriter::new(false);
+        w.bool(true);
+        w.u8(0);
+        w.bool(false);
+        w.u8(0);
+        w.u8(0);
+        w.bool(false);
+        w.u8(0);
+        w.u8(0);
+        w.u32(read_index);
+        w.u32(read_last);
+        w.bytes(data_buffer);
+        w.bool(false);
+        for _ in 0..2 {
+            Media::transient("test", DISK_BLANK.to_vec()).unwrap().save_state(&mut w);
+        }
+        w.data
+    }
+
+    #[test]
+    fn invalid_floppy_controller_state() {
+        let mut fdc = FloppyController::new(false, false);
+        let valid = fdc_state(0x1000, 0x1200, &[]);
+        assert!(fdc.load_state(&mut SnapshotReader::new(&valid)).is_ok());
+
+        let len = DISK_BLANK.len() as u32;
+        for data in [
+            fdc_state(0x1200, 0x1000, &[]),
+            fdc_state(0x1000, 0x2000, &[]),
+            fdc_state(len, len + 0x200, &[]),
+            fdc_state(0, 0, &[0; 1000]),
+        ].iter() {
+            assert!(fdc.load_state(&mut SnapshotReader::new(data)).is_err());
+        }
+    }
+}
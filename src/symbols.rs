@@ -0,0 +1,177 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Result;
+
+use super::address;
+
+/*
+Symbols to show names instead of addresses on the traces and the
+debugger. They are loaded from the symbol files or listings generated by
+the assemblers. Each line is parsed with the first rule that applies:
+    name[:] equ value    as in the z80asm and pasmo .sym files
+    name = value         as in the z88dk .sym files
+    ... addr ... name:   as in the listings, addr has 4 hex digits
+    addr name            as in many other symbol files
+Values can be hex with the $, 0x, # prefixes or h suffix, or decimal.
+The text after ';' is ignored.
+
+The file name can be prefixed with "rom:" or "ram:" to apply the
+symbols only when that bank is selected.
+*/
+
+// Symbols are shown as name+offset up to this distance
+const MAX_OFFSET: u16 = 0x100;
+
+struct Symbol {
+    name: String,
+    rom_rank: Option<bool>, // None for both banks
+}
+
+pub struct Symbols {
+    symbols: BTreeMap<u16, Vec<Symbol>>,
+    loaded: bool,
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols {
+            symbols: BTreeMap::new(),
+            loaded: false,
+        }
+    }
+
+    /// True if any symbol file has been loaded
+    pub fn is_loaded(&self) -> bool {
+        self.loaded
+    }
+
+    pub fn add(&mut self, address: u16, name: &str, rom_rank: Option<bool>) {
+        self.symbols.entry(address).or_default().push(Symbol {
+            name: name.to_owned(),
+            rom_rank,
+        });
+    }
+
+    /// Adds the symbols of a program built with the asm subcommand
+    pub fn add_assembled(&mut self, symbols: &BTreeMap<String, u16>) {
+        for (name, address) in symbols.iter() {
+            self.add(*address, name, None);
+        }
+        self.loaded = true;
+    }
+
+    /// Loads a symbol file, optionally prefixed with the bank. Returns the
+    /// number of symbols loaded.
+    pub fn load(&mut self, spec: &str) -> Result<usize> {
+        let (rom_rank, filename) = address::split_bank(spec);
+
+        let content = fs::read(filename)?;
+        let content = String::from_utf8_lossy(&content);
+        let mut count = 0;
+        for line in content.lines() {
+            if let Some((address, name)) = parse_line(line) {
+                self.add(address, &name, rom_rank);
+                count += 1;
+            }
+        }
+        self.loaded = true;
+        Ok(count)
+    }
+
+    /// Name of the symbol at address
+    pub fn name(&self, address: u16, rom_rank: bool) -> Option<String> {
+        self.symbols.get(&address)?.iter()
+            .find(|symbol| symbol.rom_rank.is_none_or(|bank| bank == rom_rank))
+            .map(|symbol| symbol.name.clone())
+    }
+
+    /// Name of the nearest symbol before address, as name+offset
+    pub fn describe(&self, address: u16, rom_rank: bool) -> Option<String> {
+        let (symbol_address, name) = self.nearest(address, rom_rank)?;
+        let offset = address - symbol_address;
+        Some(if offset == 0 {
+            name.to_owned()
+        } else {
+            format!("{}+{:x}", name, offset)
+        })
+    }
+
+    /// Address and name of the nearest symbol before address
+    pub fn nearest(&self, address: u16, rom_rank: bool) -> Option<(u16, &str)> {
+        for (symbol_address, symbols) in self.symbols.range(..=address).rev() {
+            if address - symbol_address > MAX_OFFSET {
+                return None;
+            }
+            let symbol = symbols.iter()
+                .find(|symbol| symbol.rom_rank.is_none_or(|bank| bank == rom_rank));
+            if let Some(symbol) = symbol {
+                return Some((*symbol_address, &symbol.name));
+            }
+        }
+        None
+    }
+
+    /// Address and bank of the symbol with that name
+    pub fn address(&self, name: &str) -> Option<(u16, Option<bool>)> {
+        for (address, symbols) in self.symbols.iter() {
+            for symbol in symbols.iter() {
+                if symbol.name.eq_ignore_ascii_case(name) {
+                    return Some((*address, symbol.rom_rank));
+                }
+            }
+        }
+        None
+    }
+}
+
+fn parse_line(line: &str) -> Option<(u16, String)> {
+    let line = line.split(';').next().unwrap_or("");
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+
+    // name equ value, name = value
+    let position = tokens.iter().position(|token|
+        token.eq_ignore_ascii_case("equ") || *token == "=");
+    if let Some(position) = position {
+        let name = tokens.get(position.wrapping_sub(1))?.trim_end_matches(':');
+        let value = parse_value(tokens.get(position + 1)?)?;
+        return if is_identifier(name) {Some((value, name.to_owned()))} else {None};
+    }
+
+    // ... addr ... name:
+    let position = tokens.iter().position(|token|
+        token.ends_with(':') && is_identifier(token.trim_end_matches(':')));
+    if let Some(position) = position {
+        let name = tokens[position].trim_end_matches(':');
+        let address = tokens[..position].iter().rev().find_map(|token| address::parse_listing_address(token))?;
+        return Some((address, name.to_owned()));
+    }
+
+    // addr name
+    if tokens.len() == 2 && is_identifier(tokens[1]) {
+        return Some((address::parse_listing_address(tokens[0])?, tokens[1].to_owned()));
+    }
+    None
+}
+
+fn parse_value(token: &str) -> Option<u16> {
+    let token = token.trim_end_matches(',');
+    let hex = token.strip_prefix('$')
+        .or_else(|| token.strip_prefix("0x"))
+        .or_else(|| token.strip_prefix('#'))
+        .or_else(|| token.strip_suffix(['h', 'H']));
+    let value = match hex {
+        Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+        None => token.parse::<u32>().ok()?,
+    };
+    // Some assemblers export 32 bit values
+    if value <= 0xffff {Some(value as u16)} else {None}
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(first) if first.is_ascii_alphabetic() || "_.@".contains(first) =>
+            chars.all(|c| c.is_ascii_alphanumeric() || "_.@$?".contains(c)),
+        _ => false,
+    }
+}
@@ -0,0 +1,114 @@
+use std::fs::File;
+use std::io::{BufWriter, Result, Write};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::rewind::INSTRUCTIONS_PER_MS;
+
+/*
+Destination of all the traces. By default they are printed on stdout as
+they always were, mixed with the Kaypro screen. With --trace-file they go
+to a file, with the count of instructions executed on each record, and
+the screen stays in place.
+
+The jsonl format writes one JSON object per record:
+    {"instruction":1234,"ms":4,"source":"fdc","text":"FDC: Restore"}
+where ms is the emulated time, estimated as in the rewind.
+*/
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    Text,
+    Jsonl,
+}
+
+impl TraceFormat {
+    pub fn parse(text: &str) -> Option<TraceFormat> {
+        match text {
+            "text" => Some(TraceFormat::Text),
+            "jsonl" => Some(TraceFormat::Jsonl),
+            _ => None,
+        }
+    }
+}
+
+struct TraceSink {
+    format: TraceFormat,
+    file: Option<BufWriter<File>>,
+}
+
+static SINK: Mutex<TraceSink> = Mutex::new(TraceSink {
+    format: TraceFormat::Text,
+    file: None,
+});
+
+// Instructions executed, updated by the main loop
+static INSTRUCTION: AtomicU64 = AtomicU64::new(0);
+
+/// Writes the traces with the format on the file, or on stdout if None.
+pub fn init(format: TraceFormat, filename: Option<&str>) -> Result<()> {
+    let file = match filename {
+        Some(filename) => Some(BufWriter::new(File::create(filename)?)),
+        None => None,
+    };
+    let mut sink = SINK.lock().unwrap();
+    sink.format = format;
+    sink.file = file;
+    Ok(())
+}
+
+/// True if the traces go to a file instead of the screen
+pub fn is_file() -> bool {
+    SINK.lock().unwrap().file.is_some()
+}
+
+pub fn set_instruction(counter: u64) {
+    INSTRUCTION.store(counter, Ordering::Relaxed);
+}
+
+pub fn flush() {
+    if let Some(file) = SINK.lock().unwrap().file.as_mut() {
+        let _ = file.flush();
+    }
+}
+
+/// Writes a trace record, use the trace! macro instead.
+pub fn write(source: &str, text: &str) {
+    let instruction = INSTRUCTION.load(Ordering::Relaxed);
+    let mut sink = SINK.lock().unwrap();
+    let line = match sink.format {
+        TraceFormat::Text if sink.file.is_some() => format!("{:10} {}", instruction, text),
+        TraceFormat::Text => text.to_owned(),
+        TraceFormat::Jsonl => format!(
+            "{{\"instruction\":{},\"ms\":{},\"source\":\"{}\",\"text\":\"{}\"}}",
+            instruction, instruction / INSTRUCTIONS_PER_MS, source, json_escape(text)),
+    };
+    match sink.file.as_mut() {
+        Some(file) => {
+            let _ = writeln!(file, "{}", line);
+        }
+        None => println!("{}", line),
+    }
+}
+
+fn json_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped += "\\\"",
+            '\\' => escaped += "\\\\",
+            '\n' => escaped += "\\n",
+            '\r' => escaped += "\\r",
+            c if (c as u32) < 0x20 => escaped += &format!("\\u{:04x}", c as u32),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Writes a trace record from a source, with the syntax of format!
+macro_rules! trace {
+    ($source:expr, $($arg:tt)*) => {
+        $crate::trace_log::write($source, &format!($($arg)*))
+    };
+}
@@ -0,0 +1,165 @@
+use super::address;
+
+/*
+Watchpoints on memory writes and port accesses.
+
+Syntax: <kind> [rom:|ram:]<start>[-<end>] [=<value>] [changed] [log]
+    kind: "write" for memory writes, "in", "out" or "io" for ports
+    rom: or ram: limits the watch to accesses with that bank selected
+    =value limits the watch to accesses with that value
+    changed limits the watch to writes that modify the memory content
+    log prints a message instead of stopping on the debugger
+Numbers are hexadecimal. Examples:
+    write ram:fc00-fcff
+    write rom:3000-3fff changed
+    out 1c =80 log
+*/
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum WatchKind {
+    Write,
+    PortIn,
+    PortOut,
+    PortInOut,
+}
+
+struct Watchpoint {
+    kind: WatchKind,
+    start: u16,
+    end: u16,
+    rom_rank: Option<bool>,
+    value: Option<u8>,
+    changed: bool,
+    log: bool,
+    spec: String,
+}
+
+impl Watchpoint {
+    fn parse(spec: &str) -> Result<Watchpoint, String> {
+        let params: Vec<&str> = spec.split_whitespace().collect();
+        if params.len() < 2 {
+            return Err(format!("Invalid watchpoint '{}'", spec));
+        }
+
+        let kind = match params[0] {
+            "write" => WatchKind::Write,
+            "in" => WatchKind::PortIn,
+            "out" => WatchKind::PortOut,
+            "io" => WatchKind::PortInOut,
+            _ => return Err(format!("Unknown watchpoint kind '{}'", params[0])),
+        };
+
+        let (rom_rank, start, end) = address::parse_range(params[1])?;
+
+        let mut watchpoint = Watchpoint {
+            kind,
+            start,
+            end,
+            rom_rank,
+            value: None,
+            changed: false,
+            log: false,
+            spec: params.join(" "),
+        };
+        for param in params.iter().skip(2) {
+            if let Some(value) = param.strip_prefix('=') {
+                let value = address::parse_hex(value)?;
+                if value > 0xff {
+                    return Err(format!("Invalid watchpoint value '{}'", param));
+                }
+                watchpoint.value = Some(value as u8);
+            } else if *param == "changed" {
+                watchpoint.changed = true;
+            } else if *param == "log" {
+                watchpoint.log = true;
+            } else {
+                return Err(format!("Unknown watchpoint condition '{}'", param));
+            }
+        }
+        Ok(watchpoint)
+    }
+
+    fn matches(&self, kind: WatchKind, address: u16, value: u8, rom_rank: bool) -> bool {
+        let kind_matches = self.kind == kind
+            || (self.kind == WatchKind::PortInOut && kind != WatchKind::Write);
+        kind_matches
+            && (self.start..=self.end).contains(&address)
+            && self.rom_rank.is_none_or(|bank| bank == rom_rank)
+            && self.value.is_none_or(|v| v == value)
+    }
+}
+
+pub struct Watchpoints {
+    watchpoints: Vec<Watchpoint>,
+    break_requested: bool,
+}
+
+impl Watchpoints {
+    pub fn new() -> Watchpoints {
+        Watchpoints {
+            watchpoints: Vec::new(),
+            break_requested: false,
+        }
+    }
+
+    pub fn add(&mut self, spec: &str) -> Result<(), String> {
+        let watchpoint = Watchpoint::parse(spec)?;
+        self.watchpoints.push(watchpoint);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, index: usize) -> bool {
+        if index < self.watchpoints.len() {
+            self.watchpoints.remove(index);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn list(&self) -> Vec<String> {
+        self.watchpoints.iter().map(|w| w.spec.clone()).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.watchpoints.is_empty()
+    }
+
+    /// Returns true once after a watchpoint requests to stop on the debugger
+    pub fn take_break(&mut self) -> bool {
+        let requested = self.break_requested;
+        self.break_requested = false;
+        requested
+    }
+
+    pub fn check_write(&mut self, address: u16, previous: u8, value: u8, rom_rank: bool) {
+        self.check(WatchKind::Write, address, value, rom_rank, previous != value);
+    }
+
+    pub fn check_port_in(&mut self, port: u8, value: u8, rom_rank: bool) {
+        self.check(WatchKind::PortIn, port as u16, value, rom_rank, true);
+    }
+
+    pub fn check_port_out(&mut self, port: u8, value: u8, rom_rank: bool) {
+        self.check(WatchKind::PortOut, port as u16, value, rom_rank, true);
+    }
+
+    fn check(&mut self, kind: WatchKind, address: u16, value: u8, rom_rank: bool, changed: bool) {
+        for (index, watchpoint) in self.watchpoints.iter().enumerate() {
+            if watchpoint.matches(kind, address, value, rom_rank)
+                    && (changed || !watchpoint.changed) {
+                let access = match kind {
+                    WatchKind::Write => "write",
+                    WatchKind::PortIn => "in",
+                    _ => "out",
+                };
+                trace!("watch", "Watchpoint {} '{}': {} {:04x} = {:02x} on {}",
+                    index, watchpoint.spec, access, address, value,
+                    if rom_rank {"ROM"} else {"RAM"});
+                if !watchpoint.log {
+                    self.break_requested = true;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+// Boots each image of the disks folder on headless mode, runs DIR and
+// compares the text of the screen with the file of the same name in
+// tests/golden. The bootable disks are booted as A:, the data disks go
+// on B: with the embedded CP/M on A:. To write the golden files again
+// after an intended change of the screens, run:
+//     UPDATE_GOLDEN=1 cargo test --test golden_screens
+
+enum Drive {
+    A,
+    B,
+}
+
+fn check_screen(image: &str, rom: Option<&str>, drive: Drive) {
+    let path = format!("disks/{}.img", image);
+    let mut args = vec!["--headless", "--input", "-"];
+    if let Some(rom) = rom {
+        args.extend_from_slice(&["--rom", rom]);
+    }
+    let input = match drive {
+        Drive::A => {
+            args.push(&path);
+            "DIR\n"
+        }
+        Drive::B => {
+            args.extend_from_slice(&["$", &path]);
+            "DIR B:\n"
+        }
+    };
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_izkaypro"))
+        .args(&args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Error running izkaypro");
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{}: the emulator failed: {}", image,
+        String::from_utf8_lossy(&output.stderr));
+    let screen = String::from_utf8_lossy(&output.stdout);
+
+    let golden = format!("tests/golden/{}.txt", image);
+    if env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::write(&golden, screen.as_bytes()).unwrap();
+        return;
+    }
+    let expected = fs::read_to_string(&golden)
+        .unwrap_or_else(|err| panic!("Error reading '{}': {}", golden, err));
+    assert!(screen == expected, "{}: the screen doesn't match {}\n{}", image, golden, screen);
+}
+
+macro_rules! golden_screen {
+    ($name:ident, $image:expr, $drive:ident) => {
+        golden_screen!($name, $image, None, $drive);
+    };
+    ($name:ident, $image:expr, $rom:expr, $drive:ident) => {
+        #[test]
+        fn $name() {
+            check_screen($image, $rom, Drive::$drive);
+        }
+    };
+}
+
+// Bootable CP/M disks, the Kaypro 4 ones are double sided
+golden_screen!(blank, "blank", A);
+golden_screen!(cpm22_gm272, "cpm22-gm272", A);
+golden_screen!(cpm22_kaypro4, "cpm22-kaypro4", A);
+golden_screen!(cpm22_kaypro4_blank, "cpm22-kaypro4-blank", A);
+golden_screen!(cpm22_rom149, "cpm22-rom149", Some("roms/81-149c.rom"), A);
+golden_screen!(cpm22_rom232, "cpm22-rom232", A);
+golden_screen!(cpm22_sp272, "cpm22-sp272", A);
+golden_screen!(cpmish, "cpmish", A);
+golden_screen!(games, "Games", A);
+golden_screen!(perfect_writer_lessons, "PerfectWriterLessons", A);
+golden_screen!(perfect_writer_utils, "PerfectWriterUtils", A);
+golden_screen!(turbo_pascal_301a, "TurboPascal_301A", A);
+golden_screen!(zork, "Zork", A);
+
+// Master disks, they boot to a message asking to make a copy
+golden_screen!(perfect_calc_10, "PerfectCalc10", B);
+golden_screen!(perfect_calc_11, "PerfectCalc11", B);
+golden_screen!(perfect_writer_and_speller, "PerfectWriterAndSpeller", B);
+golden_screen!(the_word_plus, "TheWordPlus", B);
+golden_screen!(word_star_33, "WordStar33", B);
+
+// Data disks, they don't boot
+golden_screen!(calc_star, "CalcStar", B);
+golden_screen!(diagnostics, "Diagnostics", B);
+golden_screen!(kug18_diagnostics, "KUG18Diagnostics", B);
+golden_screen!(kug34_games, "KUG34Games", B);
+golden_screen!(mbasic, "MBasic", B);
+golden_screen!(perfect_calc_lessons, "PerfectCalcLessons", B);
+golden_screen!(perfect_filer, "PerfectFiler", B);
+golden_screen!(profit_plan_102, "ProfitPlan102", B);
+golden_screen!(profit_plan_404, "ProfitPlan404", B);
+golden_screen!(select_wp_master, "SelectWPMaster", B);
+golden_screen!(select_wp_teach_and_install, "SelectWPTeachAndInstall", B);
+golden_screen!(text_editor_sp, "TextEditorSP", B);
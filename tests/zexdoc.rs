@@ -0,0 +1,21 @@
+use std::process::Command;
+
+// ZEXDOC, the Z80 instruction exerciser by Frank D. Cringle, run on the
+// embedded CP/M with the run subcommand. It checks the CPU, and the
+// floppy controller and CP/M loading it from a disk. It takes several
+// minutes on a release build, run it with:
+//     cargo test --release -- --ignored zexdoc
+#[test]
+#[ignore]
+fn zexdoc() {
+    let output = Command::new(env!("CARGO_BIN_EXE_izkaypro"))
+        .args(["run", "tests/res/zexdoc.com"])
+        .output()
+        .expect("Error running izkaypro");
+    let text = String::from_utf8_lossy(&output.stdout);
+    print!("{}", text);
+    assert!(output.status.success(), "The program didn't end: {}",
+        String::from_utf8_lossy(&output.stderr));
+    assert!(!text.contains("ERROR"), "Some instructions failed");
+    assert!(text.contains("Tests complete"), "The exerciser didn't complete");
+}
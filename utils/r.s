@@ -0,0 +1,111 @@
+; R.COM: copies a file from the host to CP/M, with the host services
+; of izkaypro (--host-services)
+; Usage: R HOSTFILE [CPMFILE]
+BDOS:          EQU 0005h
+FCB:           EQU 005ch
+FCB2:          EQU 006ch
+TAIL:          EQU 0080h
+BUFFER:        EQU 0080h
+; BDOS calls:
+CWRITESTR:     EQU 09h
+FCLOSE:        EQU 10h
+FDELETE:       EQU 13h
+FWRITE:        EQU 15h
+FMAKE:         EQU 16h
+; Host services:
+SERVICES:      EQU 1ah
+DATA:          EQU 1bh
+OPENREAD:      EQU 05h
+READ:          EQU 06h
+
+org	0100h
+	in a, (SERVICES)
+	cp 'K'
+	ld de, nohost
+	jp nz, error
+	; Host file name, the first word of the command line
+	ld a, OPENREAD
+	out (SERVICES), a
+	ld hl, TAIL+1
+	ld a, (TAIL)
+	ld b, a
+skip:
+	ld a, b
+	or a
+	jp z, usage
+	ld a, (hl)
+	cp ' '
+	jr nz, name
+	inc hl
+	dec b
+	jr skip
+name:
+	out (DATA), a
+	inc hl
+	dec b
+	jr z, named
+	ld a, (hl)
+	cp ' '
+	jr nz, name
+named:
+	xor a
+	out (DATA), a
+	in a, (DATA)
+	or a
+	ld de, notfound
+	jp nz, error
+	; CP/M file, the second name if given
+	ld a, (FCB2+1)
+	cp ' '
+	jr z, create
+	ld hl, FCB2
+	ld de, FCB
+	ld bc, 16
+	ldir
+create:
+	ld de, FCB
+	ld c, FDELETE
+	call BDOS
+	ld de, FCB
+	ld c, FMAKE
+	call BDOS
+	inc a
+	ld de, dirfull
+	jp z, error
+	xor a
+	ld (FCB+32), a
+loop:
+	ld a, READ
+	out (SERVICES), a
+	in a, (DATA)
+	or a
+	jr z, close
+	ld hl, BUFFER
+	ld bc, 8000h + DATA
+	inir
+	ld de, FCB
+	ld c, FWRITE
+	call BDOS
+	or a
+	ld de, diskfull
+	jp nz, error
+	jr loop
+close:
+	ld de, FCB
+	ld c, FCLOSE
+	jp BDOS
+usage:
+	ld de, usagemsg
+error:
+	ld c, CWRITESTR
+	jp BDOS
+nohost:
+	db "Host services not enabled$"
+notfound:
+	db "Host file not found$"
+dirfull:
+	db "Directory full$"
+diskfull:
+	db "Disk full$"
+usagemsg:
+	db "Usage: R HOSTFILE [CPMFILE]$"
@@ -0,0 +1,108 @@
+; W.COM: copies a file from CP/M to the host, with the host services
+; of izkaypro (--host-services)
+; Usage: W HOSTFILE [CPMFILE]
+BDOS:          EQU 0005h
+FCB:           EQU 005ch
+FCB2:          EQU 006ch
+TAIL:          EQU 0080h
+BUFFER:        EQU 0080h
+; BDOS calls:
+CWRITESTR:     EQU 09h
+FOPEN:         EQU 0fh
+FREAD:         EQU 14h
+; Host services:
+SERVICES:      EQU 1ah
+DATA:          EQU 1bh
+OPENWRITE:     EQU 07h
+WRITE:         EQU 08h
+CLOSE:         EQU 09h
+
+org	0100h
+	in a, (SERVICES)
+	cp 'K'
+	ld de, nohost
+	jp nz, error
+	ld a, (TAIL)
+	or a
+	jp z, usage
+	; CP/M file, the second name if given
+	ld a, (FCB2+1)
+	cp ' '
+	jr z, open
+	ld hl, FCB2
+	ld de, FCB
+	ld bc, 16
+	ldir
+open:
+	ld de, FCB
+	ld c, FOPEN
+	call BDOS
+	inc a
+	ld de, notfound
+	jp z, error
+	xor a
+	ld (FCB+32), a
+	; Host file name, the first word of the command line
+	ld a, OPENWRITE
+	out (SERVICES), a
+	ld hl, TAIL+1
+	ld a, (TAIL)
+	ld b, a
+skip:
+	ld a, b
+	or a
+	jp z, usage
+	ld a, (hl)
+	cp ' '
+	jr nz, name
+	inc hl
+	dec b
+	jr skip
+name:
+	out (DATA), a
+	inc hl
+	dec b
+	jr z, named
+	ld a, (hl)
+	cp ' '
+	jr nz, name
+named:
+	xor a
+	out (DATA), a
+	in a, (DATA)
+	or a
+	ld de, hosterror
+	jp nz, error
+loop:
+	ld de, FCB
+	ld c, FREAD
+	call BDOS
+	or a
+	jr nz, close
+	ld a, WRITE
+	out (SERVICES), a
+	ld hl, BUFFER
+	ld bc, 8000h + DATA
+	otir
+	jr loop
+close:
+	ld a, CLOSE
+	out (SERVICES), a
+	in a, (DATA)
+	or a
+	ld de, hosterror
+	jp nz, error
+	ret
+usage:
+	ld de, usagemsg
+error:
+	ld c, CWRITESTR
+	jp BDOS
+nohost:
+	db "Host services not enabled$"
+notfound:
+	db "File not found$"
+hosterror:
+	db "Error writing the host file$"
+usagemsg:
+	db "Usage: W HOSTFILE [CPMFILE]$"